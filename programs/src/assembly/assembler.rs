@@ -2,12 +2,15 @@ use crate::assembly::symbol_table::SymbolTable;
 use crate::assembly::{Instruction, UnresolvedInstruction};
 use anyhow::{anyhow, Result};
 
+/// Symbols of variables with their RAM addresses.
+pub type Variables = Vec<(String, u16)>;
+
 pub struct Assembler();
 impl Assembler {
     pub fn assemble_resolved(instruction: &Instruction) -> u16 {
-        match instruction {
-            &Instruction::Address { value } => value & (!(1u16 << 15)),
-            &Instruction::Compute {
+        match *instruction {
+            Instruction::Address { value } => value & (!(1u16 << 15)),
+            Instruction::Compute {
                 operation,
                 dest,
                 jump,
@@ -21,17 +24,24 @@ impl Assembler {
         }
     }
 
-    pub fn assemble_resolved_all(instructions: &Vec<Instruction>) -> Vec<u16> {
+    pub fn assemble_resolved_all(instructions: &[Instruction]) -> Vec<u16> {
         instructions.iter().map(Self::assemble_resolved).collect()
     }
 
     pub fn assemble(instructions: Vec<UnresolvedInstruction>) -> Result<Vec<u16>> {
+        Self::assemble_with_variables(instructions).map(|(assembled, _)| assembled)
+    }
+
+    /// Assembles `instructions`, also returning the variables allocated from RAM 16 in order.
+    pub fn assemble_with_variables(
+        instructions: Vec<UnresolvedInstruction>,
+    ) -> Result<(Vec<u16>, Variables)> {
         let mut table = SymbolTable::new();
         let mut jmp_line = 0u16;
         for instruction in &instructions {
             match instruction {
                 UnresolvedInstruction::Label { symbol } => {
-                    if !table.register(&symbol, jmp_line) {
+                    if !table.register(symbol, jmp_line) {
                         return Err(anyhow!(
                             "Symbol {} is either a reserved symbol or declared more than once",
                             symbol
@@ -56,6 +66,9 @@ impl Assembler {
             };
             resolved_instructions.push(resolved);
         }
-        Ok(Self::assemble_resolved_all(&resolved_instructions))
+        Ok((
+            Self::assemble_resolved_all(&resolved_instructions),
+            table.into_variables(),
+        ))
     }
 }
@@ -0,0 +1,153 @@
+use anyhow::{bail, Result};
+
+const RAM_SIZE: usize = 1 << 15;
+const JUMP: u16 = 0b1110_1010_1000_0111; // 0;JMP
+
+/// Executes assembled Hack machine code, one instruction per cycle.
+pub struct Emulator {
+    rom: Vec<u16>,
+    ram: Vec<u16>,
+    a: u16,
+    d: u16,
+    pc: u16,
+    cycles: u64,
+}
+
+impl Emulator {
+    pub fn new(rom: Vec<u16>) -> Self {
+        Self {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+    pub fn ram_mut(&mut self) -> &mut [u16] {
+        &mut self.ram
+    }
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn compute(&self, instruction: u16) -> u16 {
+        let bit = |i: u16| instruction & (1 << i) != 0;
+        let mut x = self.d;
+        let mut y = if bit(12) {
+            self.ram[self.a as usize % RAM_SIZE]
+        } else {
+            self.a
+        };
+        if bit(11) {
+            x = 0;
+        }
+        if bit(10) {
+            x = !x;
+        }
+        if bit(9) {
+            y = 0;
+        }
+        if bit(8) {
+            y = !y;
+        }
+        let out = if bit(7) { x.wrapping_add(y) } else { x & y };
+        if bit(6) {
+            !out
+        } else {
+            out
+        }
+    }
+
+    pub fn step(&mut self) -> Result<()> {
+        let instruction = match self.rom.get(self.pc as usize) {
+            Some(&instruction) => instruction,
+            None => bail!("Program counter {} is out of ROM", self.pc),
+        };
+        self.cycles += 1;
+        if instruction & (1 << 15) == 0 {
+            self.a = instruction;
+            self.pc += 1;
+            return Ok(());
+        }
+        let out = self.compute(instruction);
+        let address = self.a;
+        if instruction & (1 << 3) != 0 {
+            self.ram[address as usize % RAM_SIZE] = out;
+        }
+        if instruction & (1 << 4) != 0 {
+            self.d = out;
+        }
+        if instruction & (1 << 5) != 0 {
+            self.a = out;
+        }
+        let signed = out as i16;
+        let jump = (instruction & 0b100 != 0 && signed < 0)
+            || (instruction & 0b010 != 0 && signed == 0)
+            || (instruction & 0b001 != 0 && signed > 0);
+        self.pc = if jump { address } else { self.pc + 1 };
+        Ok(())
+    }
+
+    /// Whether the program is in the `(L) @L 0;JMP` loop conventionally used to stop.
+    pub fn is_halted(&self) -> bool {
+        let pc = self.pc as usize;
+        self.rom.get(pc) == Some(&self.pc) && self.rom.get(pc + 1) == Some(&JUMP)
+    }
+
+    /// Runs until the program halts, failing if it takes more than `max_cycles` cycles.
+    pub fn run(&mut self, max_cycles: u64) -> Result<()> {
+        while !self.is_halted() {
+            if self.cycles >= max_cycles {
+                bail!("Program didn't halt in {} cycles", max_cycles);
+            }
+            self.step()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembly::assembler::Assembler;
+    use crate::assembly::parser::Parser;
+
+    #[test]
+    fn test() -> Result<()> {
+        // R2 = R0 * R1
+        let asm = r#"
+            @R2
+            M=0
+        (LOOP)
+            @R0
+            D=M
+            @END
+            D;JEQ
+            @R1
+            D=M
+            @R2
+            M=M+D
+            @R0
+            M=M-1
+            @LOOP
+            0;JMP
+        (END)
+            @END
+            0;JMP
+        "#;
+        let mut emulator = Emulator::new(Assembler::assemble(Parser::parse(asm.as_bytes())?)?);
+        emulator.ram_mut()[0] = 6;
+        emulator.ram_mut()[1] = 7;
+        emulator.run(1000)?;
+        assert_eq!(emulator.ram()[2], 42);
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
 use enumset::EnumSet;
 
 pub mod assembler;
+pub mod emulator;
 pub mod parser;
-mod symbol_table;
+pub mod symbol_table;
 
 #[derive(EnumSetType, Debug)]
 pub enum Dest {
@@ -19,7 +20,7 @@ pub enum Condition {
 }
 
 #[derive(EnumSetType, Debug)]
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 pub enum Operation {
     A  = 6,
     ZY = 5,
@@ -81,7 +81,7 @@ impl Parser {
 
     fn parse_operation(arg: &str) -> Result<EnumSet<Operation>> {
         static TABLE: OnceCell<HashMap<&str, EnumSet<Operation>>> = OnceCell::new();
-        #[cfg_attr(rustfmt, rustfmt_skip)]
+        #[rustfmt::skip]
         let table = TABLE.get_or_init(|| {
             let mut table = HashMap::new();
             table.insert("0",   EnumSet::from_u8(0b101010));
@@ -113,7 +113,7 @@ impl Parser {
         let ret = table
             .get(non_addressing.as_str())
             .with_context(|| format!("Unknown operation `{}`", arg))?;
-        let mut ret = (*ret).clone();
+        let mut ret = *ret;
         if arg.contains('M') {
             ret.insert(Operation::A);
         }
@@ -130,12 +130,12 @@ impl Parser {
             _ => {}
         }
         let mut ret = EnumSet::new();
-        ret.insert(match arg.bytes().nth(1).unwrap() {
+        ret.insert(match arg.as_bytes()[1] {
             b'G' => Condition::GT,
             b'L' => Condition::LT,
             _ => return Err(anyhow!("Unable to parse jump `{}`", arg)),
         });
-        match arg.bytes().nth(2).unwrap() {
+        match arg.as_bytes()[2] {
             b'E' => {
                 ret.insert(Condition::EQ);
             }
@@ -174,7 +174,7 @@ impl Parser {
 
     fn parse_line(line: String) -> Result<Option<UnresolvedInstruction>> {
         let line = Self::clean_line(line);
-        return if line.is_empty() {
+        if line.is_empty() {
             // Empty line (may be comment)
             Ok(None)
         } else if line.starts_with('(') && line.ends_with(')') {
@@ -187,13 +187,13 @@ impl Parser {
             } else {
                 Err(anyhow!("Invalid symbol `{}`", symbol))
             }
-        } else if line.starts_with('@') {
-            Self::parse_a_instruction(&line[1..]).map(Some)
+        } else if let Some(arg) = line.strip_prefix('@') {
+            Self::parse_a_instruction(arg).map(Some)
         } else {
             Self::parse_c_instruction(line)
                 .map(UnresolvedInstruction::from)
                 .map(Some)
-        };
+        }
     }
 
     pub fn parse<R: BufRead>(input: R) -> Result<Vec<UnresolvedInstruction>> {
@@ -209,6 +209,6 @@ impl Parser {
                 res.with_context(|| format!("Failed to parse on L:{} `{}`", i + 1, line))
             })
             .collect::<Result<Vec<_>>>()?;
-        Ok(parsed.into_iter().flat_map(|x| x).collect::<Vec<_>>())
+        Ok(parsed.into_iter().flatten().collect::<Vec<_>>())
     }
 }
@@ -4,45 +4,67 @@ use std::collections::HashMap;
 pub struct SymbolTable {
     table: HashMap<String, u16>,
     next_address: u16,
+    // Symbols registered automatically, in the order of their addresses
+    variables: Vec<(String, u16)>,
+}
+
+fn predefined() -> &'static HashMap<String, u16> {
+    static INITIAL_TABLE: OnceCell<HashMap<String, u16>> = OnceCell::new();
+    #[rustfmt::skip]
+    let table = INITIAL_TABLE.get_or_init(|| {
+        let mut table = HashMap::new();
+        table.insert("SP".to_owned(),     0x0000);
+        table.insert("LCL".to_owned(),    0x0001);
+        table.insert("ARG".to_owned(),    0x0002);
+        table.insert("THIS".to_owned(),   0x0003);
+        table.insert("THAT".to_owned(),   0x0004);
+        table.insert("SCREEN".to_owned(), 0x4000);
+        table.insert("KBD".to_owned(),    0x6000);
+        for i in 0..16 {
+            table.insert(format!("R{}", i), i);
+        }
+        table
+    });
+    table
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SymbolTable {
     pub fn new() -> SymbolTable {
-        static INITIAL_TABLE: OnceCell<HashMap<String, u16>> = OnceCell::new();
-        #[cfg_attr(rustfmt, rustfmt_skip)]
-        let table = INITIAL_TABLE.get_or_init(|| {
-            let mut table = HashMap::new();
-            table.insert("SP".to_owned(),     0x0000);
-            table.insert("LCL".to_owned(),    0x0001);
-            table.insert("ARG".to_owned(),    0x0002);
-            table.insert("THIS".to_owned(),   0x0003);
-            table.insert("THAT".to_owned(),   0x0004);
-            table.insert("SCREEN".to_owned(), 0x4000);
-            table.insert("KBD".to_owned(),    0x6000);
-            for i in 0..16 {
-                table.insert(format!("R{}", i), i);
-            }
-            return table;
-        }).clone();
         SymbolTable {
-            table,
+            table: predefined().clone(),
             next_address: 0x0010,
+            variables: Vec::new(),
         }
     }
 
+    /// Whether `symbol` is a register or I/O address known to every program.
+    pub fn is_predefined(symbol: &str) -> bool {
+        predefined().contains_key(symbol)
+    }
+
     pub fn register(&mut self, symbol: &str, value: u16) -> bool {
         self.table.insert(symbol.to_owned(), value).is_none()
     }
 
     pub fn get_or_auto_register(&mut self, symbol: &str) -> u16 {
-        let ret = self
+        let ret = *self
             .table
             .entry(symbol.to_owned())
-            .or_insert(self.next_address)
-            .clone();
+            .or_insert(self.next_address);
         if ret == self.next_address {
+            self.variables.push((symbol.to_owned(), ret));
             self.next_address += 1;
         }
         ret
     }
+
+    pub fn into_variables(self) -> Vec<(String, u16)> {
+        self.variables
+    }
 }
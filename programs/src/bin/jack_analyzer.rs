@@ -1,5 +1,6 @@
 use anyhow::{anyhow, Context, Result};
 
+use nand2tetris::jack::parser::Parser;
 use nand2tetris::jack::tokenizer::TokenIterator;
 use nand2tetris::jack::xml_analyzer::XMLAnalyzer;
 use std::fs::{self, File};
@@ -13,12 +14,17 @@ fn main() -> Result<()> {
     let mut args = std::env::args();
     args.next()
         .with_context(|| "First arg should be the program name...")?;
-    let input_path = args
-        .next()
-        .with_context(|| "This program expects an argument but non was given")?;
-    if args.next().is_some() {
-        return Result::Err(anyhow!("This program expects at most one argument"));
+    let mut language_extensions = false;
+    let mut input_path = None;
+    for arg in args {
+        if arg == "--language-extensions" {
+            language_extensions = true;
+        } else if input_path.replace(arg).is_some() {
+            return Result::Err(anyhow!("This program expects at most one input"));
+        }
     }
+    let input_path =
+        input_path.with_context(|| "This program expects an input but non was given")?;
 
     let jack_files = if fs::metadata(&input_path)
         .with_context(|| format!("Unable to read {}", input_path))?
@@ -34,7 +40,7 @@ fn main() -> Result<()> {
                             .file_name()
                             .map::<&Path, _>(|p| p.as_ref())
                             .and_then(|p| p.to_str())
-                            .map_or(false, |p| p.ends_with(JACK_EXT));
+                            .is_some_and(|p| p.ends_with(JACK_EXT));
                         if jack_name && e.path().is_file() {
                             Some(e.path().to_path_buf())
                         } else {
@@ -44,7 +50,7 @@ fn main() -> Result<()> {
             })
             .collect::<Result<Vec<_>>>()?
             .into_iter()
-            .flat_map(|x| x)
+            .flatten()
             .collect::<Vec<PathBuf>>()
     } else {
         if !input_path.ends_with(JACK_EXT) {
@@ -57,11 +63,12 @@ fn main() -> Result<()> {
         let jack = File::open(&jack_file)
             .map(BufReader::new)
             .with_context(|| format!("Unable to open file {}", jack_file.to_string_lossy()))?;
-        let token_iterator = TokenIterator::from(jack);
-        let mut analyzer = XMLAnalyzer::from(token_iterator);
-        let result = analyzer
-            .compile()
+        let mut token_iterator = TokenIterator::from(jack);
+        token_iterator.set_file_name(&jack_file.to_string_lossy());
+        token_iterator.set_language_extensions(language_extensions);
+        let class = Parser::parse(token_iterator)
             .with_context(|| format!("Unable to analyze {}", jack_file.to_string_lossy()))?;
+        let result = XMLAnalyzer::compile(&class);
         let output_file = jack_file.with_extension(&XML_EXT[1..]);
         let mut xml = File::create(&output_file)
             .map(BufWriter::new)
@@ -1,10 +1,21 @@
 use anyhow::{anyhow, Context, Result};
+use enumset::EnumSet;
 
+use nand2tetris::ir::parser::Parser;
+use nand2tetris::ir::verifier::Verifier;
 use nand2tetris::ir::writer::IRWriter;
+use nand2tetris::ir::Extension;
+use nand2tetris::jack::ast::Semantics;
 use nand2tetris::jack::ir_analyzer::IRAnalyzer;
+use nand2tetris::jack::parser::Parser as JackParser;
+use nand2tetris::jack::return_checker::ReturnChecker;
+use nand2tetris::jack::signatures::Signatures;
 use nand2tetris::jack::tokenizer::TokenIterator;
+use nand2tetris::jack::type_checker::TypeChecker;
+use nand2tetris::jack::warnings::{Warning, WarningChecker};
+use nand2tetris::jack::Errors;
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter};
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
 
 const JACK_EXT: &str = ".jack";
@@ -14,17 +25,41 @@ fn main() -> Result<()> {
     let mut args = std::env::args();
     args.next()
         .with_context(|| "First arg should be the program name...")?;
-    let input_path = args
-        .next()
-        .with_context(|| "This program expects an argument but non was given")?;
-    if args.next().is_some() {
-        return Result::Err(anyhow!("This program expects at most one argument"));
+    let mut extended_instructions = false;
+    let mut strict_types = false;
+    let mut language_extensions = false;
+    let mut semantics = Semantics::Precedence;
+    let mut allowed = EnumSet::new();
+    let mut input_path = None;
+    for arg in args {
+        if arg == "--extended-instructions" {
+            extended_instructions = true;
+        } else if arg == "--strict-types" {
+            strict_types = true;
+        } else if arg == "--language-extensions" {
+            language_extensions = true;
+        } else if arg == "--left-to-right" {
+            semantics = Semantics::LeftToRight;
+        } else if let Some(warnings) = arg.strip_prefix("--allow=") {
+            for warning in warnings.split(',') {
+                allowed |= warning.parse::<Warning>()?;
+            }
+        } else if input_path.replace(arg).is_some() {
+            return Result::Err(anyhow!("This program expects at most one input"));
+        }
     }
+    let input_path =
+        input_path.with_context(|| "This program expects an input but non was given")?;
+    let extensions = if extended_instructions {
+        EnumSet::only(Extension::Instructions)
+    } else {
+        EnumSet::new()
+    };
 
-    let jack_files = if fs::metadata(&input_path)
+    let is_dir = fs::metadata(&input_path)
         .with_context(|| format!("Unable to read {}", input_path))?
-        .is_dir()
-    {
+        .is_dir();
+    let jack_files = if is_dir {
         fs::read_dir(&input_path)
             .with_context(|| format!("Unable to read {}", input_path))?
             .map(|r| {
@@ -35,7 +70,7 @@ fn main() -> Result<()> {
                             .file_name()
                             .map::<&Path, _>(|p| p.as_ref())
                             .and_then(|p| p.to_str())
-                            .map_or(false, |p| p.ends_with(JACK_EXT));
+                            .is_some_and(|p| p.ends_with(JACK_EXT));
                         if jack_name && e.path().is_file() {
                             Some(e.path().to_path_buf())
                         } else {
@@ -45,7 +80,7 @@ fn main() -> Result<()> {
             })
             .collect::<Result<Vec<_>>>()?
             .into_iter()
-            .flat_map(|x| x)
+            .flatten()
             .collect::<Vec<PathBuf>>()
     } else {
         if !input_path.ends_with(JACK_EXT) {
@@ -54,21 +89,72 @@ fn main() -> Result<()> {
         vec![PathBuf::from(&input_path)]
     };
 
+    // Calls are checked against every class compiled together
+    let mut parsed = vec![];
+    let mut signatures = Signatures::new();
+    signatures.add_os_api();
+    // A single file may call classes compiled separately
+    signatures.set_complete(is_dir);
     for jack_file in jack_files {
         let jack = File::open(&jack_file)
             .map(BufReader::new)
             .with_context(|| format!("Unable to open file {}", jack_file.to_string_lossy()))?;
-        let token_iterator = TokenIterator::from(jack);
+        let mut token_iterator = TokenIterator::from(jack);
+        token_iterator.set_file_name(&jack_file.to_string_lossy());
+        token_iterator.set_language_extensions(language_extensions);
+        let (class, errors) = JackParser::parse_recovering(token_iterator);
+        if let Some(class) = &class {
+            signatures.add_class(class);
+        }
+        parsed.push((jack_file, class, errors));
+    }
+
+    let mut warning_checker = WarningChecker::new();
+    warning_checker.allow(allowed);
+    for (jack_file, class, mut errors) in parsed {
+        // Report the semantic errors in what could be parsed along with the syntax errors
+        let mut vm = vec![];
+        if let Some(class) = class {
+            for warning in warning_checker.check(&class) {
+                eprintln!("warning: {}", warning);
+            }
+            // Calls are checked here rather than again by `IRAnalyzer`
+            let mut type_checker = TypeChecker::new(&signatures);
+            type_checker.set_strict(strict_types);
+            type_checker.set_semantics(semantics);
+            if let Err(e) = type_checker.check(&class) {
+                errors.push(e);
+            }
+            if let Err(e) = ReturnChecker::check(&class) {
+                errors.push(e);
+            }
+            let mut analyzer = IRAnalyzer::new(IRWriter::new(&mut vm));
+            analyzer.set_extended_instructions(extended_instructions);
+            analyzer.set_semantics(semantics);
+            if let Err(e) = analyzer.compile(&class) {
+                errors.push(e);
+            }
+        }
+        if !errors.is_empty() {
+            return Err(Errors(errors))
+                .with_context(|| format!("Unable to analyze {}", jack_file.to_string_lossy()));
+        }
+        // Catch compiler bugs leaving the stack unbalanced before they crash at runtime
+        Parser::parse_lines_with(vm.as_slice(), extensions)
+            .and_then(|lines| {
+                let commands: Vec<_> = lines.into_iter().filter_map(|line| line.command).collect();
+                Verifier::verify(&commands)
+            })
+            .with_context(|| {
+                format!(
+                    "Compiled invalid VM code for {}",
+                    jack_file.to_string_lossy()
+                )
+            })?;
 
         let output_file = jack_file.with_extension(&VM_EXT[1..]);
-        let vm = File::create(&output_file)
-            .map(BufWriter::new)
-            .with_context(|| format!("Unable to open file {}", output_file.to_string_lossy()))?;
-        let ir_writer = IRWriter::new(vm);
-        let mut analyzer = IRAnalyzer::from(token_iterator, ir_writer);
-        analyzer
-            .compile()
-            .with_context(|| format!("Unable to analyze {}", jack_file.to_string_lossy()))?;
+        fs::write(&output_file, vm)
+            .with_context(|| format!("Unable to write file {}", output_file.to_string_lossy()))?;
     }
     Result::Ok(())
 }
@@ -1,24 +1,197 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
+use enumset::EnumSet;
+use nand2tetris::ir::c_translator::CTranslator;
+use nand2tetris::ir::inliner::Inliner;
+use nand2tetris::ir::linker::Linker;
+use nand2tetris::ir::optimize;
 use nand2tetris::ir::parser::Parser;
-use nand2tetris::ir::translator::Translator;
+use nand2tetris::ir::translator::{Bootstrap, Translator};
+use nand2tetris::ir::verifier::Verifier;
+use nand2tetris::ir::Extension;
 use std::fs::{self, File};
 use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 
 const ASM_EXT: &str = ".asm";
+const C_EXT: &str = ".c";
 const VM_EXT: &str = ".vm";
+const MAP_EXT: &str = "map";
 
-fn main() -> Result<()> {
+const USAGE: &str = "Usage: translator [--extensions=negative-constants,instructions] \
+[--bootstrap=standard|none] [--entry=FUNCTION] \
+[--sp=N] [--lcl=N] [--arg=N] [--this=N] [--that=N] [--target=hack|c] [--compact] [--cache-top-of-stack] [--inline=SIZE] [--optimize] [--link] [--static-report] [--source-map] <FILE.vm|DIR>";
+
+/// What the VM code is translated into.
+#[derive(PartialEq)]
+enum Target {
+    Hack,
+    C,
+}
+
+struct Args {
+    extensions: EnumSet<Extension>,
+    bootstrap: Bootstrap,
+    target: Target,
+    compact: bool,
+    cache_top_of_stack: bool,
+    inline: Option<usize>,
+    optimize: bool,
+    link: bool,
+    static_report: bool,
+    source_map: bool,
+    input_path: String,
+}
+
+fn parse_register(option: &str, value: &str) -> Result<Option<u16>> {
+    let value = value
+        .parse()
+        .with_context(|| format!("Invalid value `{}` for {}", value, option))?;
+    Ok(Some(value))
+}
+
+/// Formats sorted `addresses` as comma separated ranges like `16-18, 21`.
+fn format_ranges(addresses: &[u16]) -> String {
+    let mut ranges: Vec<(u16, u16)> = vec![];
+    for &address in addresses {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == address => *end = address,
+            _ => ranges.push((address, address)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn parse_args() -> Result<Args> {
     let mut args = std::env::args();
     args.next()
         .with_context(|| "First arg should be the program name...")?;
-    let input_path = args
-        .next()
-        .with_context(|| "This program expects an argument but non was given")?;
-    if args.next().is_some() {
-        return Result::Err(anyhow!("This program expects at most one argument"));
+    let mut extensions = EnumSet::new();
+    let mut bootstrap = Bootstrap::standard();
+    let mut target = Target::Hack;
+    let mut compact = false;
+    let mut cache_top_of_stack = false;
+    let mut inline = None;
+    let mut optimize = false;
+    let mut link = false;
+    let mut static_report = false;
+    let mut source_map = false;
+    let mut input_path = None;
+    for arg in args {
+        if arg == "--compact" {
+            compact = true;
+        } else if arg == "--cache-top-of-stack" {
+            cache_top_of_stack = true;
+        } else if arg == "--optimize" {
+            optimize = true;
+        } else if arg == "--link" {
+            link = true;
+        } else if arg == "--static-report" {
+            static_report = true;
+        } else if arg == "--source-map" {
+            source_map = true;
+        } else if let Some(option) = arg.strip_prefix("--") {
+            let (key, value) = option
+                .split_once('=')
+                .with_context(|| format!("Option `{}` expects a value\n{}", arg, USAGE))?;
+            match key {
+                "extensions" => {
+                    for extension in value.split(',') {
+                        extensions |= extension.parse::<Extension>().with_context(|| USAGE)?;
+                    }
+                }
+                "bootstrap" => {
+                    bootstrap = match value {
+                        "standard" => Bootstrap::standard(),
+                        "none" => Bootstrap::none(),
+                        _ => bail!("Unknown bootstrap `{}`\n{}", value, USAGE),
+                    }
+                }
+                "target" => {
+                    target = match value {
+                        "hack" => Target::Hack,
+                        "c" => Target::C,
+                        _ => bail!("Unknown target `{}`\n{}", value, USAGE),
+                    }
+                }
+                "entry" => {
+                    let entry_point = value
+                        .parse()
+                        .with_context(|| format!("Invalid entry function `{}`", value))?;
+                    bootstrap.entry_point = Some(entry_point);
+                }
+                "inline" => {
+                    let max_size = value
+                        .parse()
+                        .with_context(|| format!("Invalid value `{}` for {}", value, arg))?;
+                    inline = Some(max_size);
+                }
+                "sp" => bootstrap.sp = parse_register(&arg, value)?,
+                "lcl" => bootstrap.lcl = parse_register(&arg, value)?,
+                "arg" => bootstrap.arg = parse_register(&arg, value)?,
+                "this" => bootstrap.this = parse_register(&arg, value)?,
+                "that" => bootstrap.that = parse_register(&arg, value)?,
+                _ => bail!("Unknown option `{}`\n{}", arg, USAGE),
+            }
+        } else if input_path.replace(arg).is_some() {
+            bail!("This program expects at most one input\n{}", USAGE);
+        }
     }
+    let input_path = input_path
+        .with_context(|| format!("This program expects an input but non was given\n{}", USAGE))?;
+    Ok(Args {
+        extensions,
+        bootstrap,
+        target,
+        compact,
+        cache_top_of_stack,
+        inline,
+        optimize,
+        link,
+        static_report,
+        source_map,
+        input_path,
+    })
+}
+
+fn main() -> Result<()> {
+    let Args {
+        extensions,
+        bootstrap,
+        target,
+        compact,
+        cache_top_of_stack,
+        inline,
+        optimize,
+        link,
+        static_report,
+        source_map,
+        input_path,
+    } = parse_args()?;
+    if source_map && (inline.is_some() || optimize || link) {
+        bail!(
+            "Source maps can't be made when rewriting commands with --inline, --optimize or --link"
+        );
+    }
+    if target == Target::C && (compact || cache_top_of_stack || static_report || source_map) {
+        bail!(
+            "--compact, --cache-top-of-stack, --static-report and --source-map only apply to Hack"
+        );
+    }
+    let output_ext = match target {
+        Target::Hack => ASM_EXT,
+        Target::C => C_EXT,
+    };
 
     let (output_path, vm_files) = if fs::metadata(&input_path)
         .with_context(|| format!("Unable to read {}", input_path))?
@@ -34,7 +207,7 @@ fn main() -> Result<()> {
                             .file_name()
                             .map::<&Path, _>(|p| p.as_ref())
                             .and_then(|p| p.to_str())
-                            .map_or(false, |p| p.ends_with(VM_EXT));
+                            .is_some_and(|p| p.ends_with(VM_EXT));
                         if vm_name && e.path().is_file() {
                             Some(e.path().to_path_buf())
                         } else {
@@ -44,7 +217,7 @@ fn main() -> Result<()> {
             })
             .collect::<Result<Vec<_>>>()?
             .into_iter()
-            .flat_map(|x| x)
+            .flatten()
             .collect::<Vec<PathBuf>>();
         let mut output_path = PathBuf::from(input_path);
         let dirname = output_path
@@ -57,29 +230,94 @@ fn main() -> Result<()> {
             })?
             .to_owned();
         output_path.push(dirname);
-        output_path.set_extension(&ASM_EXT[1..]);
+        output_path.set_extension(&output_ext[1..]);
         (output_path, files)
     } else {
         if !input_path.ends_with(VM_EXT) {
             return Result::Err(anyhow!("Input file must be suffixed by {}", VM_EXT));
         }
-        let output_path = PathBuf::from(&input_path).with_extension(&ASM_EXT[1..]);
+        let output_path = PathBuf::from(&input_path).with_extension(&output_ext[1..]);
         (output_path, vec![PathBuf::from(&input_path)])
     };
 
-    let mut translator = Translator::new();
+    let mut classes = vec![];
+    let mut sources = vec![];
     for path in &vm_files {
         let vm = File::open(path)
             .map(BufReader::new)
             .with_context(|| format!("Unable to open file {}", path.to_string_lossy()))?;
-        let parsed = Parser::parse(vm)?;
+        let lines = Parser::parse_lines_with(vm, extensions)?;
+        let parsed: Vec<_> = lines
+            .iter()
+            .filter_map(|line| line.command.clone())
+            .collect();
+        Verifier::verify(&parsed)
+            .with_context(|| format!("Invalid VM code in {}", path.to_string_lossy()))?;
         let class = path
             .file_name()
             .with_context(|| anyhow!("Unable to get file name from {}", path.to_string_lossy()))?
             .to_str()
             .with_context(|| anyhow!("Unable to stringify file name {}", path.to_string_lossy()))?
             .trim_end_matches(VM_EXT);
-        translator.add_commands(class, &parsed)?;
+        classes.push((class.to_owned(), parsed));
+        sources.push(lines);
+    }
+    if let Some(max_size) = inline {
+        classes = Inliner::inline(classes, max_size);
+    }
+    if optimize {
+        classes = classes
+            .into_iter()
+            .map(|(class, commands)| (class, optimize::optimize(commands)))
+            .collect();
+    }
+    if link {
+        let entry_point = bootstrap
+            .entry_point
+            .as_ref()
+            .with_context(|| "Linking requires an entry function")?;
+        classes = Linker::link(classes, entry_point).with_context(|| "Failed to link")?;
+    }
+
+    if target == Target::C {
+        let mut translator = CTranslator::with_bootstrap(&bootstrap);
+        for (class, commands) in &classes {
+            translator.add_commands(class, commands)?;
+        }
+        let source = translator.get_source()?;
+        let mut output_file = File::create(&output_path)
+            .with_context(|| format!("Unable to open file {}", output_path.to_string_lossy()))
+            .map(BufWriter::new)?;
+        for line in source {
+            writeln!(output_file, "{}", line)?;
+        }
+        return Result::Ok(());
+    }
+
+    let mut translator = Translator::with_bootstrap(&bootstrap);
+    translator.set_compact(compact);
+    translator.set_cache_top_of_stack(cache_top_of_stack);
+    if source_map {
+        for ((class, _), lines) in classes.iter().zip(&sources) {
+            translator.add_lines(class, lines)?;
+        }
+        let map_path = output_path.with_extension(MAP_EXT);
+        let mut map_file = File::create(&map_path)
+            .with_context(|| format!("Unable to open file {}", map_path.to_string_lossy()))
+            .map(BufWriter::new)?;
+        for (address, source) in translator.source_map().ranges() {
+            writeln!(map_file, "{} {}", address, source)?;
+        }
+    } else {
+        for (class, commands) in &classes {
+            translator.add_commands(class, commands)?;
+        }
+    }
+    let static_allocation = translator.static_allocation()?;
+    if static_report {
+        for (class, addresses) in static_allocation {
+            println!("{}: {}", class, format_ranges(&addresses));
+        }
     }
     let ret = translator.get_assembly();
     let mut output_file = File::create(&output_path)
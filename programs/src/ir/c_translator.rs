@@ -0,0 +1,559 @@
+use crate::ir::translator::{Bootstrap, STATIC_END, STATIC_START};
+use crate::ir::{
+    split_functions, Arithmetic, Command, FunctionCall, MemoryAccess, ProgramFlow, Segment, Symbol,
+};
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::{HashMap, HashSet};
+
+/// The OS function that loops forever once the program is done, which instead exits.
+const HALT_FUNCTION: &str = "Sys.halt";
+
+/// Definitions shared by every translated program, simulating the Hack RAM and the VM stack.
+const RUNTIME: &str = r#"// Usage: PROGRAM [SCREEN.pbm [MAX_STEPS]]
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#define SP ram[0]
+#define LCL ram[1]
+#define ARG ram[2]
+#define THIS ram[3]
+#define THAT ram[4]
+#define TOP ram[(uint16_t)(SP - 1)]
+#define SCREEN 0x4000
+
+typedef void (*vm_function)(void);
+
+// Addresses wrap around at 16 bits as on Hack, so the whole range is backed
+static uint16_t ram[0x10000];
+static unsigned long long steps, max_steps;
+static const char *screen_path;
+
+// Writes the screen as a 512x256 PBM image if requested and exits with `status`.
+static void stop(int status) {
+    if (screen_path) {
+        FILE *file = fopen(screen_path, "wb");
+        if (!file) {
+            perror(screen_path);
+            exit(1);
+        }
+        fprintf(file, "P4\n512 256\n");
+        for (int i = 0; i < 256 * 32; i++) {
+            // Hack shows the least significant bit leftmost, PBM the most significant one
+            uint16_t word = ram[SCREEN + i];
+            for (int shift = 0; shift < 16; shift += 8) {
+                unsigned char byte = 0;
+                for (int bit = 0; bit < 8; bit++) {
+                    byte |= ((word >> (shift + bit)) & 1) << (7 - bit);
+                }
+                fputc(byte, file);
+            }
+        }
+        fclose(file);
+    }
+    exit(status);
+}
+
+// Counts jumps and calls, stopping at the `max_steps`th one.
+static inline void step(void) {
+    if (++steps == max_steps) {
+        fprintf(stderr, "Stopped after %llu steps\n", steps);
+        stop(2);
+    }
+}
+
+static inline void push(uint16_t value) {
+    ram[SP] = value;
+    SP++;
+}
+
+static inline uint16_t pop(void) {
+    SP--;
+    return ram[SP];
+}
+
+// Pushes the standard frame, whose return address is left 0 as C keeps track of it.
+static inline void call(vm_function callee, uint16_t n_args) {
+    uint16_t arg = SP - n_args;
+    push(0);
+    push(LCL);
+    push(ARG);
+    push(THIS);
+    push(THAT);
+    ARG = arg;
+    LCL = SP;
+    step();
+    callee();
+}
+
+static inline void ret(void) {
+    uint16_t frame = LCL;
+    ram[ARG] = pop();
+    SP = ARG + 1;
+    THAT = ram[(uint16_t)(frame - 1)];
+    THIS = ram[(uint16_t)(frame - 2)];
+    ARG = ram[(uint16_t)(frame - 3)];
+    LCL = ram[(uint16_t)(frame - 4)];
+}
+
+// Shifting by a negative amount leaves `x` as is.
+static inline uint16_t shl(uint16_t x, uint16_t y) {
+    return (int16_t)y <= 0 ? x : y >= 16 ? 0 : (uint16_t)(x << y);
+}
+
+static inline uint16_t shr(uint16_t x, uint16_t y) {
+    return (int16_t)y <= 0 ? x : y >= 16 ? 0 : x >> y;
+}
+"#;
+
+/// Turns `symbol` into a C identifier, escaping `_` so that distinct symbols stay distinct.
+fn mangle(prefix: &str, symbol: &Symbol) -> String {
+    let mut ret = prefix.to_owned();
+    for c in symbol.0.chars() {
+        match c {
+            '_' => ret.push_str("__"),
+            '.' => ret.push_str("_d"),
+            ':' => ret.push_str("_c"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Translates VM commands into a single C program, as a portable alternative to `Translator`.
+///
+/// Each VM function becomes a C function working on a simulated Hack RAM with the standard
+/// mapping, so statics get the same addresses and programs draw the same screen. The program
+/// stops when the entry function returns, on `call Sys.halt`, on a `goto` to the label right
+/// before it, or after `MAX_STEPS` jumps and calls with the exit status 2.
+pub struct CTranslator {
+    bootstrap: Bootstrap,
+    definitions: Vec<String>,
+    functions: HashSet<Symbol>,
+    // Functions to run when there's no entry point, one per run of commands outside functions
+    blocks: Vec<String>,
+    called: Vec<Symbol>,
+    // Functions pushed by `push-function`, whose addresses are their indices plus 1
+    addressed: Vec<Symbol>,
+    static_addresses: HashMap<String, u16>,
+}
+
+impl Default for CTranslator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CTranslator {
+    pub fn new() -> Self {
+        Self::with_bootstrap(&Bootstrap::standard())
+    }
+
+    pub fn with_bootstrap(bootstrap: &Bootstrap) -> Self {
+        CTranslator {
+            bootstrap: bootstrap.clone(),
+            definitions: vec![],
+            functions: HashSet::new(),
+            blocks: vec![],
+            called: vec![],
+            addressed: vec![],
+            static_addresses: HashMap::new(),
+        }
+    }
+
+    fn add_called(&mut self, name: &Symbol) {
+        if !self.called.contains(name) {
+            self.called.push(name.clone());
+        }
+    }
+
+    /// The RAM address of a static, allocated in the order of appearance like the assembler does.
+    fn static_address(&mut self, class: &Symbol, index: u16) -> Result<u16> {
+        let symbol = format!("{}.{}", class.0, index);
+        let address = STATIC_START + self.static_addresses.len() as u16;
+        if let Some(&address) = self.static_addresses.get(&symbol) {
+            return Ok(address);
+        }
+        ensure!(
+            address < STATIC_END,
+            "Static `{}` doesn't fit in RAM {}-{} since the program has more than {} statics",
+            symbol,
+            STATIC_START,
+            STATIC_END - 1,
+            STATIC_END - STATIC_START
+        );
+        self.static_addresses.insert(symbol, address);
+        Ok(address)
+    }
+
+    /// The C lvalue or value of `index` in `segment`.
+    fn operand(&mut self, class: &Symbol, segment: &Segment, index: u16) -> Result<String> {
+        let indirect = |pointer| match index {
+            0 => format!("ram[{}]", pointer),
+            _ => format!("ram[(uint16_t)({} + {})]", pointer, index),
+        };
+        let ret = match segment {
+            Segment::Argument => indirect("ARG"),
+            Segment::Local => indirect("LCL"),
+            Segment::Static => format!("ram[{}]", self.static_address(class, index)?),
+            Segment::Constant => index.to_string(),
+            Segment::This => indirect("THIS"),
+            Segment::That => indirect("THAT"),
+            Segment::Pointer => {
+                ensure!(index < 2);
+                ["THIS", "THAT"][index as usize].to_owned()
+            }
+            Segment::Temp => {
+                ensure!(index < 8);
+                format!("ram[{}]", 5 + index)
+            }
+        };
+        Ok(ret)
+    }
+
+    fn translate_arithmetic(&mut self, arithmetic: &Arithmetic) -> String {
+        if let Some(function) = arithmetic.library_function() {
+            let function = Symbol(function.to_owned());
+            self.add_called(&function);
+            return format!("call({}, 2);", mangle("f_", &function));
+        }
+        let binary = |operation: &str| format!("{{ uint16_t y = pop(); {} }}", operation);
+        match arithmetic {
+            Arithmetic::Add => binary("TOP += y;"),
+            Arithmetic::Sub => binary("TOP -= y;"),
+            Arithmetic::Neg => "TOP = -TOP;".to_owned(),
+            Arithmetic::Eq => binary("TOP = -(TOP == y);"),
+            // By the sign of the difference, overflowing like the Hack translation
+            Arithmetic::Gt => binary("TOP = -((int16_t)(uint16_t)(TOP - y) > 0);"),
+            Arithmetic::Lt => binary("TOP = -((int16_t)(uint16_t)(TOP - y) < 0);"),
+            Arithmetic::And => binary("TOP &= y;"),
+            Arithmetic::Or => binary("TOP |= y;"),
+            Arithmetic::Not => "TOP = ~TOP;".to_owned(),
+            Arithmetic::Shl => binary("TOP = shl(TOP, y);"),
+            Arithmetic::Shr => binary("TOP = shr(TOP, y);"),
+            Arithmetic::Mul | Arithmetic::Div => unreachable!("Called as library functions"),
+        }
+    }
+
+    fn translate_command(
+        &mut self,
+        class: &Symbol,
+        command: &Command,
+        previous: Option<&Command>,
+        used_labels: &HashSet<&Symbol>,
+    ) -> Result<Option<String>> {
+        let ret = match command {
+            Command::Arithmetic(arithmetic) => self.translate_arithmetic(arithmetic),
+            Command::MemoryAccess(MemoryAccess::Push { segment, index }) => {
+                format!("push({});", self.operand(class, segment, *index)?)
+            }
+            Command::MemoryAccess(MemoryAccess::Pop { segment, index }) => {
+                if let Segment::Constant = segment {
+                    bail!("Unable to pop to {:?}", command);
+                }
+                format!("{} = pop();", self.operand(class, segment, *index)?)
+            }
+            Command::MemoryAccess(MemoryAccess::PushIndirect) => "TOP = ram[TOP];".to_owned(),
+            Command::MemoryAccess(MemoryAccess::PopIndirect) => {
+                "{ uint16_t y = pop(); ram[pop()] = y; }".to_owned()
+            }
+            Command::ProgramFlow(ProgramFlow::Label { label }) => {
+                if !used_labels.contains(label) {
+                    return Ok(None);
+                }
+                format!("{}:;", mangle("l_", label))
+            }
+            Command::ProgramFlow(ProgramFlow::Goto { label }) => match previous {
+                Some(Command::ProgramFlow(ProgramFlow::Label { label: previous }))
+                    if previous == label =>
+                {
+                    "stop(0);".to_owned()
+                }
+                _ => format!("step(); goto {};", mangle("l_", label)),
+            },
+            Command::ProgramFlow(ProgramFlow::IfGoto { label }) => {
+                format!("step(); if (pop()) goto {};", mangle("l_", label))
+            }
+            Command::FunctionCall(FunctionCall::Declare { .. }) => {
+                unreachable!("Functions are split beforehand")
+            }
+            Command::FunctionCall(FunctionCall::Invoke { name, .. }) if name.0 == HALT_FUNCTION => {
+                "stop(0);".to_owned()
+            }
+            Command::FunctionCall(FunctionCall::Invoke { name, n_args }) => {
+                self.add_called(name);
+                format!("call({}, {});", mangle("f_", name), n_args)
+            }
+            Command::FunctionCall(FunctionCall::Address { name }) => {
+                self.add_called(name);
+                let address = match self.addressed.iter().position(|f| f == name) {
+                    Some(i) => i + 1,
+                    None => {
+                        self.addressed.push(name.clone());
+                        self.addressed.len()
+                    }
+                };
+                format!("push({});", address)
+            }
+            Command::FunctionCall(FunctionCall::InvokeIndirect { n_args }) => {
+                format!("call(function_at(pop()), {});", n_args)
+            }
+            Command::FunctionCall(FunctionCall::Return) => "ret(); return;".to_owned(),
+        };
+        Ok(Some(ret))
+    }
+
+    /// Translates a single function, or commands outside of functions, into a C function.
+    fn add_function(&mut self, class: &Symbol, commands: &[Command]) -> Result<()> {
+        let mut lines = vec![];
+        let body = match commands {
+            [Command::FunctionCall(FunctionCall::Declare { name, n_locals }), body @ ..] => {
+                ensure!(
+                    self.functions.insert(name.clone()),
+                    "Function `{}` is defined more than once",
+                    name.0
+                );
+                lines.push(format!("void {}(void) {{", mangle("f_", name)));
+                if *n_locals > 0 {
+                    lines.push(format!(
+                        "    for (int i = 0; i < {}; i++) push(0);",
+                        n_locals
+                    ));
+                }
+                body
+            }
+            _ => {
+                let block = format!("block_{}", self.blocks.len());
+                lines.push(format!("void {}(void) {{", block));
+                self.blocks.push(block);
+                commands
+            }
+        };
+        let used_labels: HashSet<&Symbol> = body
+            .iter()
+            .filter_map(|command| match command {
+                Command::ProgramFlow(ProgramFlow::Goto { label })
+                | Command::ProgramFlow(ProgramFlow::IfGoto { label }) => Some(label),
+                _ => None,
+            })
+            .collect();
+        for (i, command) in body.iter().enumerate() {
+            let previous = i.checked_sub(1).map(|j| &body[j]);
+            if let Some(code) = self.translate_command(class, command, previous, &used_labels)? {
+                lines.push(format!("    {} // {}", code, command));
+            }
+        }
+        lines.push("}".to_owned());
+        self.definitions.push(lines.join("\n"));
+        Ok(())
+    }
+
+    pub fn add_commands(&mut self, class: &str, commands: &[Command]) -> Result<()> {
+        let class = class
+            .parse()
+            .with_context(|| format!("Class name `{}` is invalid", class))?;
+        for function in split_functions(commands) {
+            self.add_function(&class, function)?;
+        }
+        Ok(())
+    }
+
+    /// The C source, failing if a function is called but not defined.
+    pub fn get_source(mut self) -> Result<Vec<String>> {
+        if let Some(entry_point) = self.bootstrap.entry_point.clone() {
+            self.add_called(&entry_point);
+        }
+        if let Some(name) = self.called.iter().find(|f| !self.functions.contains(f)) {
+            bail!("Function `{}` is called but not defined", name.0);
+        }
+
+        let mut ret = vec![RUNTIME.to_owned()];
+        let mut functions: Vec<_> = self.functions.iter().map(|f| mangle("f_", f)).collect();
+        functions.sort();
+        for function in functions.iter().chain(&self.blocks) {
+            ret.push(format!("void {}(void);", function));
+        }
+        if !self.addressed.is_empty() {
+            let addressed: Vec<_> = self.addressed.iter().map(|f| mangle("f_", f)).collect();
+            ret.push(format!(
+                "\nstatic const vm_function functions[] = {{0, {}}};",
+                addressed.join(", ")
+            ));
+            ret.push(
+                r#"
+static vm_function function_at(uint16_t address) {
+    if (address == 0 || address >= sizeof functions / sizeof *functions) {
+        fprintf(stderr, "No function at address %u\n", (unsigned)address);
+        stop(1);
+    }
+    return functions[address];
+}"#
+                .to_owned(),
+            );
+        }
+        for definition in self.definitions {
+            ret.push(String::new());
+            ret.push(definition);
+        }
+
+        ret.push("\nint main(int argc, char **argv) {".to_owned());
+        ret.push("    screen_path = argc > 1 ? argv[1] : NULL;".to_owned());
+        ret.push("    max_steps = argc > 2 ? strtoull(argv[2], NULL, 10) : 0;".to_owned());
+        let bootstrap = &self.bootstrap;
+        let registers = [
+            ("SP", bootstrap.sp),
+            ("LCL", bootstrap.lcl),
+            ("ARG", bootstrap.arg),
+            ("THIS", bootstrap.this),
+            ("THAT", bootstrap.that),
+        ];
+        for (register, value) in registers.iter() {
+            if let Some(value) = value {
+                ret.push(format!("    {} = {};", register, value));
+            }
+        }
+        match &bootstrap.entry_point {
+            Some(entry_point) => ret.push(format!("    call({}, 0);", mangle("f_", entry_point))),
+            None => {
+                for block in &self.blocks {
+                    ret.push(format!("    {}();", block));
+                }
+            }
+        }
+        ret.push("    stop(0);".to_owned());
+        ret.push("}".to_owned());
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::parser::Parser;
+    use crate::ir::Extension;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+    use std::process;
+
+    /// Draws `max(3, 4)`, `5 == 5`, `sum(6)`, `6 * 7` and an indirect `double(5)` on the screen.
+    const VM: &str = r#"
+        function Sys.init 0
+        push constant 16384
+        pop pointer 1
+        push constant 3
+        push constant 4
+        call Main.max 2
+        pop that 0
+        push constant 5
+        push constant 5
+        eq
+        pop that 1
+        push constant 6
+        call Main.sum 1
+        pop that 2
+        push constant 6
+        push constant 7
+        mul
+        pop that 3
+        push constant 5
+        push-function Main.double
+        call-indirect 1
+        pop that 4
+        call Sys.halt 0
+        function Main.max 0
+        push argument 0
+        push argument 1
+        gt
+        if-goto FIRST
+        push argument 1
+        return
+        label FIRST
+        push argument 0
+        return
+        function Main.sum 0
+        push argument 0
+        push constant 1
+        lt
+        not
+        if-goto REC
+        push constant 0
+        return
+        label REC
+        push argument 0
+        push argument 0
+        push constant 1
+        sub
+        call Main.sum 1
+        add
+        return
+        function Main.double 0
+        push argument 0
+        push argument 0
+        add
+        return
+        function Math.multiply 0
+        push constant 42
+        return
+        "#;
+
+    /// Compiles and runs `source` in `dir`, returning the screen it outputs, or `None` where no C
+    /// compiler is available.
+    fn run(dir: &Path, source: &str) -> Result<Option<Vec<u8>>> {
+        fs::write(dir.join("main.c"), source)?;
+        let compiled = match process::Command::new("cc")
+            .arg("-o")
+            .arg(dir.join("main"))
+            .arg(dir.join("main.c"))
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        ensure!(
+            compiled.status.success(),
+            "cc failed:\n{}",
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+        let status = process::Command::new(dir.join("main"))
+            .arg(dir.join("screen.pbm"))
+            .status()?;
+        ensure!(status.success(), "The translation failed with {}", status);
+        Ok(Some(fs::read(dir.join("screen.pbm"))?))
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let commands: Vec<_> =
+            Parser::parse_lines_with(VM.as_bytes(), Extension::Instructions.into())?
+                .into_iter()
+                .filter_map(|line| line.command)
+                .collect();
+        let mut translator = CTranslator::new();
+        translator.add_commands("Main", &commands)?;
+        let source = translator.get_source()?.join("\n");
+        assert!(source.contains("void f_Main_dmax(void) {"));
+
+        let dir = std::env::temp_dir().join(format!("c_translator_test_{}", process::id()));
+        fs::create_dir_all(&dir)?;
+        let screen = run(&dir, &source);
+        fs::remove_dir_all(&dir)?;
+        if let Some(screen) = screen? {
+            let header = b"P4\n512 256\n".len();
+            let words: Vec<u16> = screen[header..header + 10]
+                .chunks(2)
+                // Each byte has its leftmost pixel, the least significant bit in Hack, first
+                .map(|bytes| u16::from_le_bytes([bytes[0].reverse_bits(), bytes[1].reverse_bits()]))
+                .collect();
+            assert_eq!(words, [4, 0xFFFF, 21, 42, 10]);
+        }
+
+        let error = CTranslator::new().get_source().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Function `Sys.init` is called but not defined"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+use crate::ir::verifier::Verifier;
+use crate::ir::{
+    split_functions, Command, FunctionCall, MemoryAccess, ProgramFlow, Segment, Symbol,
+};
+use std::collections::HashMap;
+
+/// A function whose body can replace its `call`s.
+struct Inlinable {
+    class: String,
+    n_args: u16,
+    n_locals: u16,
+    body: Vec<Command>,
+    uses_static: bool,
+    /// `pointer` indices the body writes, which are restored after it unlike on `return`.
+    written_pointers: Vec<u16>,
+}
+
+impl Inlinable {
+    /// Caller locals needed to hold the arguments, locals and saved pointers of the callee, and one
+    /// to discard the unused arguments of a call passing `n_args`.
+    fn n_slots(&self, n_args: u16) -> u16 {
+        let discarded = if n_args > self.n_args { 1 } else { 0 };
+        self.n_args + self.n_locals + self.written_pointers.len() as u16 + discarded
+    }
+}
+
+/// Whether every reachable `return` in `function` leaves exactly the returned value on the stack,
+/// so that jumping past the inlined body is equivalent to returning.
+fn returns_single_value(function: &[Command]) -> bool {
+    Verifier::stack_heights(function).is_ok_and(|heights| {
+        function.iter().zip(heights).all(|(command, height)| {
+            command != &Command::FunctionCall(FunctionCall::Return) || height.is_none_or(|h| h == 1)
+        })
+    })
+}
+
+fn inlinable(class: &str, function: &[Command], max_size: usize) -> Option<(Symbol, Inlinable)> {
+    let (name, n_locals, body) = match function {
+        [Command::FunctionCall(FunctionCall::Declare { name, n_locals }), body @ ..] => {
+            (name, *n_locals, body)
+        }
+        _ => return None,
+    };
+    let calls = body.iter().any(|command| {
+        matches!(
+            command,
+            Command::FunctionCall(
+                FunctionCall::Invoke { .. } | FunctionCall::InvokeIndirect { .. }
+            )
+        )
+    });
+    if calls || body.len() > max_size || !returns_single_value(function) {
+        return None;
+    }
+    let mut n_args = 0;
+    let mut uses_static = false;
+    let mut written_pointers = vec![];
+    for command in body {
+        if let Command::MemoryAccess(MemoryAccess::PopIndirect) = command {
+            // Stores may hit THIS and THAT
+            for pointer in 0..2 {
+                if !written_pointers.contains(&pointer) {
+                    written_pointers.push(pointer);
+                }
+            }
+        }
+        let (segment, index) = match command {
+            Command::MemoryAccess(MemoryAccess::Push { segment, index })
+            | Command::MemoryAccess(MemoryAccess::Pop { segment, index }) => (segment, *index),
+            _ => continue,
+        };
+        match segment {
+            // Passed arguments beyond the used ones are discarded at call sites
+            Segment::Argument => n_args = n_args.max(index + 1),
+            Segment::Static => uses_static = true,
+            Segment::Pointer
+                if matches!(command, Command::MemoryAccess(MemoryAccess::Pop { .. }))
+                    && !written_pointers.contains(&index) =>
+            {
+                written_pointers.push(index)
+            }
+            _ => {}
+        }
+    }
+    Some((
+        name.clone(),
+        Inlinable {
+            class: class.to_owned(),
+            n_args,
+            n_locals,
+            body: body.to_vec(),
+            uses_static,
+            written_pointers,
+        },
+    ))
+}
+
+/// Appends `callee` in place of a `call` passing `n_args` arguments, using caller locals from
+/// `base` and labels prefixed by `prefix`.
+fn add_inlined(ret: &mut Vec<Command>, callee: &Inlinable, n_args: u16, base: u16, prefix: &str) {
+    let rename = |label: &Symbol| Symbol(format!("{}:{}", prefix, label.0));
+    let end = Symbol(prefix.to_owned());
+    let saved = |i: usize| base + callee.n_args + callee.n_locals + i as u16;
+    let discarded = saved(callee.written_pointers.len());
+
+    for i in (0..n_args).rev() {
+        ret.push(
+            MemoryAccess::Pop {
+                segment: Segment::Local,
+                index: if i < callee.n_args {
+                    base + i
+                } else {
+                    discarded
+                },
+            }
+            .into(),
+        );
+    }
+    for i in 0..callee.n_locals {
+        ret.push(
+            MemoryAccess::Push {
+                segment: Segment::Constant,
+                index: 0,
+            }
+            .into(),
+        );
+        ret.push(
+            MemoryAccess::Pop {
+                segment: Segment::Local,
+                index: base + callee.n_args + i,
+            }
+            .into(),
+        );
+    }
+    for (i, &pointer) in callee.written_pointers.iter().enumerate() {
+        ret.push(
+            MemoryAccess::Push {
+                segment: Segment::Pointer,
+                index: pointer,
+            }
+            .into(),
+        );
+        ret.push(
+            MemoryAccess::Pop {
+                segment: Segment::Local,
+                index: saved(i),
+            }
+            .into(),
+        );
+    }
+
+    let remap = |segment: &Segment, index: u16| match segment {
+        Segment::Argument => (Segment::Local, base + index),
+        Segment::Local => (Segment::Local, base + callee.n_args + index),
+        _ => (segment.clone(), index),
+    };
+    for command in &callee.body {
+        ret.push(match command {
+            Command::MemoryAccess(MemoryAccess::Push { segment, index }) => {
+                let (segment, index) = remap(segment, *index);
+                MemoryAccess::Push { segment, index }.into()
+            }
+            Command::MemoryAccess(MemoryAccess::Pop { segment, index }) => {
+                let (segment, index) = remap(segment, *index);
+                MemoryAccess::Pop { segment, index }.into()
+            }
+            Command::ProgramFlow(ProgramFlow::Label { label }) => ProgramFlow::Label {
+                label: rename(label),
+            }
+            .into(),
+            Command::ProgramFlow(ProgramFlow::Goto { label }) => ProgramFlow::Goto {
+                label: rename(label),
+            }
+            .into(),
+            Command::ProgramFlow(ProgramFlow::IfGoto { label }) => ProgramFlow::IfGoto {
+                label: rename(label),
+            }
+            .into(),
+            Command::FunctionCall(FunctionCall::Return) => {
+                ProgramFlow::Goto { label: end.clone() }.into()
+            }
+            command => command.clone(),
+        });
+    }
+    ret.push(ProgramFlow::Label { label: end }.into());
+
+    for (i, &pointer) in callee.written_pointers.iter().enumerate() {
+        ret.push(
+            MemoryAccess::Push {
+                segment: Segment::Local,
+                index: saved(i),
+            }
+            .into(),
+        );
+        ret.push(
+            MemoryAccess::Pop {
+                segment: Segment::Pointer,
+                index: pointer,
+            }
+            .into(),
+        );
+    }
+}
+
+pub struct Inliner();
+impl Inliner {
+    /// Replaces `call`s of functions with at most `max_size` commands by their bodies, mapping
+    /// their `argument` and `local` segments onto extra locals of the caller.
+    ///
+    /// Only functions without `call`s are inlined, so inlined bodies never nest.
+    /// Functions using `static` are only inlined into their own class, since other classes can't
+    /// refer to its statics.
+    pub fn inline(
+        classes: Vec<(String, Vec<Command>)>,
+        max_size: usize,
+    ) -> Vec<(String, Vec<Command>)> {
+        let inlinables: HashMap<Symbol, Inlinable> = classes
+            .iter()
+            .flat_map(|(class, commands)| {
+                split_functions(commands)
+                    .into_iter()
+                    .filter_map(move |function| inlinable(class, function, max_size))
+            })
+            .collect();
+
+        classes
+            .iter()
+            .map(|(class, commands)| {
+                let mut ret = Vec::with_capacity(commands.len());
+                for function in split_functions(commands) {
+                    let (name, n_locals, body) = match function {
+                        [Command::FunctionCall(FunctionCall::Declare { name, n_locals }), body @ ..] => {
+                            (name, *n_locals, body)
+                        }
+                        _ => {
+                            ret.extend(function.iter().cloned());
+                            continue;
+                        }
+                    };
+                    let mut inlined = vec![];
+                    let mut n_slots = 0;
+                    let mut n_sites = 0;
+                    for command in body {
+                        let callee = match command {
+                            Command::FunctionCall(FunctionCall::Invoke { name, n_args }) => {
+                                inlinables
+                                    .get(name)
+                                    .filter(|callee| !callee.uses_static || &callee.class == class)
+                                    .filter(|callee| callee.n_args <= *n_args)
+                                    .map(|callee| (name, callee, *n_args))
+                            }
+                            _ => None,
+                        };
+                        match callee {
+                            Some((callee_name, callee, n_args)) => {
+                                let prefix = format!("{}:{}", callee_name.0, n_sites);
+                                add_inlined(&mut inlined, callee, n_args, n_locals, &prefix);
+                                n_slots = n_slots.max(callee.n_slots(n_args));
+                                n_sites += 1;
+                            }
+                            None => inlined.push(command.clone()),
+                        }
+                    }
+                    ret.push(
+                        FunctionCall::Declare {
+                            name: name.clone(),
+                            n_locals: n_locals + n_slots,
+                        }
+                        .into(),
+                    );
+                    ret.extend(inlined);
+                }
+                (class.clone(), ret)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::parser::Parser;
+    use anyhow::Result;
+
+    #[test]
+    fn test() -> Result<()> {
+        let math = Parser::parse(
+            r#"
+            function Math.abs 0
+            push argument 0
+            push constant 0
+            lt
+            if-goto NEGATIVE
+            push argument 0
+            return
+            label NEGATIVE
+            push argument 0
+            neg
+            return
+            function Math.loop 1
+            label LOOP
+            goto LOOP
+            "#
+            .as_bytes(),
+        )?;
+        let main = Parser::parse(
+            r#"
+            function Main.main 1
+            push local 0
+            call Math.abs 1
+            call Main.getX 1
+            return
+            function Main.getX 0
+            push argument 0
+            pop pointer 0
+            push this 0
+            return
+            "#
+            .as_bytes(),
+        )?;
+        let classes = vec![("Math".to_owned(), math.clone()), ("Main".to_owned(), main)];
+        let inlined = Inliner::inline(classes, 10);
+        assert_eq!(inlined[0], ("Math".to_owned(), math));
+        let expected = Parser::parse(
+            r#"
+            function Main.main 3
+            push local 0
+            pop local 1
+            push local 1
+            push constant 0
+            lt
+            if-goto Math.abs:0:NEGATIVE
+            push local 1
+            goto Math.abs:0
+            label Math.abs:0:NEGATIVE
+            push local 1
+            neg
+            goto Math.abs:0
+            label Math.abs:0
+            pop local 1
+            push pointer 0
+            pop local 2
+            push local 1
+            pop pointer 0
+            push this 0
+            goto Main.getX:1
+            label Main.getX:1
+            push local 2
+            pop pointer 0
+            return
+            function Main.getX 0
+            push argument 0
+            pop pointer 0
+            push this 0
+            return
+            "#
+            .as_bytes(),
+        )?;
+        assert_eq!(inlined[1], ("Main".to_owned(), expected));
+
+        let main = Parser::parse(
+            r#"
+            function Main.f 0
+            push constant 1
+            push constant 2
+            call Main.first 2
+            call Main.g 1
+            return
+            function Main.first 0
+            push argument 0
+            return
+            function Main.g 0
+            push argument 0
+            call Main.first 1
+            return
+            "#
+            .as_bytes(),
+        )?;
+        let inlined = Inliner::inline(vec![("Main".to_owned(), main)], 10);
+        let expected = Parser::parse(
+            r#"
+            function Main.f 2
+            push constant 1
+            push constant 2
+            pop local 1
+            pop local 0
+            push local 0
+            goto Main.first:0
+            label Main.first:0
+            call Main.g 1
+            return
+            function Main.first 0
+            push argument 0
+            return
+            function Main.g 1
+            push argument 0
+            pop local 0
+            push local 0
+            goto Main.first:0
+            label Main.first:0
+            return
+            "#
+            .as_bytes(),
+        )?;
+        assert_eq!(inlined[0], ("Main".to_owned(), expected));
+        Ok(())
+    }
+}
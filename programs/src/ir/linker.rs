@@ -0,0 +1,168 @@
+use crate::ir::{Command, FunctionCall, Symbol};
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+
+/// A function declared in a class, or the commands before the first declaration if `name` is
+/// `None`.
+struct Function<'a> {
+    class: usize,
+    name: Option<&'a Symbol>,
+    commands: &'a [Command],
+}
+
+fn split_functions(class: usize, commands: &[Command]) -> Vec<Function<'_>> {
+    super::split_functions(commands)
+        .into_iter()
+        .map(|commands| Function {
+            class,
+            name: match &commands[0] {
+                Command::FunctionCall(FunctionCall::Declare { name, .. }) => Some(name),
+                _ => None,
+            },
+            commands,
+        })
+        .collect()
+}
+
+/// Functions `function` may call, including the library functions of extended arithmetic and
+/// functions whose address it takes.
+fn callees<'a>(function: &Function<'a>) -> impl Iterator<Item = Symbol> + 'a {
+    function
+        .commands
+        .iter()
+        .filter_map(|command| match command {
+            Command::FunctionCall(FunctionCall::Invoke { name, .. })
+            | Command::FunctionCall(FunctionCall::Address { name }) => Some(name.clone()),
+            Command::Arithmetic(arithmetic) => arithmetic
+                .library_function()
+                .map(|name| Symbol(name.to_owned())),
+            _ => None,
+        })
+}
+
+pub struct Linker();
+impl Linker {
+    /// Drops functions of `classes` unreachable from `entry_point` (and from commands outside of
+    /// any function) via `call`s, making sure that every reachable call has its callee defined.
+    pub fn link(
+        classes: Vec<(String, Vec<Command>)>,
+        entry_point: &Symbol,
+    ) -> Result<Vec<(String, Vec<Command>)>> {
+        let functions: Vec<_> = classes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (_, commands))| split_functions(i, commands))
+            .collect();
+        let mut by_name = HashMap::new();
+        for (i, function) in functions.iter().enumerate() {
+            if let Some(name) = function.name {
+                if by_name.insert(name, i).is_some() {
+                    bail!("Function `{}` is defined more than once", name.0);
+                }
+            }
+        }
+
+        let mut reachable = HashSet::new();
+        let mut stack: Vec<_> = (0..functions.len())
+            .filter(|&i| functions[i].name.is_none())
+            .collect();
+        match by_name.get(entry_point) {
+            Some(&i) => stack.push(i),
+            None => bail!("Entry point `{}` is not defined", entry_point.0),
+        }
+        let mut undefined = vec![];
+        while let Some(i) = stack.pop() {
+            if !reachable.insert(i) {
+                continue;
+            }
+            for callee in callees(&functions[i]) {
+                match by_name.get(&callee) {
+                    Some(&j) => stack.push(j),
+                    None => undefined.push(format!(
+                        "`{}` called from `{}`",
+                        callee.0,
+                        functions[i]
+                            .name
+                            .map_or(&classes[functions[i].class].0, |name| &name.0)
+                    )),
+                }
+            }
+        }
+        if !undefined.is_empty() {
+            undefined.sort();
+            undefined.dedup();
+            bail!("Undefined functions: {}", undefined.join(", "));
+        }
+
+        let mut linked: Vec<Vec<Command>> = vec![vec![]; classes.len()];
+        for (i, function) in functions.iter().enumerate() {
+            if reachable.contains(&i) {
+                linked[function.class].extend(function.commands.iter().cloned());
+            }
+        }
+        Ok(classes
+            .into_iter()
+            .zip(linked)
+            .filter(|(_, commands)| !commands.is_empty())
+            .map(|((class, _), commands)| (class, commands))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::parser::Parser;
+
+    #[test]
+    fn test() -> Result<()> {
+        let sys = Parser::parse(
+            r#"
+            function Sys.init 0
+            call Main.main 0
+            label HALT
+            goto HALT
+            function Sys.error 1
+            call Sys.halt 0
+            return
+            "#
+            .as_bytes(),
+        )?;
+        let main = Parser::parse(
+            r#"
+            function Main.main 0
+            call Main.helper 0
+            return
+            function Main.helper 0
+            call Main.main 0
+            return
+            function Main.unused 0
+            call Undefined.function 0
+            return
+            "#
+            .as_bytes(),
+        )?;
+        let unused = Parser::parse("function Unused.f 0\npush constant 0\nreturn".as_bytes())?;
+        let classes = vec![
+            ("Sys".to_owned(), sys.clone()),
+            ("Main".to_owned(), main.clone()),
+            ("Unused".to_owned(), unused),
+        ];
+        let linked = Linker::link(classes, &"Sys.init".parse()?)?;
+        assert_eq!(
+            linked,
+            vec![
+                ("Sys".to_owned(), sys[..4].to_vec()),
+                ("Main".to_owned(), main[..6].to_vec()),
+            ]
+        );
+
+        let classes = vec![("Sys".to_owned(), sys), ("Main".to_owned(), main)];
+        let error = Linker::link(classes, &"Sys.error".parse()?).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Undefined functions: `Sys.halt` called from `Sys.error`"
+        );
+        Ok(())
+    }
+}
@@ -1,14 +1,21 @@
 use crate::regex;
-use anyhow::{anyhow, ensure};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use enumset::{EnumSet, EnumSetType};
+use std::fmt::{self, Display, Formatter};
 use std::str::FromStr;
 
+pub mod c_translator;
+pub mod inliner;
+pub mod linker;
+pub mod optimize;
 pub mod parser;
 pub mod translator;
+pub mod verifier;
 pub mod writer;
 
 type Word = u16;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Arithmetic {
     // u16 or i16
     Add,
@@ -22,9 +29,25 @@ pub enum Arithmetic {
     And,
     Or,
     Not,
+    // Extended instructions
+    Mul,
+    Div,
+    Shl, // by the top of the stack, taken as 0 if negative
+    Shr, // logical, like Shl
+}
+
+impl Arithmetic {
+    /// The OS function computing this operation, which has no Hack counterpart.
+    pub fn library_function(&self) -> Option<&'static str> {
+        match self {
+            Arithmetic::Mul => Some("Math.multiply"),
+            Arithmetic::Div => Some("Math.divide"),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Segment {
     Argument, // per function invocation
     Local,    // per function invocation, initialized with 0
@@ -36,30 +59,36 @@ pub enum Segment {
     Temp,    // has 8 elements
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum MemoryAccess {
     Push { segment: Segment, index: Word },
     Pop { segment: Segment, index: Word },
+    // Extended instructions, popping the address to read, or the value to store then its address
+    PushIndirect,
+    PopIndirect,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub struct Symbol(String);
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum ProgramFlow {
     Label { label: Symbol },
     Goto { label: Symbol },
     IfGoto { label: Symbol },
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum FunctionCall {
     Declare { name: Symbol, n_locals: Word },
     Invoke { name: Symbol, n_args: Word },
     Return,
+    // Extended instructions, pushing the address of a function and calling the popped one
+    Address { name: Symbol },
+    InvokeIndirect { n_args: Word },
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Command {
     Arithmetic(Arithmetic),
     MemoryAccess(MemoryAccess),
@@ -67,6 +96,29 @@ pub enum Command {
     FunctionCall(FunctionCall),
 }
 
+/// An opt-in addition to the standard VM language.
+#[derive(EnumSetType, Debug)]
+pub enum Extension {
+    /// `push constant -N` down to -32768, translated like the equivalent `not` of a constant
+    NegativeConstants,
+    /// `mul`, `div`, `shl`, `shr`, `push-indirect`, `pop-indirect`, `push-function` and
+    /// `call-indirect`
+    Instructions,
+}
+
+/// A source line of VM code, keeping what `Parser::parse` drops so that tools rewriting VM code
+/// can preserve annotations and layout.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Line {
+    /// 1-based line number in the source
+    pub number: usize,
+    pub command: Option<Command>,
+    /// Text following `//`, if any
+    pub comment: Option<String>,
+    /// Text before `//` as written, including whitespace
+    pub code: String,
+}
+
 impl From<Arithmetic> for Command {
     fn from(value: Arithmetic) -> Self {
         Command::Arithmetic(value)
@@ -122,9 +174,163 @@ impl FromStr for Segment {
     }
 }
 
-impl ToString for Arithmetic {
-    fn to_string(&self) -> String {
-        match self {
+impl FromStr for Extension {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "negative-constants" => Ok(Extension::NegativeConstants),
+            "instructions" => Ok(Extension::Instructions),
+            _ => Err(anyhow!("Unknown extension {}", s)),
+        }
+    }
+}
+
+/// Parses the index of `segment`, checking it against the segment's size.
+fn parse_index(segment: &Segment, token: &str, extensions: EnumSet<Extension>) -> Result<Word> {
+    if let (Segment::Constant, Some(magnitude)) = (segment, token.strip_prefix('-')) {
+        ensure!(
+            extensions.contains(Extension::NegativeConstants),
+            "Negative constant {} requires the negative-constants extension",
+            token
+        );
+        let magnitude: Word = magnitude
+            .parse()
+            .with_context(|| format!("Invalid constant {}", token))?;
+        ensure!(magnitude <= 0x8000, "Constant {} is below -32768", token);
+        return Ok(magnitude.wrapping_neg());
+    }
+    let index = token
+        .parse()
+        .with_context(|| format!("Invalid index {}", token))?;
+    let max = match segment {
+        Segment::Constant => 0x7FFF,
+        Segment::Temp => 7,
+        Segment::Pointer => 1,
+        _ => Word::MAX,
+    };
+    ensure!(
+        index <= max,
+        "Index {} is out of range 0-{} of segment {}",
+        index,
+        max,
+        segment
+    );
+    Ok(index)
+}
+
+impl Command {
+    /// Parses a command, accepting the syntax of `extensions` besides the standard one.
+    pub fn parse_with(s: &str, extensions: EnumSet<Extension>) -> Result<Self> {
+        let tokens: Vec<_> = s.split_whitespace().collect();
+        let (&command, operands) = tokens.split_first().with_context(|| "Expected a command")?;
+        let n_operands = match command {
+            "add" | "sub" | "neg" | "eq" | "gt" | "lt" | "and" | "or" | "not" | "return" => 0,
+            "label" | "goto" | "if-goto" => 1,
+            "push" | "pop" | "function" | "call" => 2,
+            "mul" | "div" | "shl" | "shr" | "push-indirect" | "pop-indirect" => {
+                ensure!(
+                    extensions.contains(Extension::Instructions),
+                    "`{}` requires the instructions extension",
+                    command
+                );
+                0
+            }
+            "push-function" | "call-indirect" => {
+                ensure!(
+                    extensions.contains(Extension::Instructions),
+                    "`{}` requires the instructions extension",
+                    command
+                );
+                1
+            }
+            _ => bail!("Unknown command {}", command),
+        };
+        ensure!(
+            operands.len() == n_operands,
+            "`{}` expects {} operand(s) but got {}",
+            command,
+            n_operands,
+            operands.len()
+        );
+        let number = |name, token: &str| {
+            token
+                .parse::<Word>()
+                .with_context(|| format!("Invalid {} {}", name, token))
+        };
+        let ret = match command {
+            "add" => Arithmetic::Add.into(),
+            "sub" => Arithmetic::Sub.into(),
+            "neg" => Arithmetic::Neg.into(),
+            "eq" => Arithmetic::Eq.into(),
+            "gt" => Arithmetic::Gt.into(),
+            "lt" => Arithmetic::Lt.into(),
+            "and" => Arithmetic::And.into(),
+            "or" => Arithmetic::Or.into(),
+            "not" => Arithmetic::Not.into(),
+            "mul" => Arithmetic::Mul.into(),
+            "div" => Arithmetic::Div.into(),
+            "shl" => Arithmetic::Shl.into(),
+            "shr" => Arithmetic::Shr.into(),
+            "push-indirect" => MemoryAccess::PushIndirect.into(),
+            "pop-indirect" => MemoryAccess::PopIndirect.into(),
+            "push" | "pop" => {
+                let segment = operands[0].parse()?;
+                let index = parse_index(&segment, operands[1], extensions)?;
+                if command == "push" {
+                    MemoryAccess::Push { segment, index }.into()
+                } else {
+                    MemoryAccess::Pop { segment, index }.into()
+                }
+            }
+            "label" => ProgramFlow::Label {
+                label: operands[0].parse()?,
+            }
+            .into(),
+            "goto" => ProgramFlow::Goto {
+                label: operands[0].parse()?,
+            }
+            .into(),
+            "if-goto" => ProgramFlow::IfGoto {
+                label: operands[0].parse()?,
+            }
+            .into(),
+            "function" => FunctionCall::Declare {
+                name: operands[0].parse()?,
+                n_locals: number("num locals", operands[1])?,
+            }
+            .into(),
+            "call" => FunctionCall::Invoke {
+                name: operands[0].parse()?,
+                n_args: number("num args", operands[1])?,
+            }
+            .into(),
+            "return" => FunctionCall::Return.into(),
+            "push-function" => FunctionCall::Address {
+                name: operands[0].parse()?,
+            }
+            .into(),
+            "call-indirect" => FunctionCall::InvokeIndirect {
+                n_args: number("num args", operands[0])?,
+            }
+            .into(),
+            _ => unreachable!(),
+        };
+        Ok(ret)
+    }
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Command::parse_with(s, EnumSet::new())
+    }
+}
+
+impl Display for Arithmetic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
             Arithmetic::Add => "add",
             Arithmetic::Sub => "sub",
             Arithmetic::Neg => "neg",
@@ -134,14 +340,18 @@ impl ToString for Arithmetic {
             Arithmetic::And => "and",
             Arithmetic::Or => "or",
             Arithmetic::Not => "not",
-        }
-        .to_owned()
+            Arithmetic::Mul => "mul",
+            Arithmetic::Div => "div",
+            Arithmetic::Shl => "shl",
+            Arithmetic::Shr => "shr",
+        };
+        write!(f, "{}", s)
     }
 }
 
-impl ToString for Segment {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
             Segment::Argument => "argument",
             Segment::Local => "local",
             Segment::Static => "static",
@@ -150,39 +360,102 @@ impl ToString for Segment {
             Segment::That => "that",
             Segment::Pointer => "pointer",
             Segment::Temp => "temp",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Display for MemoryAccess {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            // Only the negative-constants extension yields constants beyond 32767
+            MemoryAccess::Push {
+                segment: Segment::Constant,
+                index,
+            } if *index >= 0x8000 => write!(f, "push constant -{}", index.wrapping_neg()),
+            MemoryAccess::Push { segment, index } => write!(f, "push {} {}", segment, index),
+            MemoryAccess::Pop { segment, index } => write!(f, "pop {} {}", segment, index),
+            MemoryAccess::PushIndirect => write!(f, "push-indirect"),
+            MemoryAccess::PopIndirect => write!(f, "pop-indirect"),
         }
-        .to_owned()
     }
 }
 
-impl ToString for MemoryAccess {
-    fn to_string(&self) -> String {
+impl Display for ProgramFlow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            MemoryAccess::Push { segment, index } => {
-                format!("push {} {}", segment.to_string(), index)
-            }
-            MemoryAccess::Pop { segment, index } => {
-                format!("pop {} {}", segment.to_string(), index)
+            ProgramFlow::Label { label } => write!(f, "label {}", label.0),
+            ProgramFlow::Goto { label } => write!(f, "goto {}", label.0),
+            ProgramFlow::IfGoto { label } => write!(f, "if-goto {}", label.0),
+        }
+    }
+}
+impl Display for FunctionCall {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FunctionCall::Declare { name, n_locals } => {
+                write!(f, "function {} {}", name.0, n_locals)
             }
+            FunctionCall::Invoke { name, n_args } => write!(f, "call {} {}", name.0, n_args),
+            FunctionCall::Return => write!(f, "return"),
+            FunctionCall::Address { name } => write!(f, "push-function {}", name.0),
+            FunctionCall::InvokeIndirect { n_args } => write!(f, "call-indirect {}", n_args),
         }
     }
 }
 
-impl ToString for ProgramFlow {
-    fn to_string(&self) -> String {
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            ProgramFlow::Label { label } => format!("label {}", label.0),
-            ProgramFlow::Goto { label } => format!("goto {}", label.0),
-            ProgramFlow::IfGoto { label } => format!("if-goto {}", label.0),
+            Command::Arithmetic(arithmetic) => arithmetic.fmt(f),
+            Command::MemoryAccess(memory_access) => memory_access.fmt(f),
+            Command::ProgramFlow(program_flow) => program_flow.fmt(f),
+            Command::FunctionCall(function_call) => function_call.fmt(f),
         }
     }
 }
-impl ToString for FunctionCall {
-    fn to_string(&self) -> String {
-        match self {
-            FunctionCall::Declare { name, n_locals } => format!("function {} {}", name.0, n_locals),
-            FunctionCall::Invoke { name, n_args } => format!("call {} {}", name.0, n_args),
-            FunctionCall::Return => "return".to_owned(),
+
+/// Writes the line as it was read, unless `command` was changed since.
+impl Display for Line {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let unchanged = match &self.command {
+            Some(command) => {
+                Command::parse_with(&self.code, EnumSet::all())
+                    .ok()
+                    .as_ref()
+                    == Some(command)
+            }
+            None => self.code.trim().is_empty(),
+        };
+        if unchanged {
+            write!(f, "{}", self.code)?;
+        } else if let Some(command) = &self.command {
+            write!(f, "{}", command)?;
+            if self.comment.is_some() {
+                write!(f, " ")?;
+            }
+        }
+        match &self.comment {
+            Some(comment) => write!(f, "//{}", comment),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Splits `commands` of a class at each `function`, keeping commands before the first one apart.
+fn split_functions(commands: &[Command]) -> Vec<&[Command]> {
+    let mut ret = vec![];
+    let mut start = 0;
+    for end in 1..=commands.len() {
+        if end == commands.len()
+            || matches!(
+                commands[end],
+                Command::FunctionCall(FunctionCall::Declare { .. })
+            )
+        {
+            ret.push(&commands[start..end]);
+            start = end;
         }
     }
+    ret
 }
@@ -0,0 +1,526 @@
+use crate::ir::{Arithmetic, Command, FunctionCall, MemoryAccess, ProgramFlow, Segment, Symbol};
+use std::collections::HashSet;
+
+/// Rewrites `commands` of a class into equivalent but cheaper ones, until no rewrite applies.
+///
+/// `temp 0` is assumed to be a scratch register that isn't read across function boundaries without
+/// being written first, as it is in the Jack compiler output.
+pub fn optimize(mut commands: Vec<Command>) -> Vec<Command> {
+    loop {
+        let optimized = remove_unused_labels(remove_dead_code(remove_discarded_pops(
+            fuse_branches(fold_constants(commands.clone())),
+        )));
+        if optimized == commands {
+            return optimized;
+        }
+        commands = optimized;
+    }
+}
+
+fn push_constant(index: u16) -> Command {
+    MemoryAccess::Push {
+        segment: Segment::Constant,
+        index,
+    }
+    .into()
+}
+
+/// Appends commands pushing `value`, which may not fit in `push constant`.
+fn add_constant(commands: &mut Vec<Command>, value: u16) {
+    if value < 0x8000 {
+        commands.push(push_constant(value));
+    } else {
+        commands.push(push_constant(!value));
+        commands.push(Arithmetic::Not.into());
+    }
+}
+
+/// The value and the number of commands of a constant pushed at the end of `commands`.
+fn trailing_constant(commands: &[Command]) -> Option<(u16, usize)> {
+    let constant = |command: &Command| match command {
+        Command::MemoryAccess(MemoryAccess::Push {
+            segment: Segment::Constant,
+            index,
+        }) => Some(*index),
+        _ => None,
+    };
+    match commands {
+        [.., last] if constant(last).is_some() => constant(last).map(|c| (c, 1)),
+        [.., pushed, Command::Arithmetic(Arithmetic::Not)] => constant(pushed).map(|c| (!c, 2)),
+        [.., pushed, Command::Arithmetic(Arithmetic::Neg)] => {
+            constant(pushed).map(|c| (c.wrapping_neg(), 2))
+        }
+        _ => None,
+    }
+}
+
+/// The result of `arithmetic`, or `None` if it's left to fail at runtime like division by 0.
+fn evaluate(arithmetic: &Arithmetic, x: u16, y: u16) -> Option<u16> {
+    let boolean = |b: bool| if b { !0 } else { 0 };
+    let shift = (y as i16).max(0) as u32;
+    let ret = match arithmetic {
+        Arithmetic::Add => x.wrapping_add(y),
+        Arithmetic::Sub => x.wrapping_sub(y),
+        Arithmetic::Neg => y.wrapping_neg(),
+        Arithmetic::Eq => boolean(x == y),
+        // As translated, by the sign of the wrapping difference
+        Arithmetic::Gt => boolean((x.wrapping_sub(y) as i16) > 0),
+        Arithmetic::Lt => boolean((x.wrapping_sub(y) as i16) < 0),
+        Arithmetic::And => x & y,
+        Arithmetic::Or => x | y,
+        Arithmetic::Not => !y,
+        Arithmetic::Mul => x.wrapping_mul(y),
+        Arithmetic::Div if y == 0 => return None,
+        Arithmetic::Div => (x as i16).wrapping_div(y as i16) as u16,
+        Arithmetic::Shl => x.checked_shl(shift).unwrap_or(0),
+        Arithmetic::Shr => x.checked_shr(shift).unwrap_or(0),
+    };
+    Some(ret)
+}
+
+fn is_unary(arithmetic: &Arithmetic) -> bool {
+    matches!(arithmetic, Arithmetic::Neg | Arithmetic::Not)
+}
+
+/// Whether `x op y == x` for any `x`.
+fn is_right_identity(arithmetic: &Arithmetic, y: u16) -> bool {
+    match arithmetic {
+        Arithmetic::Add | Arithmetic::Sub | Arithmetic::Or => y == 0,
+        Arithmetic::Shl | Arithmetic::Shr => y == 0,
+        Arithmetic::Mul | Arithmetic::Div => y == 1,
+        Arithmetic::And => y == !0,
+        _ => false,
+    }
+}
+
+/// Folds arithmetic on constants, cancels `not; not` and `neg; neg`, drops identity operations
+/// like `push constant 0; add`, and resolves `if-goto` on constants.
+fn fold_constants(commands: Vec<Command>) -> Vec<Command> {
+    let mut ret: Vec<Command> = Vec::with_capacity(commands.len());
+    for command in commands {
+        match &command {
+            Command::Arithmetic(arithmetic) if is_unary(arithmetic) => {
+                if let Some((y, len)) = trailing_constant(&ret) {
+                    ret.truncate(ret.len() - len);
+                    add_constant(&mut ret, evaluate(arithmetic, 0, y).unwrap());
+                } else if ret.last() == Some(&command) {
+                    ret.pop();
+                } else {
+                    ret.push(command);
+                }
+            }
+            Command::Arithmetic(arithmetic) => {
+                if let Some((y, y_len)) = trailing_constant(&ret) {
+                    let rest = &ret[..ret.len() - y_len];
+                    let folded = trailing_constant(rest)
+                        .and_then(|(x, x_len)| Some((evaluate(arithmetic, x, y)?, x_len)));
+                    if let Some((value, x_len)) = folded {
+                        ret.truncate(ret.len() - y_len - x_len);
+                        add_constant(&mut ret, value);
+                    } else if is_right_identity(arithmetic, y) {
+                        ret.truncate(ret.len() - y_len);
+                    } else {
+                        ret.push(command);
+                    }
+                } else {
+                    ret.push(command);
+                }
+            }
+            Command::ProgramFlow(ProgramFlow::IfGoto { label }) => {
+                if let Some((condition, len)) = trailing_constant(&ret) {
+                    ret.truncate(ret.len() - len);
+                    if condition != 0 {
+                        ret.push(
+                            ProgramFlow::Goto {
+                                label: label.clone(),
+                            }
+                            .into(),
+                        );
+                    }
+                } else {
+                    ret.push(command);
+                }
+            }
+            _ => ret.push(command),
+        }
+    }
+    ret
+}
+
+/// Rewrites `eq; not; if-goto L` into `sub; if-goto L`, `not; if-goto L1; goto L2; label L1`
+/// into `if-goto L2; label L1` after `eq`, `gt` or `lt`, and drops `goto L` right before
+/// `label L`. Only comparisons are known to give 0 or -1, the values `not` swaps.
+fn fuse_branches(commands: Vec<Command>) -> Vec<Command> {
+    use Arithmetic::{Eq, Gt, Lt, Not, Sub};
+    use Command::{Arithmetic as A, ProgramFlow as P};
+    use ProgramFlow::{Goto, IfGoto, Label};
+
+    let mut ret = Vec::with_capacity(commands.len());
+    let mut i = 0;
+    while i < commands.len() {
+        match &commands[i..] {
+            [A(comparison @ (Eq | Gt | Lt)), A(Not), P(IfGoto { label }), P(Goto { label: target }), P(Label { label: next }), ..]
+                if label == next =>
+            {
+                ret.push(A(comparison.clone()));
+                ret.push(
+                    IfGoto {
+                        label: target.clone(),
+                    }
+                    .into(),
+                );
+                ret.push(
+                    Label {
+                        label: next.clone(),
+                    }
+                    .into(),
+                );
+                i += 5;
+            }
+            [A(Eq), A(Not), P(IfGoto { label }), ..] => {
+                ret.push(Sub.into());
+                ret.push(
+                    IfGoto {
+                        label: label.clone(),
+                    }
+                    .into(),
+                );
+                i += 3;
+            }
+            [P(Goto { label }), P(Label { label: next }), ..] if label == next => {
+                i += 1;
+            }
+            [command, ..] => {
+                ret.push(command.clone());
+                i += 1;
+            }
+            [] => unreachable!(),
+        }
+    }
+    ret
+}
+
+/// Whether the value popped by the `pop temp 0` at the head of `commands` is certainly discarded,
+/// i.e. it would stay under values pushed later until a `return` drops the frame.
+fn is_discarded_pop(commands: &[Command]) -> bool {
+    let mut depth = 0u16;
+    for command in &commands[1..] {
+        let (consumed, produced) = match command {
+            Command::FunctionCall(FunctionCall::Return) => return depth > 0,
+            Command::Arithmetic(arithmetic) if is_unary(arithmetic) => (1, 1),
+            // Library functions behind them may observe temp 0 as any callee
+            Command::Arithmetic(arithmetic) if arithmetic.library_function().is_some() => {
+                return false
+            }
+            Command::Arithmetic(_) => (2, 1),
+            Command::MemoryAccess(MemoryAccess::Push {
+                segment: Segment::Temp,
+                index: 0,
+            })
+            | Command::MemoryAccess(MemoryAccess::PushIndirect) => return false,
+            Command::MemoryAccess(MemoryAccess::PopIndirect) => (2, 0),
+            Command::MemoryAccess(MemoryAccess::Push { .. }) => (0, 1),
+            Command::MemoryAccess(MemoryAccess::Pop { .. }) => (1, 0),
+            // Callees may observe temp 0, and jumps leave the basic block
+            Command::ProgramFlow(_) | Command::FunctionCall(_) => return false,
+        };
+        if depth < consumed {
+            return false;
+        }
+        depth = depth - consumed + produced;
+    }
+    false
+}
+
+/// Drops `pop temp 0` of values left for `return` to discard anyway, like the one after `do` in
+/// `do Foo.bar(); return;`.
+fn remove_discarded_pops(commands: Vec<Command>) -> Vec<Command> {
+    let discarded_pop = Command::MemoryAccess(MemoryAccess::Pop {
+        segment: Segment::Temp,
+        index: 0,
+    });
+    let mut ret = Vec::with_capacity(commands.len());
+    for (i, command) in commands.iter().enumerate() {
+        if command != &discarded_pop || !is_discarded_pop(&commands[i..]) {
+            ret.push(command.clone());
+        }
+    }
+    ret
+}
+
+/// Drops commands which can't be reached since they follow `goto` or `return` without a label.
+fn remove_dead_code(commands: Vec<Command>) -> Vec<Command> {
+    let mut ret = Vec::with_capacity(commands.len());
+    let mut reachable = true;
+    for command in commands {
+        match &command {
+            Command::ProgramFlow(ProgramFlow::Label { .. })
+            | Command::FunctionCall(FunctionCall::Declare { .. }) => reachable = true,
+            _ => {}
+        }
+        if !reachable {
+            continue;
+        }
+        if let Command::ProgramFlow(ProgramFlow::Goto { .. })
+        | Command::FunctionCall(FunctionCall::Return) = &command
+        {
+            reachable = false;
+        }
+        ret.push(command);
+    }
+    ret
+}
+
+/// Drops labels which no `goto` or `if-goto` in the same function refers to.
+fn remove_unused_labels(commands: Vec<Command>) -> Vec<Command> {
+    let mut ret = Vec::with_capacity(commands.len());
+    let mut function_start = 0;
+    while function_start < commands.len() {
+        let function_end = commands[function_start + 1..]
+            .iter()
+            .position(|command| {
+                matches!(command, Command::FunctionCall(FunctionCall::Declare { .. }))
+            })
+            .map_or(commands.len(), |i| function_start + 1 + i);
+        let function = &commands[function_start..function_end];
+        let used: HashSet<&Symbol> = function
+            .iter()
+            .filter_map(|command| match command {
+                Command::ProgramFlow(ProgramFlow::Goto { label })
+                | Command::ProgramFlow(ProgramFlow::IfGoto { label }) => Some(label),
+                _ => None,
+            })
+            .collect();
+        ret.extend(
+            function
+                .iter()
+                .filter(|command| match command {
+                    Command::ProgramFlow(ProgramFlow::Label { label }) => used.contains(label),
+                    _ => true,
+                })
+                .cloned(),
+        );
+        function_start = function_end;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::parser::Parser;
+    use crate::ir::Extension;
+    use anyhow::Result;
+
+    fn parse(vm: &str) -> Result<Vec<Command>> {
+        let lines = Parser::parse_lines_with(vm.as_bytes(), Extension::Instructions.into())?;
+        Ok(lines.into_iter().filter_map(|line| line.command).collect())
+    }
+
+    fn assert_optimized(input: &str, expected: &str) -> Result<()> {
+        assert_eq!(optimize(parse(input)?), parse(expected)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold_constants() -> Result<()> {
+        assert_optimized(
+            r#"
+            push constant 2
+            push constant 3
+            add
+            push constant 0
+            not
+            push constant 1
+            add
+            push constant 4
+            sub
+            neg
+            not
+            not
+            push constant 0
+            add
+            "#,
+            r#"
+            push constant 5
+            push constant 4
+            "#,
+        )?;
+        assert_optimized(
+            r#"
+            push constant 1
+            push constant 3
+            sub
+            push local 0
+            push constant 0
+            not
+            and
+            "#,
+            r#"
+            push constant 1
+            not
+            push local 0
+            "#,
+        )?;
+        // Comparisons wrap like the translated code, so 20000 > -20000 is false
+        assert_optimized(
+            r#"
+            push constant 20000
+            push constant 20000
+            neg
+            gt
+            push constant 20000
+            push constant 20000
+            neg
+            lt
+            "#,
+            r#"
+            push constant 0
+            push constant 0
+            not
+            "#,
+        )
+    }
+
+    #[test]
+    fn test_fold_extended() -> Result<()> {
+        assert_optimized(
+            r#"
+            push constant 6
+            push constant 7
+            mul
+            push constant 3
+            shl
+            push constant 1
+            push constant 0
+            div
+            push local 0
+            push constant 1
+            mul
+            push constant 0
+            shr
+            "#,
+            r#"
+            push constant 336
+            push constant 1
+            push constant 0
+            div
+            push local 0
+            "#,
+        )
+    }
+
+    #[test]
+    fn test_control_flow() -> Result<()> {
+        assert_optimized(
+            r#"
+            function Main.main 1
+            label WHILE
+            push constant 0
+            not
+            not
+            if-goto END
+            push local 0
+            push constant 1
+            eq
+            not
+            if-goto ELSE
+            push constant 1
+            pop local 0
+            label ELSE
+            call Main.f 0
+            pop temp 0
+            goto WHILE
+            push constant 3
+            label END
+            call Main.f 0
+            pop temp 0
+            push constant 0
+            return
+            function Main.f 0
+            push constant 0
+            not
+            if-goto ALWAYS
+            label NEVER
+            label ALWAYS
+            push constant 0
+            return
+            "#,
+            r#"
+            function Main.main 1
+            label WHILE
+            push local 0
+            push constant 1
+            sub
+            if-goto ELSE
+            push constant 1
+            pop local 0
+            label ELSE
+            call Main.f 0
+            pop temp 0
+            goto WHILE
+            function Main.f 0
+            push constant 0
+            return
+            "#,
+        )?;
+        assert_optimized(
+            r#"
+            function Main.main 0
+            push local 0
+            push constant 3
+            lt
+            not
+            if-goto THEN
+            goto ELSE
+            label THEN
+            push constant 1
+            return
+            label ELSE
+            push constant 2
+            return
+            "#,
+            r#"
+            function Main.main 0
+            push local 0
+            push constant 3
+            lt
+            if-goto ELSE
+            push constant 1
+            return
+            label ELSE
+            push constant 2
+            return
+            "#,
+        )?;
+        // Any value but 0 takes the `if-goto`, so `not` can't be dropped from other conditions
+        assert_optimized(
+            r#"
+            function Main.main 0
+            push local 0
+            not
+            if-goto THEN
+            goto ELSE
+            label THEN
+            push constant 1
+            return
+            label ELSE
+            push constant 2
+            return
+            "#,
+            r#"
+            function Main.main 0
+            push local 0
+            not
+            if-goto THEN
+            goto ELSE
+            label THEN
+            push constant 1
+            return
+            label ELSE
+            push constant 2
+            return
+            "#,
+        )
+    }
+}
@@ -1,127 +1,61 @@
-use crate::common::trim_line_comment;
-use crate::ir::{Arithmetic, Command, FunctionCall, MemoryAccess, ProgramFlow};
-use anyhow::{anyhow, ensure, Context, Result};
-use std::collections::VecDeque;
+use crate::ir::{Command, Extension, Line};
+use anyhow::{Context, Result};
+use enumset::EnumSet;
 use std::io::BufRead;
 
 pub struct Parser();
 impl Parser {
-    fn parse_line(original_line: String) -> Result<Vec<Command>> {
-        let line = trim_line_comment(original_line);
-        let mut tokens: VecDeque<_> = line.split_whitespace().filter(|x| !x.is_empty()).collect();
-        let commands = if let Some(command) = tokens.pop_front() {
-            match command {
-                "add" => {
-                    ensure!(tokens.is_empty());
-                    vec![Arithmetic::Add.into()]
-                }
-                "sub" => {
-                    ensure!(tokens.is_empty());
-                    vec![Arithmetic::Sub.into()]
-                }
-                "neg" => {
-                    ensure!(tokens.is_empty());
-                    vec![Arithmetic::Neg.into()]
-                }
-                "eq" => {
-                    ensure!(tokens.is_empty());
-                    vec![Arithmetic::Eq.into()]
-                }
-                "gt" => {
-                    ensure!(tokens.is_empty());
-                    vec![Arithmetic::Gt.into()]
-                }
-                "lt" => {
-                    ensure!(tokens.is_empty());
-                    vec![Arithmetic::Lt.into()]
-                }
-                "and" => {
-                    ensure!(tokens.is_empty());
-                    vec![Arithmetic::And.into()]
-                }
-                "or" => {
-                    ensure!(tokens.is_empty());
-                    vec![Arithmetic::Or.into()]
-                }
-                "not" => {
-                    ensure!(tokens.is_empty());
-                    vec![Arithmetic::Not.into()]
-                }
-                "push" => {
-                    ensure!(tokens.len() == 2);
-                    let segment = tokens[0].parse()?;
-                    let index = tokens[1].parse()?;
-                    vec![MemoryAccess::Push { segment, index }.into()]
-                }
-                "pop" => {
-                    ensure!(tokens.len() == 2);
-                    let segment = tokens[0].parse()?;
-                    let index = tokens[1].parse()?;
-                    vec![MemoryAccess::Pop { segment, index }.into()]
-                }
-                "label" => {
-                    ensure!(tokens.len() == 1);
-                    let label = tokens[0].parse()?;
-                    vec![ProgramFlow::Label { label }.into()]
-                }
-                "goto" => {
-                    ensure!(tokens.len() == 1);
-                    let label = tokens[0].parse()?;
-                    vec![ProgramFlow::Goto { label }.into()]
-                }
-                "if-goto" => {
-                    ensure!(tokens.len() == 1);
-                    let label = tokens[0].parse()?;
-                    vec![ProgramFlow::IfGoto { label }.into()]
-                }
-                "function" => {
-                    ensure!(tokens.len() == 2);
-                    let name = tokens[0].parse()?;
-                    let n_locals = tokens[1]
-                        .parse()
-                        .with_context(|| format!("Invalid num locals {}", tokens[1]))?;
-                    vec![FunctionCall::Declare { name, n_locals }.into()]
-                }
-                "call" => {
-                    ensure!(tokens.len() == 2);
-                    let name = tokens[0].parse()?;
-                    let n_args = tokens[1]
-                        .parse()
-                        .with_context(|| format!("Invalid num args {}", tokens[1]))?;
-                    vec![FunctionCall::Invoke { name, n_args }.into()]
-                }
-                "return" => {
-                    ensure!(tokens.is_empty());
-                    vec![FunctionCall::Return.into()]
-                }
-                unknown_command => return Err(anyhow!("Unknown command {}", unknown_command)),
-            }
+    fn parse_line(number: usize, line: &str, extensions: EnumSet<Extension>) -> Result<Line> {
+        let (code, comment) = match line.find("//") {
+            Some(i) => (&line[..i], Some(line[i + 2..].to_owned())),
+            None => (line, None),
+        };
+        let command = if code.trim().is_empty() {
+            None
         } else {
-            vec![]
+            Some(Command::parse_with(code, extensions)?)
         };
-        return Ok(commands);
+        Ok(Line {
+            number,
+            command,
+            comment,
+            code: code.to_owned(),
+        })
     }
 
-    pub fn parse<R: BufRead>(input: R) -> Result<Vec<Command>> {
-        let lines = input
+    /// Parses every line of `input`, including blank and comment-only ones.
+    pub fn parse_lines<R: BufRead>(input: R) -> Result<Vec<Line>> {
+        Self::parse_lines_with(input, EnumSet::new())
+    }
+
+    /// Like `parse_lines`, also accepting the syntax of `extensions`.
+    pub fn parse_lines_with<R: BufRead>(
+        input: R,
+        extensions: EnumSet<Extension>,
+    ) -> Result<Vec<Line>> {
+        input
             .lines()
             .enumerate()
-            .map(|(i, res)| res.map(|line| (i, line)).with_context(|| "IO failure"))
-            .collect::<Result<Vec<_>>>()?;
-        let parsed = lines
-            .into_iter()
-            .map(|(i, line)| (i, line.clone(), Self::parse_line(line)))
-            .map(|(i, line, res)| {
-                res.with_context(|| format!("Failed to parse on L:{} `{}`", i + 1, line))
+            .map(|(i, line)| {
+                let line = line.with_context(|| "IO failure")?;
+                Self::parse_line(i + 1, &line, extensions)
+                    .with_context(|| format!("Failed to parse on L:{} `{}`", i + 1, line))
             })
-            .collect::<Result<Vec<_>>>()?;
-        Ok(parsed.into_iter().flat_map(|x| x).collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn parse<R: BufRead>(input: R) -> Result<Vec<Command>> {
+        Ok(Self::parse_lines(input)?
+            .into_iter()
+            .filter_map(|line| line.command)
+            .collect())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::ir::writer::IRWriter;
     use crate::ir::*;
 
     #[test]
@@ -157,4 +91,84 @@ mod test {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_lines() -> Result<()> {
+        let vm = "// Main.vm\n\nfunction Main.main 1\n  push   constant 7 // seven\npop local 0\n";
+        let lines = Parser::parse_lines(vm.as_bytes())?;
+        assert_eq!(
+            lines[3],
+            Line {
+                number: 4,
+                command: Some("push constant 7".parse()?),
+                comment: Some(" seven".to_owned()),
+                code: "  push   constant 7 ".to_owned(),
+            }
+        );
+        let mut written = vec![];
+        let mut writer = IRWriter::new(&mut written);
+        for line in &lines {
+            writer.write_line(line)?;
+        }
+        assert_eq!(String::from_utf8(written)?, vm);
+        let mut line = lines[3].clone();
+        line.command = Some("push constant 8".parse()?);
+        assert_eq!(line.to_string(), "push constant 8 // seven");
+
+        for command in &[
+            "add",
+            "not",
+            "push that 3",
+            "pop pointer 1",
+            "label L.1",
+            "goto L",
+            "if-goto L",
+            "function F.f 2",
+            "call F.f 0",
+            "return",
+        ] {
+            assert_eq!(&command.parse::<Command>()?.to_string(), command);
+        }
+        assert!("push constant".parse::<Command>().is_err());
+        assert!("jump L".parse::<Command>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_indices() -> Result<()> {
+        let error = |vm: &str| format!("{:#}", Parser::parse(vm.as_bytes()).unwrap_err());
+        assert!(error("push constant 32768")
+            .ends_with("Index 32768 is out of range 0-32767 of segment constant"));
+        assert!(error("pop temp 8").ends_with("Index 8 is out of range 0-7 of segment temp"));
+        assert!(error("push pointer 2").ends_with("Index 2 is out of range 0-1 of segment pointer"));
+        assert!(error("push constant -1")
+            .ends_with("Negative constant -1 requires the negative-constants extension"));
+        Parser::parse(
+            "push constant 32767\npush temp 7\npop pointer 1\npush local 40000".as_bytes(),
+        )?;
+
+        let extensions = Extension::NegativeConstants.into();
+        let lines = Parser::parse_lines_with(
+            "push constant -1\npush constant -32768".as_bytes(),
+            extensions,
+        )?;
+        let commands: Vec<_> = lines.into_iter().filter_map(|line| line.command).collect();
+        assert_eq!(
+            commands,
+            vec![
+                Command::MemoryAccess(MemoryAccess::Push {
+                    segment: Segment::Constant,
+                    index: 0xFFFF
+                }),
+                Command::MemoryAccess(MemoryAccess::Push {
+                    segment: Segment::Constant,
+                    index: 0x8000
+                }),
+            ]
+        );
+        assert_eq!(commands[1].to_string(), "push constant -32768");
+        assert!(Parser::parse_lines_with("push constant -32769".as_bytes(), extensions).is_err());
+        assert!(Parser::parse_lines_with("push local -1".as_bytes(), extensions).is_err());
+        Ok(())
+    }
 }
@@ -1,42 +1,232 @@
-use crate::ir::{Arithmetic, Command, FunctionCall, MemoryAccess, ProgramFlow, Segment, Symbol};
-use anyhow::{anyhow, ensure, Context, Result};
+use crate::assembly::symbol_table::SymbolTable;
+use crate::ir::{
+    Arithmetic, Command, FunctionCall, Line, MemoryAccess, ProgramFlow, Segment, Symbol,
+};
+use anyhow::{bail, ensure, Context, Result};
+use enumset::EnumSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 
+/// The RAM region of the `static` segment in the standard VM mapping.
+pub const STATIC_START: u16 = 16;
+pub const STATIC_END: u16 = 256;
+
 #[derive(Debug)]
 struct TranslationContext {
     class: Symbol,
     function: Option<Symbol>,
 }
 
+/// Code placed at ROM address 0, before any translated class.
+///
+/// Each register left as `None` is not touched, and no function is called when `entry_point` is
+/// `None`, so `Bootstrap::none()` emits nothing at all.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Bootstrap {
+    pub sp: Option<u16>,
+    pub lcl: Option<u16>,
+    pub arg: Option<u16>,
+    pub this: Option<u16>,
+    pub that: Option<u16>,
+    pub entry_point: Option<Symbol>,
+}
+
+impl Bootstrap {
+    /// No bootstrap code, as expected by single-file tests like `SimpleAdd` or `StackTest`.
+    pub fn none() -> Self {
+        Bootstrap {
+            sp: None,
+            lcl: None,
+            arg: None,
+            this: None,
+            that: None,
+            entry_point: None,
+        }
+    }
+
+    /// `SP=256` followed by `call Sys.init 0`.
+    pub fn standard() -> Self {
+        Bootstrap {
+            sp: Some(256),
+            entry_point: Some(Symbol("Sys.init".to_owned())),
+            ..Self::none()
+        }
+    }
+}
+
+impl Default for Bootstrap {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+/// Resolved location of a `push`/`pop` operand, from which the shortest instructions are selected.
+#[derive(Debug)]
+enum Operand {
+    Constant(u16),
+    /// A RAM address or a symbol denoting it
+    Direct(String),
+    /// `index` words after the address held in `pointer`
+    Indirect {
+        pointer: &'static str,
+        index: u16,
+    },
+}
+
+// Up to these indices, `*pointer + index` is addressed by stepping with `@pointer A=M+1 A=A+1 ...`,
+// which is no longer than adding `@index D=A` and, when D holds the value, saving it to scratches.
+const MAX_STEPPED_LOAD_INDEX: u16 = 3;
+const MAX_STEPPED_STORE_INDEX: u16 = 10;
+const MAX_STEPPED_POP_INDEX: u16 = 6;
+
+/// Routines emitted once at the end of the program and shared by every site jumping into them.
+#[derive(EnumSetType, Debug)]
+enum SharedRoutine {
+    Call,
+    Return,
+    Eq,
+    Gt,
+    Lt,
+}
+
+impl SharedRoutine {
+    fn label(&self) -> &'static str {
+        match self {
+            SharedRoutine::Call => "$$CALL",
+            SharedRoutine::Return => "$$RETURN",
+            SharedRoutine::Eq => "$$EQ",
+            SharedRoutine::Gt => "$$GT",
+            SharedRoutine::Lt => "$$LT",
+        }
+    }
+}
+
+/// Maps ROM addresses of translated code to the VM source lines it comes from.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    // Ranges by increasing start address, each lasting until the next one
+    ranges: Vec<(u16, Option<String>)>,
+}
+
+impl SourceMap {
+    /// The VM source, like `Main.vm:12`, of the instruction at ROM `address`.
+    pub fn lookup(&self, address: u16) -> Option<&str> {
+        let end = self.ranges.partition_point(|(start, _)| *start <= address);
+        self.ranges[..end]
+            .last()
+            .and_then(|(_, source)| source.as_deref())
+    }
+
+    /// The start address and source of each range of instructions translated from a VM line.
+    pub fn ranges(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.ranges
+            .iter()
+            .filter_map(|(start, source)| source.as_deref().map(|source| (*start, source)))
+    }
+
+    fn set_source(&mut self, address: u16, source: Option<String>) {
+        if let Some((start, current)) = self.ranges.last_mut() {
+            if *current == source {
+                return;
+            }
+            if *start == address {
+                *current = source;
+                return;
+            }
+        }
+        self.ranges.push((address, source));
+    }
+}
+
 pub struct Translator {
     translated_code: Vec<String>,
+    // The ROM address of the next instruction
+    rom_address: u16,
+    source_map: SourceMap,
     next_label_id: usize,
+    compact: bool,
+    used_routines: EnumSet<SharedRoutine>,
+    cache_top_of_stack: bool,
+    // Whether D holds the top of the stack, which then isn't counted by SP
+    top_in_d: bool,
+    // The class of each assembler symbol of a static
+    static_owners: HashMap<String, Symbol>,
+    // The symbols of A-instructions in the order of their first use, and the labels, from which
+    // follow the addresses the assembler gives variables
+    symbols: Vec<String>,
+    used_symbols: HashSet<String>,
+    labels: HashSet<String>,
+}
+
+impl Default for Translator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Translator {
     pub fn new() -> Self {
+        Self::with_bootstrap(&Bootstrap::standard())
+    }
+
+    pub fn with_bootstrap(bootstrap: &Bootstrap) -> Self {
         let mut ret = Translator {
             translated_code: Vec::new(),
+            rom_address: 0,
+            source_map: SourceMap::default(),
             next_label_id: 0,
+            compact: false,
+            used_routines: EnumSet::new(),
+            cache_top_of_stack: false,
+            top_in_d: false,
+            static_owners: HashMap::new(),
+            symbols: Vec::new(),
+            used_symbols: HashSet::new(),
+            labels: HashSet::new(),
         };
-        ret.add_comment("Setup SP");
-        ret.add_assembly(&["@256", "D=A", "@SP", "M=D"]);
-        let mut context = TranslationContext {
-            class: Symbol("$ENTRY_POINT$".to_owned()),
-            function: Some(Symbol("$ENTRY_POINT$".to_owned())),
-        };
-        ret.add_comment("Call Sys.init");
-        ret.add_function_call(
-            &mut context,
-            &FunctionCall::Invoke {
-                name: Symbol("Sys.init".to_owned()),
-                n_args: 0,
-            },
-        )
-        .unwrap();
+        let registers = [
+            ("SP", bootstrap.sp),
+            ("LCL", bootstrap.lcl),
+            ("ARG", bootstrap.arg),
+            ("THIS", bootstrap.this),
+            ("THAT", bootstrap.that),
+        ];
+        for (register, value) in registers.iter() {
+            if let Some(value) = value {
+                ret.add_comment(format!("Setup {}", register));
+                ret.add_set_register(register, *value);
+            }
+        }
+        if let Some(entry_point) = &bootstrap.entry_point {
+            let mut context = TranslationContext {
+                class: Symbol("$ENTRY_POINT$".to_owned()),
+                function: Some(Symbol("$ENTRY_POINT$".to_owned())),
+            };
+            ret.add_comment(format!("Call {}", entry_point.0));
+            ret.add_function_call(
+                &mut context,
+                &FunctionCall::Invoke {
+                    name: entry_point.clone(),
+                    n_args: 0,
+                },
+            )
+            .unwrap();
+        }
         ret
     }
 
+    /// Makes calls, returns and comparisons jump into shared routines instead of being inlined,
+    /// trading a few cycles per operation for a much smaller ROM.
+    pub fn set_compact(&mut self, compact: bool) {
+        self.compact = compact;
+    }
+
+    /// Keeps the top of the stack in D across commands within a basic block, writing it back only
+    /// at labels, jumps, calls and returns.
+    pub fn set_cache_top_of_stack(&mut self, cache_top_of_stack: bool) {
+        self.cache_top_of_stack = cache_top_of_stack;
+    }
+
     fn generate_label(&mut self) -> String {
         let label = format!("$$L{}", self.next_label_id);
         self.next_label_id += 1;
@@ -45,7 +235,20 @@ impl Translator {
 
     fn add_assembly<S: ToString>(&mut self, asm: &[S]) {
         for s in asm {
-            self.translated_code.push(s.to_string())
+            let s = s.to_string();
+            if let Some(label) = s.strip_prefix('(') {
+                self.labels.insert(label.trim_end_matches(')').to_owned());
+            } else if !s.starts_with("//") {
+                self.rom_address += 1;
+            }
+            if let Some(symbol) = s.strip_prefix('@') {
+                let numeric = symbol.starts_with(|c: char| c.is_ascii_digit());
+                if !numeric && !self.used_symbols.contains(symbol) {
+                    self.used_symbols.insert(symbol.to_owned());
+                    self.symbols.push(symbol.to_owned());
+                }
+            }
+            self.translated_code.push(s)
         }
     }
 
@@ -57,6 +260,71 @@ impl Translator {
         self.add_assembly(&[format!("({})", label)]);
     }
 
+    fn add_set_register(&mut self, register: &str, value: u16) {
+        // A-instructions can only load 15 bits, so build the others from their complement
+        if value < 0x8000 {
+            self.add_assembly(&[format!("@{}", value)]);
+            self.add_assembly(&["D=A"]);
+        } else {
+            self.add_assembly(&[format!("@{}", !value)]);
+            self.add_assembly(&["D=!A"]);
+        }
+        self.add_assembly(&[format!("@{}", register)]);
+        self.add_assembly(&["M=D"]);
+    }
+
+    /// Jumps into `routine` with the return address in D.
+    fn add_shared_routine_jump(&mut self, routine: SharedRoutine) {
+        let return_address = self.generate_label();
+        self.used_routines.insert(routine);
+        self.add_assembly(&[format!("@{}", return_address)]);
+        self.add_assembly(&["D=A"]);
+        self.add_assembly(&[format!("@{}", routine.label())]);
+        self.add_assembly(&["0;JMP"]);
+        self.add_label(&return_address);
+    }
+
+    fn add_shared_comparison(&mut self, routine: SharedRoutine, condition: &str) {
+        let end = format!("{}_END", routine.label());
+        self.add_label(&routine.label());
+        self.add_assembly(&["@R13", "M=D"]);
+        self.add_assembly(&["@SP", "AM=M-1", "D=M", "A=A-1", "D=M-D", "M=-1"]);
+        self.add_assembly(&[format!("@{}", end)]);
+        self.add_assembly(&[format!("D;J{}", condition)]);
+        self.add_assembly(&["@SP", "A=M-1", "M=0"]);
+        self.add_label(&end);
+        self.add_assembly(&["@R13", "A=M", "0;JMP"]);
+    }
+
+    fn add_shared_call(&mut self) {
+        // D: return address, R13: callee, R14: the number of arguments
+        self.add_label(&SharedRoutine::Call.label());
+        self.add_assembly(&["@SP", "A=M", "M=D"]);
+        for register in &["LCL", "ARG", "THIS", "THAT"] {
+            self.add_assembly(&[format!("@{}", register)]);
+            self.add_assembly(&["D=M", "@SP", "AM=M+1", "M=D"]);
+        }
+        self.add_assembly(&["@SP", "MD=M+1", "@LCL", "M=D"]);
+        self.add_assembly(&["@R14", "D=D-M", "@5", "D=D-A", "@ARG", "M=D"]);
+        self.add_assembly(&["@R13", "A=M", "0;JMP"]);
+    }
+
+    fn add_shared_return(&mut self) {
+        self.add_label(&SharedRoutine::Return.label());
+        // R13: the end of the frame, R14: the return address
+        self.add_assembly(&["@LCL", "D=M", "@R13", "M=D"]);
+        self.add_assembly(&["@5", "A=D-A", "D=M", "@R14", "M=D"]);
+        // *ARG = pop(), SP = ARG + 1
+        self.add_assembly(&["@SP", "A=M-1", "D=M", "@ARG", "A=M", "M=D"]);
+        self.add_assembly(&["D=A+1", "@SP", "M=D"]);
+        for register in &["THAT", "THIS", "ARG", "LCL"] {
+            self.add_assembly(&["@R13", "AM=M-1", "D=M"]);
+            self.add_assembly(&[format!("@{}", register)]);
+            self.add_assembly(&["M=D"]);
+        }
+        self.add_assembly(&["@R14", "A=M", "0;JMP"]);
+    }
+
     fn add_comparison_arithmetic(&mut self, condition: &str) {
         let one = self.generate_label();
         let end = self.generate_label();
@@ -71,35 +339,154 @@ impl Translator {
         self.add_assembly(&["@SP", "A=M-1", "M=D"]);
     }
 
+    /// Writes the top of the stack held in D back to the RAM.
+    fn add_flush(&mut self) {
+        if self.top_in_d {
+            self.add_push_d();
+            self.top_in_d = false;
+        }
+    }
+
+    /// Moves the top of the stack to D unless it's already there.
+    fn add_load_top(&mut self) {
+        if !self.top_in_d {
+            self.add_assembly(&["@SP", "AM=M-1", "D=M"]);
+            self.top_in_d = true;
+        }
+    }
+
+    fn add_cached_comparison(&mut self, condition: &str) {
+        self.add_load_top();
+        let one = self.generate_label();
+        let end = self.generate_label();
+        self.add_assembly(&["@SP", "AM=M-1", "D=M-D"]);
+        self.add_assembly(&[format!("@{}", one)]);
+        self.add_assembly(&[format!("D;J{}", condition)]);
+        self.add_assembly(&[format!("@{}", end)]);
+        self.add_assembly(&["D=0;JMP"]);
+        self.add_label(&one);
+        self.add_assembly(&["D=-1"]);
+        self.add_label(&end);
+    }
+
+    fn add_cached_arithmetic(&mut self, arithmetic: &Arithmetic) {
+        match arithmetic {
+            Arithmetic::Eq | Arithmetic::Gt | Arithmetic::Lt if self.compact => {
+                // The shared routines work on the stack in the RAM
+                self.add_flush();
+                self.add_shared_routine_jump(match arithmetic {
+                    Arithmetic::Eq => SharedRoutine::Eq,
+                    Arithmetic::Gt => SharedRoutine::Gt,
+                    _ => SharedRoutine::Lt,
+                });
+            }
+            Arithmetic::Eq => self.add_cached_comparison("EQ"),
+            Arithmetic::Gt => self.add_cached_comparison("GT"),
+            Arithmetic::Lt => self.add_cached_comparison("LT"),
+            Arithmetic::Neg | Arithmetic::Not => {
+                self.add_load_top();
+                match arithmetic {
+                    Arithmetic::Neg => self.add_assembly(&["D=-D"]),
+                    _ => self.add_assembly(&["D=!D"]),
+                }
+            }
+            Arithmetic::Shl | Arithmetic::Shr => {
+                self.add_flush();
+                self.add_shift(arithmetic);
+            }
+            Arithmetic::Mul | Arithmetic::Div => unreachable!("Called in add_command"),
+            Arithmetic::Add | Arithmetic::Sub | Arithmetic::And | Arithmetic::Or => {
+                self.add_load_top();
+                self.add_assembly(&["@SP", "AM=M-1"]);
+                match arithmetic {
+                    Arithmetic::Add => self.add_assembly(&["D=M+D"]),
+                    Arithmetic::Sub => self.add_assembly(&["D=M-D"]),
+                    Arithmetic::And => self.add_assembly(&["D=M&D"]),
+                    _ => self.add_assembly(&["D=M|D"]),
+                }
+            }
+        }
+    }
+
     fn add_arithmetic(&mut self, arithmetic: &Arithmetic) {
+        if self.cache_top_of_stack {
+            return self.add_cached_arithmetic(arithmetic);
+        }
         match arithmetic {
             Arithmetic::Add => self.add_assembly(&["@SP", "AM=M-1", "D=M", "A=A-1", "M=M+D"]),
             Arithmetic::Sub => self.add_assembly(&["@SP", "AM=M-1", "D=M", "A=A-1", "M=M-D"]),
             Arithmetic::Neg => self.add_assembly(&["@SP", "A=M-1", "M=-M"]),
+            Arithmetic::Eq if self.compact => self.add_shared_routine_jump(SharedRoutine::Eq),
+            Arithmetic::Gt if self.compact => self.add_shared_routine_jump(SharedRoutine::Gt),
+            Arithmetic::Lt if self.compact => self.add_shared_routine_jump(SharedRoutine::Lt),
             Arithmetic::Eq => self.add_comparison_arithmetic("EQ"),
             Arithmetic::Gt => self.add_comparison_arithmetic("GT"),
             Arithmetic::Lt => self.add_comparison_arithmetic("LT"),
             Arithmetic::And => self.add_assembly(&["@SP", "AM=M-1", "D=M", "A=A-1", "M=M&D"]),
             Arithmetic::Or => self.add_assembly(&["@SP", "AM=M-1", "D=M", "A=A-1", "M=M|D"]),
             Arithmetic::Not => self.add_assembly(&["@SP", "A=M-1", "M=!M"]),
+            Arithmetic::Shl | Arithmetic::Shr => self.add_shift(arithmetic),
+            Arithmetic::Mul | Arithmetic::Div => unreachable!("Called in add_command"),
         }
     }
 
+    /// Shifts the second value of the stack by the top one, bit by bit as Hack has no shifts.
+    fn add_shift(&mut self, arithmetic: &Arithmetic) {
+        let shift = self.generate_label();
+        let end = self.generate_label();
+        self.add_assembly(&["@SP", "AM=M-1", "D=M", "@R13", "M=D"]);
+        if let Arithmetic::Shl = arithmetic {
+            // Double x R13 times
+            self.add_label(&shift);
+            self.add_assembly(&["@R13", "D=M"]);
+            self.add_assembly(&[format!("@{}", end)]);
+            self.add_assembly(&["D;JLE", "@R13", "M=D-1", "@SP", "A=M-1", "D=M", "M=D+M"]);
+            self.add_assembly(&[format!("@{}", shift)]);
+            self.add_assembly(&["0;JMP"]);
+            self.add_label(&end);
+            return;
+        }
+        // Copy each bit of x at R14 = 1 << R13 upward to R15 at R13 = 1 << i
+        let copy = self.generate_label();
+        let next = self.generate_label();
+        let done = self.generate_label();
+        self.add_assembly(&["@R14", "M=1"]);
+        self.add_label(&shift);
+        self.add_assembly(&["@R13", "D=M"]);
+        self.add_assembly(&[format!("@{}", end)]);
+        self.add_assembly(&["D;JLE", "@R13", "M=D-1", "@R14", "D=M", "M=D+M"]);
+        self.add_assembly(&[format!("@{}", shift)]);
+        self.add_assembly(&["0;JMP"]);
+        self.add_label(&end);
+        self.add_assembly(&["@R13", "M=1", "@R15", "M=0"]);
+        self.add_label(&copy);
+        self.add_assembly(&["@R14", "D=M"]);
+        self.add_assembly(&[format!("@{}", done)]);
+        self.add_assembly(&["D;JEQ", "@SP", "A=M-1", "D=D&M"]);
+        self.add_assembly(&[format!("@{}", next)]);
+        self.add_assembly(&["D;JEQ", "@R13", "D=M", "@R15", "M=M|D"]);
+        self.add_label(&next);
+        self.add_assembly(&["@R13", "D=M", "M=D+M", "@R14", "D=M", "M=D+M"]);
+        self.add_assembly(&[format!("@{}", copy)]);
+        self.add_assembly(&["0;JMP"]);
+        self.add_label(&done);
+        self.add_assembly(&["@R15", "D=M", "@SP", "A=M-1", "M=D"]);
+    }
+
+    fn add_push_d(&mut self) {
+        self.add_assembly(&["@SP", "AM=M+1", "A=A-1", "M=D"]);
+    }
+
     fn add_push_const<C: ToString>(&mut self, c: C) {
         self.add_assembly(&[format!("@{}", c.to_string())]);
-        self.add_assembly(&["D=A", "@SP", "A=M", "M=D", "@SP", "M=M+1"]);
+        self.add_assembly(&["D=A"]);
+        self.add_push_d();
     }
 
     fn add_push_const_ref<C: ToString>(&mut self, ptr: C) {
         self.add_assembly(&[format!("@{}", ptr.to_string())]);
-        self.add_assembly(&["D=M", "@SP", "A=M", "M=D", "@SP", "M=M+1"]);
-    }
-
-    fn add_push(&mut self, symbol: &str, index: u16) {
-        self.add_assembly(&[format!("@{}", index)]);
-        self.add_assembly(&["D=A"]);
-        self.add_assembly(&[format!("@{}", symbol)]);
-        self.add_assembly(&["A=M+D", "D=M", "@SP", "A=M", "M=D", "@SP", "M=M+1"]);
+        self.add_assembly(&["D=M"]);
+        self.add_push_d();
     }
 
     fn add_pop_const_ref<C: ToString>(&mut self, ptr: C) {
@@ -108,20 +495,183 @@ impl Translator {
         self.add_assembly(&["M=D"]);
     }
 
-    fn add_pop(&mut self, symbol: &str, index: u16) {
-        self.add_assembly(&[format!("@{}", index)]);
-        self.add_assembly(&["D=A"]);
-        self.add_assembly(&[format!("@{}", symbol)]);
-        self.add_assembly(&["M=M+D"]);
+    fn resolve_operand(
+        context: &TranslationContext,
+        segment: &Segment,
+        index: u16,
+    ) -> Result<Operand> {
+        let pointer = |pointer| Operand::Indirect { pointer, index };
+        let operand = match segment {
+            Segment::Argument => pointer("ARG"),
+            Segment::Local => pointer("LCL"),
+            Segment::Static => Operand::Direct(format!("{}.{}", context.class.0, index)),
+            Segment::Constant => Operand::Constant(index),
+            Segment::This => pointer("THIS"),
+            Segment::That => pointer("THAT"),
+            Segment::Pointer => {
+                ensure!(index < 2);
+                Operand::Direct((3 + index).to_string())
+            }
+            Segment::Temp => {
+                ensure!(index < 8);
+                Operand::Direct((5 + index).to_string())
+            }
+        };
+        Ok(operand)
+    }
 
-        self.add_assembly(&["@SP", "AM=M-1", "D=M"]);
-        self.add_assembly(&[format!("@{}", symbol)]);
-        self.add_assembly(&["A=M", "M=D"]);
+    /// Points A at `*pointer + index` without touching D.
+    fn add_step_address(&mut self, pointer: &str, index: u16) {
+        self.add_assembly(&[format!("@{}", pointer)]);
+        if index == 0 {
+            self.add_assembly(&["A=M"]);
+        } else {
+            self.add_assembly(&["A=M+1"]);
+            for _ in 1..index {
+                self.add_assembly(&["A=A+1"]);
+            }
+        }
+    }
 
-        self.add_assembly(&[format!("@{}", index)]);
-        self.add_assembly(&["D=A"]);
-        self.add_assembly(&[format!("@{}", symbol)]);
-        self.add_assembly(&["M=M-D"]);
+    fn add_load_d(&mut self, operand: &Operand) {
+        match operand {
+            Operand::Constant(0) => self.add_assembly(&["D=0"]),
+            Operand::Constant(1) => self.add_assembly(&["D=1"]),
+            Operand::Constant(0xFFFF) => self.add_assembly(&["D=-1"]),
+            // A-instructions can only load 15 bits, as in add_set_register
+            Operand::Constant(c) if *c >= 0x8000 => {
+                self.add_assembly(&[format!("@{}", !c)]);
+                self.add_assembly(&["D=!A"]);
+            }
+            Operand::Constant(c) => {
+                self.add_assembly(&[format!("@{}", c)]);
+                self.add_assembly(&["D=A"]);
+            }
+            Operand::Direct(address) => {
+                self.add_assembly(&[format!("@{}", address)]);
+                self.add_assembly(&["D=M"]);
+            }
+            &Operand::Indirect { pointer, index } if index <= MAX_STEPPED_LOAD_INDEX => {
+                self.add_step_address(pointer, index);
+                self.add_assembly(&["D=M"]);
+            }
+            Operand::Indirect { pointer, index } => {
+                self.add_assembly(&[format!("@{}", index)]);
+                self.add_assembly(&["D=A"]);
+                self.add_assembly(&[format!("@{}", pointer)]);
+                self.add_assembly(&["A=M+D", "D=M"]);
+            }
+        }
+    }
+
+    /// Stores D to `operand`, which must not be a constant.
+    fn add_store_d(&mut self, operand: &Operand) {
+        match operand {
+            Operand::Constant(_) => unreachable!(),
+            Operand::Direct(address) => {
+                self.add_assembly(&[format!("@{}", address)]);
+                self.add_assembly(&["M=D"]);
+            }
+            &Operand::Indirect { pointer, index } if index <= MAX_STEPPED_STORE_INDEX => {
+                self.add_step_address(pointer, index);
+                self.add_assembly(&["M=D"]);
+            }
+            Operand::Indirect { pointer, index } => {
+                self.add_assembly(&["@R13", "M=D"]);
+                self.add_assembly(&[format!("@{}", index)]);
+                self.add_assembly(&["D=A"]);
+                self.add_assembly(&[format!("@{}", pointer)]);
+                self.add_assembly(&["D=M+D", "@R14", "M=D", "@R13", "D=M", "@R14", "A=M", "M=D"]);
+            }
+        }
+    }
+
+    fn add_push_operand(&mut self, operand: &Operand) {
+        match operand {
+            Operand::Constant(0) => self.add_assembly(&["@SP", "AM=M+1", "A=A-1", "M=0"]),
+            Operand::Constant(1) => self.add_assembly(&["@SP", "AM=M+1", "A=A-1", "M=1"]),
+            Operand::Constant(0xFFFF) => self.add_assembly(&["@SP", "AM=M+1", "A=A-1", "M=-1"]),
+            _ => {
+                self.add_load_d(operand);
+                self.add_push_d();
+            }
+        }
+    }
+
+    fn add_pop_operand(&mut self, operand: &Operand) {
+        match operand {
+            &Operand::Indirect { pointer, index } if index > MAX_STEPPED_POP_INDEX => {
+                // Compute the address to R13 first, as D will be occupied by the value
+                self.add_assembly(&[format!("@{}", index)]);
+                self.add_assembly(&["D=A"]);
+                self.add_assembly(&[format!("@{}", pointer)]);
+                self.add_assembly(&["D=M+D", "@R13", "M=D"]);
+                self.add_assembly(&["@SP", "AM=M-1", "D=M", "@R13", "A=M", "M=D"]);
+            }
+            _ => {
+                self.add_assembly(&["@SP", "AM=M-1", "D=M"]);
+                self.add_store_d(operand);
+            }
+        }
+    }
+
+    /// Checks that the static behind `operand` can fit in the static segment, which the assembler
+    /// shares with any other variables.
+    fn allocate_static(
+        &mut self,
+        context: &TranslationContext,
+        segment: &Segment,
+        operand: &Operand,
+    ) -> Result<()> {
+        let symbol = match (segment, operand) {
+            (Segment::Static, Operand::Direct(symbol)) => symbol,
+            _ => return Ok(()),
+        };
+        if self.static_owners.contains_key(symbol) {
+            return Ok(());
+        }
+        let address = STATIC_START + self.static_owners.len() as u16;
+        ensure!(
+            address < STATIC_END,
+            "Static `{}` doesn't fit in RAM {}-{} since the program has more than {} statics",
+            symbol,
+            STATIC_START,
+            STATIC_END - 1,
+            STATIC_END - STATIC_START
+        );
+        self.static_owners
+            .insert(symbol.clone(), context.class.clone());
+        Ok(())
+    }
+
+    /// The RAM addresses the assembler will give the statics, by class in the order of their first
+    /// static. Fails if other variables pushed statics past the static segment.
+    pub fn static_allocation(&self) -> Result<Vec<(String, Vec<u16>)>> {
+        let mut ret: Vec<(String, Vec<u16>)> = vec![];
+        let mut address = STATIC_START;
+        for symbol in &self.symbols {
+            // Shared routines are only added by `get_assembly`
+            let routine = self.used_routines.iter().any(|r| *symbol == r.label());
+            if routine || self.labels.contains(symbol) || SymbolTable::is_predefined(symbol) {
+                continue;
+            }
+            if let Some(class) = self.static_owners.get(symbol) {
+                ensure!(
+                    address < STATIC_END,
+                    "Static `{}` is placed at RAM {} after the other variables, outside of {}-{}",
+                    symbol,
+                    address,
+                    STATIC_START,
+                    STATIC_END - 1
+                );
+                match ret.iter_mut().find(|(owner, _)| owner == &class.0) {
+                    Some((_, addresses)) => addresses.push(address),
+                    None => ret.push((class.0.clone(), vec![address])),
+                }
+            }
+            address += 1;
+        }
+        Ok(ret)
     }
 
     fn add_memory_access(
@@ -130,71 +680,118 @@ impl Translator {
         memory_access: &MemoryAccess,
     ) -> Result<()> {
         match memory_access {
-            MemoryAccess::Push { segment, index } => match segment {
-                Segment::Argument => self.add_push("ARG", *index),
-                Segment::Local => self.add_push("LCL", *index),
-                Segment::Static => {
-                    self.add_push_const_ref(format!("{}.{}", context.class.0, index))
+            MemoryAccess::Push { segment, index } => {
+                let operand = Self::resolve_operand(context, segment, *index)?;
+                self.allocate_static(context, segment, &operand)?;
+                if self.cache_top_of_stack {
+                    self.add_flush();
+                    self.add_load_d(&operand);
+                    self.top_in_d = true;
+                } else {
+                    self.add_push_operand(&operand);
                 }
-                Segment::Constant => self.add_push_const(index),
-                Segment::This => self.add_push("THIS", *index),
-                Segment::That => self.add_push("THAT", *index),
-                Segment::Pointer => {
-                    ensure!(index < &2);
-                    self.add_push_const_ref(3 + index);
+            }
+            MemoryAccess::Pop { segment, index } => {
+                let operand = Self::resolve_operand(context, segment, *index)?;
+                self.allocate_static(context, segment, &operand)?;
+                if let Operand::Constant(_) = operand {
+                    bail!("Unable to pop to {:?}", memory_access);
                 }
-                Segment::Temp => {
-                    ensure!(index < &8);
-                    self.add_push_const_ref(5 + index);
+                if self.top_in_d {
+                    self.add_store_d(&operand);
+                    self.top_in_d = false;
+                } else {
+                    self.add_pop_operand(&operand);
                 }
-            },
-            MemoryAccess::Pop { segment, index } => match segment {
-                Segment::Argument => self.add_pop("ARG", *index),
-                Segment::Local => self.add_pop("LCL", *index),
-                Segment::Static => self.add_pop_const_ref(format!("{}.{}", context.class.0, index)),
-                Segment::Constant => return Err(anyhow!("Unable to pop to {:?}", memory_access)),
-                Segment::This => self.add_pop("THIS", *index),
-                Segment::That => self.add_pop("THAT", *index),
-                Segment::Pointer => {
-                    ensure!(index < &2);
-                    self.add_pop_const_ref(3 + index);
-                }
-                Segment::Temp => {
-                    ensure!(index < &8);
-                    self.add_pop_const_ref(5 + index);
+            }
+            MemoryAccess::PushIndirect if self.cache_top_of_stack => {
+                self.add_load_top();
+                self.add_assembly(&["A=D", "D=M"]);
+            }
+            MemoryAccess::PushIndirect => {
+                self.add_assembly(&["@SP", "A=M-1", "A=M", "D=M", "@SP", "A=M-1", "M=D"]);
+            }
+            MemoryAccess::PopIndirect => {
+                if !self.top_in_d {
+                    self.add_assembly(&["@SP", "AM=M-1", "D=M"]);
                 }
-            },
+                self.add_assembly(&["@SP", "AM=M-1", "A=M", "M=D"]);
+                self.top_in_d = false;
+            }
         }
         Ok(())
     }
 
+    fn function_label(context: &TranslationContext, label: &Symbol) -> String {
+        // Labels outside of any function (e.g. in bootstrap-less tests) are scoped by the class
+        let function = context.function.as_ref().unwrap_or(&context.class);
+        format!("{}${}", function.0, label.0)
+    }
+
     fn add_program_flow(
         &mut self,
         context: &TranslationContext,
         program_flow: &ProgramFlow,
     ) -> Result<()> {
-        let function = &context
-            .function
-            .as_ref()
-            .with_context(|| "Expected a function")?
-            .0;
         match program_flow {
             ProgramFlow::Label { label } => {
-                self.add_label(&format!("{}${}", function, &label.0));
+                self.add_label(&Self::function_label(context, label));
             }
             ProgramFlow::Goto { label } => {
-                self.add_assembly(&[format!("@{}${}", function, &label.0)]);
+                self.add_assembly(&[format!("@{}", Self::function_label(context, label))]);
                 self.add_assembly(&["0;JMP"]);
             }
-            ProgramFlow::IfGoto { label } => {
-                self.add_assembly(&["@SP", "AM=M-1", "D=M"]);
-                self.add_assembly(&[format!("@{}${}", function, &label.0)]);
-                self.add_assembly(&["D;JNE"]);
-            }
+            ProgramFlow::IfGoto { label } => self.add_conditional_jump(context, "JNE", label),
         }
         Ok(())
     }
 
+    /// Pops the top of the stack and jumps to `label` if it meets `condition`.
+    fn add_conditional_jump(
+        &mut self,
+        context: &TranslationContext,
+        condition: &str,
+        label: &Symbol,
+    ) {
+        self.add_load_top();
+        self.add_assembly(&[format!("@{}", Self::function_label(context, label))]);
+        self.add_assembly(&[format!("D;{}", condition)]);
+        self.top_in_d = false;
+    }
+
+    /// Recognizes `eq`, `gt` or `lt` followed by an optional `not` and `if-goto`, which then can be
+    /// a single jump on the difference of the operands without materializing a boolean. A `not`
+    /// alone isn't fused, as `if-goto` jumps on any value but 0 and `not` only maps -1 to 0.
+    ///
+    /// Returns the number of the commands, the jump condition and the label.
+    fn match_compare_and_branch(commands: &[Command]) -> Option<(usize, &'static str, &Symbol)> {
+        use Command::{Arithmetic as A, ProgramFlow as P};
+        let condition = |comparison: &Arithmetic, negated: bool| match (comparison, negated) {
+            (Arithmetic::Eq, false) => Some("JEQ"),
+            (Arithmetic::Gt, false) => Some("JGT"),
+            (Arithmetic::Lt, false) => Some("JLT"),
+            (Arithmetic::Eq, true) => Some("JNE"),
+            (Arithmetic::Gt, true) => Some("JLE"),
+            (Arithmetic::Lt, true) => Some("JGE"),
+            _ => None,
+        };
+        match commands {
+            [A(comparison), A(Arithmetic::Not), P(ProgramFlow::IfGoto { label }), ..] => {
+                condition(comparison, true).map(|condition| (3, condition, label))
+            }
+            [A(comparison), P(ProgramFlow::IfGoto { label }), ..] => {
+                condition(comparison, false).map(|condition| (2, condition, label))
+            }
+            _ => None,
+        }
+    }
+
+    /// Pops the two operands of a comparison and leaves their difference in D.
+    fn add_difference(&mut self) {
+        self.add_load_top();
+        self.add_assembly(&["@SP", "AM=M-1", "D=M-D"]);
+    }
+
     fn add_function_call(
         &mut self,
         context: &mut TranslationContext,
@@ -205,39 +802,25 @@ impl Translator {
                 self.add_label(&name.0);
                 self.add_comment("Push locals");
                 for _ in 0..*n_locals {
-                    self.add_push_const(0);
+                    self.add_push_operand(&Operand::Constant(0));
                 }
                 context.function = Some(name.clone());
                 self.add_comment("Function body");
             }
-            FunctionCall::Invoke { name, n_args } => {
-                let return_address = self.generate_label();
-                self.add_comment("  Push return address");
-                self.add_push_const(&return_address);
-                self.add_comment("  Push LCL");
-                self.add_push_const_ref("LCL");
-                self.add_comment("  Push ARG");
-                self.add_push_const_ref("ARG");
-                self.add_comment("  Push THIS");
-                self.add_push_const_ref("THIS");
-                self.add_comment("  Push THAT");
-                self.add_push_const_ref("THAT");
-
-                // ARG = SP - n - 5
-                self.add_comment("  Set ARG = SP - n - 5");
-                self.add_assembly(&["@SP", "D=M"]);
-                self.add_assembly(&[format!("@{}", n_args)]);
-                self.add_assembly(&["D=D-A", "@5", "D=D-A", "@ARG", "M=D"]);
-
-                // LCL = SP
-                self.add_comment("  Set LCL = SP");
-                self.add_assembly(&["@SP", "D=M", "@LCL", "M=D"]);
-
-                // GOTO func
-                self.add_comment(format!("  Goto func {}", name.0));
+            FunctionCall::Invoke { name, n_args } => self.add_call(Some(&name.0), *n_args),
+            FunctionCall::Address { name } => {
                 self.add_assembly(&[format!("@{}", name.0)]);
+                self.add_assembly(&["D=A"]);
+                self.add_push_d();
+            }
+            FunctionCall::InvokeIndirect { n_args } => {
+                self.add_pop_const_ref("R13");
+                self.add_call(None, *n_args);
+            }
+            FunctionCall::Return if self.compact => {
+                self.used_routines.insert(SharedRoutine::Return);
+                self.add_assembly(&[format!("@{}", SharedRoutine::Return.label())]);
                 self.add_assembly(&["0;JMP"]);
-                self.add_label(&return_address);
             }
             FunctionCall::Return => {
                 // Result (R15) = pop()
@@ -277,9 +860,67 @@ impl Translator {
         Ok(())
     }
 
+    /// Calls `callee`, or the function at the address in R13 if `None`, with `n_args` arguments.
+    fn add_call(&mut self, callee: Option<&str>, n_args: u16) {
+        if self.compact {
+            if let Some(callee) = callee {
+                self.add_assembly(&[format!("@{}", callee)]);
+                self.add_assembly(&["D=A", "@R13", "M=D"]);
+            }
+            self.add_assembly(&[format!("@{}", n_args)]);
+            self.add_assembly(&["D=A", "@R14", "M=D"]);
+            self.add_shared_routine_jump(SharedRoutine::Call);
+            return;
+        }
+        let return_address = self.generate_label();
+        self.add_comment("  Push return address");
+        self.add_push_const(&return_address);
+        self.add_comment("  Push LCL");
+        self.add_push_const_ref("LCL");
+        self.add_comment("  Push ARG");
+        self.add_push_const_ref("ARG");
+        self.add_comment("  Push THIS");
+        self.add_push_const_ref("THIS");
+        self.add_comment("  Push THAT");
+        self.add_push_const_ref("THAT");
+
+        // ARG = SP - n - 5
+        self.add_comment("  Set ARG = SP - n - 5");
+        self.add_assembly(&["@SP", "D=M"]);
+        self.add_assembly(&[format!("@{}", n_args)]);
+        self.add_assembly(&["D=D-A", "@5", "D=D-A", "@ARG", "M=D"]);
+
+        // LCL = SP
+        self.add_comment("  Set LCL = SP");
+        self.add_assembly(&["@SP", "D=M", "@LCL", "M=D"]);
+
+        // GOTO func
+        match callee {
+            Some(callee) => {
+                self.add_comment(format!("  Goto func {}", callee));
+                self.add_assembly(&[format!("@{}", callee)]);
+            }
+            None => self.add_assembly(&["@R13", "A=M"]),
+        }
+        self.add_assembly(&["0;JMP"]);
+        self.add_label(&return_address);
+    }
+
     fn add_command(&mut self, context: &mut TranslationContext, command: &Command) -> Result<()> {
         match command {
-            Command::Arithmetic(arithmetic) => self.add_arithmetic(arithmetic),
+            // Jump targets and callees expect the whole stack in the RAM
+            Command::ProgramFlow(ProgramFlow::IfGoto { .. }) => {}
+            Command::ProgramFlow(_) | Command::FunctionCall(_) => self.add_flush(),
+            Command::Arithmetic(arithmetic) if arithmetic.library_function().is_some() => {
+                self.add_flush()
+            }
+            _ => {}
+        }
+        match command {
+            Command::Arithmetic(arithmetic) => match arithmetic.library_function() {
+                Some(function) => self.add_call(Some(function), 2),
+                None => self.add_arithmetic(arithmetic),
+            },
             Command::MemoryAccess(memory_access) => {
                 self.add_memory_access(context, memory_access)?
             }
@@ -291,7 +932,40 @@ impl Translator {
         Ok(())
     }
 
-    pub fn add_commands(&mut self, class: &str, commands: &Vec<Command>) -> Result<()> {
+    pub fn add_commands(&mut self, class: &str, commands: &[Command]) -> Result<()> {
+        self.add_commands_from(class, commands, None)
+    }
+
+    /// Translates the commands of `lines` parsed from `{class}.vm`, recording in the source map
+    /// which line each instruction comes from.
+    pub fn add_lines(&mut self, class: &str, lines: &[Line]) -> Result<()> {
+        let (numbers, commands): (Vec<_>, Vec<_>) = lines
+            .iter()
+            .filter_map(|line| line.command.clone().map(|command| (line.number, command)))
+            .unzip();
+        self.add_commands_from(class, &commands, Some(&numbers))
+    }
+
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    fn set_source(
+        &mut self,
+        context: &TranslationContext,
+        line_numbers: Option<&[usize]>,
+        i: usize,
+    ) {
+        let source = line_numbers.map(|numbers| format!("{}.vm:{}", context.class.0, numbers[i]));
+        self.source_map.set_source(self.rom_address, source);
+    }
+
+    fn add_commands_from(
+        &mut self,
+        class: &str,
+        commands: &[Command],
+        line_numbers: Option<&[usize]>,
+    ) -> Result<()> {
         let class = class
             .parse()
             .with_context(|| format!("Class name `{}` is invalid", class))?;
@@ -300,19 +974,48 @@ impl Translator {
             function: None,
         };
         self.add_comment(format!("-- Class: {} --", context.class.0));
-        for command in commands {
-            self.add_comment(format!("{:?}", command));
+        let mut rest = commands;
+        while let Some(command) = rest.first() {
+            self.set_source(&context, line_numbers, commands.len() - rest.len());
+            if let Some((len, condition, label)) = Self::match_compare_and_branch(rest) {
+                for command in &rest[..len] {
+                    self.add_comment(command);
+                }
+                self.add_difference();
+                self.add_conditional_jump(&context, condition, label);
+                rest = &rest[len..];
+                continue;
+            }
+            self.add_comment(command);
             self.add_command(&mut context, command).with_context(|| {
                 format!(
-                    "Unable to translate: Command: {:?}, Context: {:?}",
+                    "Unable to translate: Command: `{}`, Context: {:?}",
                     command, context
                 )
             })?;
+            rest = &rest[1..];
         }
+        self.add_flush();
+        self.source_map.set_source(self.rom_address, None);
         Ok(())
     }
 
-    pub fn get_assembly(self) -> Vec<String> {
+    pub fn get_assembly(mut self) -> Vec<String> {
+        if !self.used_routines.is_empty() {
+            self.add_comment("-- Shared routines --");
+            // Don't run into the routines when the program falls off its end
+            self.add_label(&"$$HALT");
+            self.add_assembly(&["@$$HALT", "0;JMP"]);
+        }
+        for routine in self.used_routines {
+            match routine {
+                SharedRoutine::Call => self.add_shared_call(),
+                SharedRoutine::Return => self.add_shared_return(),
+                SharedRoutine::Eq => self.add_shared_comparison(routine, "EQ"),
+                SharedRoutine::Gt => self.add_shared_comparison(routine, "GT"),
+                SharedRoutine::Lt => self.add_shared_comparison(routine, "LT"),
+            }
+        }
         self.translated_code
     }
 }
@@ -320,9 +1023,76 @@ impl Translator {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::assembly::assembler::Assembler;
+    use crate::assembly::emulator::Emulator;
+    use crate::assembly::parser::Parser as AsmParser;
     use crate::ir::parser::Parser;
+    use crate::ir::Extension;
     use anyhow::Result;
 
+    const SYS: &str = r#"
+        function Sys.init 0
+        push constant 3
+        push constant 4
+        call Main.max 2
+        pop static 0
+        push constant 5
+        push constant 5
+        eq
+        pop static 1
+        push constant 6
+        call Main.sum 1
+        pop static 2
+        label END
+        goto END
+        "#;
+    const MAIN: &str = r#"
+        function Main.max 0
+        push argument 0
+        push argument 1
+        gt
+        if-goto FIRST
+        push argument 1
+        return
+        label FIRST
+        push argument 0
+        return
+        function Main.sum 0
+        push argument 0
+        push constant 1
+        lt
+        not
+        if-goto REC
+        push constant 0
+        return
+        label REC
+        push argument 0
+        push argument 0
+        push constant 1
+        sub
+        call Main.sum 1
+        add
+        return
+        "#;
+
+    fn run(translator: Translator) -> Result<(usize, Emulator)> {
+        let asm = translator.get_assembly().join("\n");
+        let rom = Assembler::assemble(AsmParser::parse(asm.as_bytes())?)?;
+        let size = rom.len();
+        let mut emulator = Emulator::new(rom);
+        emulator.run(100_000)?;
+        Ok((size, emulator))
+    }
+
+    fn run_program(translator: Translator) -> Result<(usize, Emulator)> {
+        let mut translator = translator;
+        translator.add_commands("Sys", &Parser::parse(SYS.as_bytes())?)?;
+        translator.add_commands("Main", &Parser::parse(MAIN.as_bytes())?)?;
+        let (size, emulator) = run(translator)?;
+        assert_eq!(emulator.ram()[16..19], [4, 0xFFFF, 21]);
+        Ok((size, emulator))
+    }
+
     #[test]
     fn test() -> Result<()> {
         let commands = Parser::parse(
@@ -341,4 +1111,428 @@ mod test {
         // println!("{:?}", translated);
         Ok(())
     }
+
+    #[test]
+    fn test_bootstrap() -> Result<()> {
+        let commands = Parser::parse(
+            r#"
+        label LOOP
+        push constant 1
+        if-goto LOOP
+        "#
+            .as_bytes(),
+        )?;
+        let mut translator = Translator::with_bootstrap(&Bootstrap::none());
+        translator.add_commands("test", &commands)?;
+        let translated = translator.get_assembly();
+        assert_eq!(
+            translated.iter().find(|line| !line.starts_with("//")),
+            Some(&"(test$LOOP)".to_owned())
+        );
+
+        let bootstrap = Bootstrap {
+            lcl: Some(0xFFFF),
+            entry_point: Some("Main.main".parse()?),
+            ..Bootstrap::standard()
+        };
+        let translated = Translator::with_bootstrap(&bootstrap).get_assembly();
+        let code: Vec<_> = translated
+            .iter()
+            .filter(|line| !line.starts_with("//"))
+            .collect();
+        assert_eq!(
+            code[..8],
+            ["@256", "D=A", "@SP", "M=D", "@0", "D=!A", "@LCL", "M=D"]
+        );
+        assert!(code.contains(&&"@Main.main".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> Result<()> {
+        let (inlined_size, _) = run_program(Translator::new())?;
+        let mut translator = Translator::new();
+        translator.set_compact(true);
+        let (compact_size, _) = run_program(translator)?;
+        assert!(compact_size < inlined_size);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_top_of_stack() -> Result<()> {
+        let (inlined_size, inlined) = run_program(Translator::new())?;
+        let mut translator = Translator::new();
+        translator.set_cache_top_of_stack(true);
+        let (cached_size, cached) = run_program(translator)?;
+        assert!(cached_size < inlined_size);
+        assert!(cached.cycles() < inlined.cycles());
+
+        let mut translator = Translator::new();
+        translator.set_compact(true);
+        translator.set_cache_top_of_stack(true);
+        run_program(translator)?;
+
+        // (a + b) * 2 - (c & d | ~e) with everything but a in the memory segments
+        let expression = Parser::parse(
+            r#"
+            push constant 7
+            pop local 1
+            push constant 12
+            pop argument 2
+            push constant 10
+            pop temp 3
+            push constant 3
+            push local 1
+            add
+            pop static 0
+            push static 0
+            push static 0
+            add
+            push argument 2
+            push temp 3
+            and
+            push constant 5
+            not
+            or
+            sub
+            pop static 1
+            "#
+            .as_bytes(),
+        )?;
+        let mut sizes = vec![];
+        let mut cycles = vec![];
+        for &cache_top_of_stack in &[false, true] {
+            let mut translator = Translator::with_bootstrap(&Bootstrap {
+                sp: Some(256),
+                lcl: Some(300),
+                arg: Some(400),
+                ..Bootstrap::none()
+            });
+            translator.set_cache_top_of_stack(cache_top_of_stack);
+            translator.add_commands("Test", &expression)?;
+            let asm = translator.get_assembly().join("\n");
+            let rom = Assembler::assemble(AsmParser::parse(asm.as_bytes())?)?;
+            sizes.push(rom.len());
+            let mut emulator = Emulator::new(rom);
+            while (emulator.pc() as usize) < sizes[sizes.len() - 1] {
+                emulator.step()?;
+            }
+            assert_eq!(
+                emulator.ram()[16..18],
+                [10, 20u16.wrapping_sub(12 & 10 | !5)]
+            );
+            assert_eq!(emulator.ram()[0], 256);
+            cycles.push(emulator.cycles());
+        }
+        assert!(sizes[1] * 4 < sizes[0] * 3, "{:?}", sizes);
+        assert!(cycles[1] * 4 < cycles[0] * 3, "{:?}", cycles);
+        Ok(())
+    }
+
+    #[test]
+    fn test_negative_constants() -> Result<()> {
+        let vm = "push constant -5\npop static 0\npush constant -32768\npop static 1";
+        let lines = Parser::parse_lines_with(vm.as_bytes(), Extension::NegativeConstants.into())?;
+        for &cache_top_of_stack in &[false, true] {
+            let mut translator = Translator::with_bootstrap(&Bootstrap {
+                sp: Some(256),
+                ..Bootstrap::none()
+            });
+            translator.set_cache_top_of_stack(cache_top_of_stack);
+            translator.add_lines("Main", &lines)?;
+            let asm = translator.get_assembly().join("\n");
+            let rom = Assembler::assemble(AsmParser::parse(asm.as_bytes())?)?;
+            let size = rom.len();
+            let mut emulator = Emulator::new(rom);
+            while (emulator.pc() as usize) < size {
+                emulator.step()?;
+            }
+            assert_eq!(emulator.ram()[16..18], [(-5i16) as u16, 0x8000]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_extended_instructions() -> Result<()> {
+        let vm = r#"
+            function Sys.init 0
+            push constant 6
+            push constant 7
+            mul
+            pop static 0
+            push constant 45
+            push constant 6
+            div
+            pop static 1
+            push constant 3
+            push constant 4
+            shl
+            pop static 2
+            push constant 16
+            neg
+            push constant 2
+            shr
+            pop static 3
+            push constant 9
+            push constant 20
+            shl
+            pop static 4
+            push constant 300
+            push constant 11
+            pop-indirect
+            push constant 300
+            push-indirect
+            pop static 5
+            push constant 5
+            push-function Sys.double
+            call-indirect 1
+            pop static 6
+            label END
+            goto END
+            function Sys.double 0
+            push argument 0
+            push argument 0
+            add
+            return
+            function Math.multiply 0
+            push constant 42
+            return
+            function Math.divide 0
+            push constant 7
+            return
+            "#;
+        let commands = Parser::parse_lines_with(vm.as_bytes(), Extension::Instructions.into())?
+            .into_iter()
+            .filter_map(|line| line.command)
+            .collect::<Vec<_>>();
+        for &(compact, cache_top_of_stack) in &[(false, false), (true, false), (true, true)] {
+            let mut translator = Translator::new();
+            translator.set_compact(compact);
+            translator.set_cache_top_of_stack(cache_top_of_stack);
+            translator.add_commands("Sys", &commands)?;
+            let (_, emulator) = run(translator)?;
+            assert_eq!(emulator.ram()[16..23], [42, 7, 48, 0x3FFC, 0, 11, 10]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_statics() -> Result<()> {
+        let mut translator = Translator::with_bootstrap(&Bootstrap {
+            sp: Some(256),
+            ..Bootstrap::none()
+        });
+        let a = "push constant 1\npop static 1\npush constant 2\npop static 0";
+        let b = "push constant 3\npop static 0\npush static 0\npop static 5";
+        translator.add_commands("A", &Parser::parse(a.as_bytes())?)?;
+        translator.add_commands("B", &Parser::parse(b.as_bytes())?)?;
+        translator.add_commands("A", &Parser::parse("push static 2".as_bytes())?)?;
+        assert_eq!(
+            translator.static_allocation()?,
+            [
+                ("A".to_owned(), vec![16, 17, 20]),
+                ("B".to_owned(), vec![18, 19])
+            ]
+        );
+        let asm = translator.get_assembly().join("\n");
+        let rom = Assembler::assemble(AsmParser::parse(asm.as_bytes())?)?;
+        let size = rom.len();
+        let mut emulator = Emulator::new(rom);
+        while (emulator.pc() as usize) < size {
+            emulator.step()?;
+        }
+        assert_eq!(emulator.ram()[16..20], [1, 2, 3, 3]);
+
+        // Without a bootstrap defining it, `Sys.init` is a variable taking RAM 16 first
+        let mut translator = Translator::new();
+        translator.add_commands("Main", &Parser::parse("push static 0".as_bytes())?)?;
+        assert_eq!(
+            translator.static_allocation()?,
+            [("Main".to_owned(), vec![17])]
+        );
+        let vm: Vec<_> = (0..240).map(|i| format!("push static {}", i)).collect();
+        translator.add_commands("Main", &Parser::parse(vm.join("\n").as_bytes())?)?;
+        let error = translator.static_allocation().unwrap_err();
+        assert!(error.to_string().contains("Static `Main.239` is placed at RAM 256"));
+
+        let mut translator = Translator::new();
+        let vm: Vec<_> = (0..241).map(|i| format!("push static {}", i)).collect();
+        let error = translator
+            .add_commands("Main", &Parser::parse(vm.join("\n").as_bytes())?)
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("Static `Main.240` doesn't fit in RAM 16-255"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_map() -> Result<()> {
+        let mut translator = Translator::with_bootstrap(&Bootstrap {
+            sp: Some(256),
+            ..Bootstrap::none()
+        });
+        let vm = "// Adds\npush constant 1\n\npush constant 2\nadd\npop static 0";
+        translator.add_lines("Main", &Parser::parse_lines(vm.as_bytes())?)?;
+        translator.add_commands("Sys", &Parser::parse("push constant 0".as_bytes())?)?;
+        let source_map = translator.source_map();
+        let starts: Vec<_> = source_map.ranges().map(|(start, _)| start).collect();
+        assert_eq!(
+            source_map
+                .ranges()
+                .map(|(_, source)| source)
+                .collect::<Vec<_>>(),
+            ["Main.vm:2", "Main.vm:4", "Main.vm:5", "Main.vm:6"]
+        );
+        // Past the bootstrap setting SP
+        assert_eq!(starts[0], 4);
+        assert_eq!(source_map.lookup(0), None);
+        assert_eq!(source_map.lookup(starts[1] - 1), Some("Main.vm:2"));
+        assert_eq!(source_map.lookup(starts[3]), Some("Main.vm:6"));
+        let size = translator.rom_address;
+        assert_eq!(source_map.lookup(size - 1), None);
+
+        // Addresses agree with the assembler's
+        let asm = translator.get_assembly().join("\n");
+        let rom = Assembler::assemble(AsmParser::parse(asm.as_bytes())?)?;
+        assert_eq!(rom.len(), size as usize);
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_pop_selection() -> Result<()> {
+        let code = |vm: &str| -> Result<Vec<String>> {
+            let mut translator = Translator::with_bootstrap(&Bootstrap::none());
+            translator.add_commands("Test", &Parser::parse(vm.as_bytes())?)?;
+            Ok(translator
+                .get_assembly()
+                .into_iter()
+                .filter(|line| !line.starts_with("//"))
+                .collect())
+        };
+        assert_eq!(code("push constant 0")?, ["@SP", "AM=M+1", "A=A-1", "M=0"]);
+        assert_eq!(
+            code("pop local 0")?,
+            ["@SP", "AM=M-1", "D=M", "@LCL", "A=M", "M=D"]
+        );
+        assert_eq!(
+            code("push argument 2")?,
+            ["@ARG", "A=M+1", "A=A+1", "D=M", "@SP", "AM=M+1", "A=A-1", "M=D"]
+        );
+        assert!(code("pop local 20")?.contains(&"@R13".to_owned()));
+
+        // Move values through every index of a segment, crossing all the selection thresholds
+        let mut vm = vec![];
+        for i in 0..16 {
+            vm.push(format!("push constant {}", 100 + i));
+            vm.push(format!("pop local {}", i));
+        }
+        for i in 0..16 {
+            vm.push(format!("push local {}", i));
+            vm.push(format!("pop that {}", 15 - i));
+        }
+        let vm = vm.join("\n");
+        for &cache_top_of_stack in &[false, true] {
+            let mut translator = Translator::with_bootstrap(&Bootstrap {
+                sp: Some(256),
+                lcl: Some(300),
+                that: Some(400),
+                ..Bootstrap::none()
+            });
+            translator.set_cache_top_of_stack(cache_top_of_stack);
+            translator.add_commands("Test", &Parser::parse(vm.as_bytes())?)?;
+            let asm = translator.get_assembly().join("\n");
+            let rom = Assembler::assemble(AsmParser::parse(asm.as_bytes())?)?;
+            let size = rom.len();
+            let mut emulator = Emulator::new(rom);
+            while (emulator.pc() as usize) < size {
+                emulator.step()?;
+            }
+            let expected: Vec<u16> = (100..116).rev().collect();
+            assert_eq!(emulator.ram()[400..416], expected[..]);
+            assert_eq!(emulator.ram()[0], 256);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_branch() -> Result<()> {
+        let commands = Parser::parse("lt\nif-goto X".as_bytes())?;
+        let mut translator = Translator::with_bootstrap(&Bootstrap::none());
+        translator.add_commands("Test", &commands)?;
+        let code: Vec<_> = translator
+            .get_assembly()
+            .into_iter()
+            .filter(|line| !line.starts_with("//"))
+            .collect();
+        assert_eq!(
+            code,
+            ["@SP", "AM=M-1", "D=M", "@SP", "AM=M-1", "D=M-D", "@Test$X", "D;JLT"]
+        );
+
+        for &(x, y) in &[(3, 5), (5, 3), (4, 4)] {
+            for &(comparison, expected) in &[("eq", x == y), ("gt", x > y), ("lt", x < y)] {
+                for &negated in &[false, true] {
+                    let vm = format!(
+                        "push constant {}\npush constant {}\n{}\n{}\
+                         if-goto T\npush constant 0\npop static 0\ngoto E\n\
+                         label T\npush constant 1\npop static 0\nlabel E",
+                        x,
+                        y,
+                        comparison,
+                        if negated { "not\n" } else { "" }
+                    );
+                    for &cache_top_of_stack in &[false, true] {
+                        let mut translator = Translator::with_bootstrap(&Bootstrap {
+                            sp: Some(256),
+                            ..Bootstrap::none()
+                        });
+                        translator.set_cache_top_of_stack(cache_top_of_stack);
+                        translator.add_commands("Test", &Parser::parse(vm.as_bytes())?)?;
+                        let asm = translator.get_assembly().join("\n");
+                        let rom = Assembler::assemble(AsmParser::parse(asm.as_bytes())?)?;
+                        let size = rom.len();
+                        let mut emulator = Emulator::new(rom);
+                        while (emulator.pc() as usize) < size {
+                            emulator.step()?;
+                        }
+                        assert_eq!(emulator.ram()[16] == 1, expected != negated, "{}", vm);
+                        assert_eq!(emulator.ram()[0], 256);
+                    }
+                }
+            }
+        }
+
+        // `if-goto` jumps on anything but 0, so `not` doesn't make a jump on zero of other values
+        for &(value, negated, expected) in &[
+            ("push constant 1", true, true),
+            ("push constant 5", true, true),
+            ("push constant 5", false, true),
+            ("push constant 0\nnot", true, false),
+            ("push constant 0", true, true),
+            ("push constant 0", false, false),
+        ] {
+            let vm = format!(
+                "{}\n{}if-goto T\npush constant 0\npop static 0\ngoto E\n\
+                 label T\npush constant 1\npop static 0\nlabel E",
+                value,
+                if negated { "not\n" } else { "" }
+            );
+            for &cache_top_of_stack in &[false, true] {
+                let mut translator = Translator::with_bootstrap(&Bootstrap {
+                    sp: Some(256),
+                    ..Bootstrap::none()
+                });
+                translator.set_cache_top_of_stack(cache_top_of_stack);
+                translator.add_commands("Test", &Parser::parse(vm.as_bytes())?)?;
+                let asm = translator.get_assembly().join("\n");
+                let rom = Assembler::assemble(AsmParser::parse(asm.as_bytes())?)?;
+                let size = rom.len();
+                let mut emulator = Emulator::new(rom);
+                while (emulator.pc() as usize) < size {
+                    emulator.step()?;
+                }
+                assert_eq!(emulator.ram()[16] == 1, expected, "{}", vm);
+            }
+        }
+        Ok(())
+    }
 }
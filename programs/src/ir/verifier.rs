@@ -0,0 +1,181 @@
+use crate::ir::{
+    split_functions, Arithmetic, Command, FunctionCall, MemoryAccess, ProgramFlow, Symbol,
+};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+
+/// The numbers of values `command` pops and pushes.
+fn stack_effect(command: &Command) -> Result<(u16, u16)> {
+    let effect = match command {
+        Command::Arithmetic(Arithmetic::Neg | Arithmetic::Not) => (1, 1),
+        Command::Arithmetic(_) => (2, 1),
+        Command::MemoryAccess(MemoryAccess::Push { .. }) => (0, 1),
+        Command::MemoryAccess(MemoryAccess::Pop { .. }) => (1, 0),
+        Command::MemoryAccess(MemoryAccess::PushIndirect) => (1, 1),
+        Command::MemoryAccess(MemoryAccess::PopIndirect) => (2, 0),
+        Command::ProgramFlow(ProgramFlow::Label { .. })
+        | Command::ProgramFlow(ProgramFlow::Goto { .. }) => (0, 0),
+        Command::ProgramFlow(ProgramFlow::IfGoto { .. }) => (1, 0),
+        Command::FunctionCall(FunctionCall::Declare { .. }) => (0, 0),
+        Command::FunctionCall(FunctionCall::Invoke { n_args, .. }) => (*n_args, 1),
+        Command::FunctionCall(FunctionCall::Return) => (1, 0),
+        Command::FunctionCall(FunctionCall::Address { .. }) => (0, 1),
+        Command::FunctionCall(FunctionCall::InvokeIndirect { n_args }) => {
+            let consumed = n_args
+                .checked_add(1)
+                .with_context(|| format!("`{}` pops too many values", command))?;
+            (consumed, 1)
+        }
+    };
+    Ok(effect)
+}
+
+pub struct Verifier();
+impl Verifier {
+    /// Computes the height of the operand stack before each of `commands`, a single function or
+    /// the commands before the first one, or `None` where a command is unreachable.
+    ///
+    /// Fails if heights disagree where control flow joins, if a command pops more values than the
+    /// function pushed, if a `return` finds the stack empty, or if a function runs off its end.
+    pub fn stack_heights(commands: &[Command]) -> Result<Vec<Option<u16>>> {
+        let mut labels = HashMap::new();
+        for (i, command) in commands.iter().enumerate() {
+            if let Command::ProgramFlow(ProgramFlow::Label { label }) = command {
+                if labels.insert(label, i).is_some() {
+                    bail!("Label `{}` is defined more than once", label.0);
+                }
+            }
+        }
+        let is_function = matches!(
+            commands.first(),
+            Some(Command::FunctionCall(FunctionCall::Declare { .. }))
+        );
+
+        let mut heights = vec![None; commands.len()];
+        let mut pending = vec![(0, 0)];
+        while let Some((i, height)) = pending.pop() {
+            let command = match commands.get(i) {
+                Some(command) => command,
+                None if is_function => bail!("Control reaches the end of the function"),
+                None => continue,
+            };
+            match heights[i] {
+                Some(known) if known == height => continue,
+                Some(known) => bail!(
+                    "Stack heights {} and {} disagree at `{}` (command {})",
+                    known,
+                    height,
+                    command,
+                    i
+                ),
+                None => heights[i] = Some(height),
+            }
+            let (consumed, produced) = stack_effect(command)?;
+            if height < consumed {
+                match command {
+                    Command::FunctionCall(FunctionCall::Return) => {
+                        bail!("Return with an empty stack (command {})", i)
+                    }
+                    _ => bail!(
+                        "`{}` underflows the stack of height {} (command {})",
+                        command,
+                        height,
+                        i
+                    ),
+                }
+            }
+            let next = height - consumed + produced;
+            let target = |label: &Symbol| {
+                labels
+                    .get(label)
+                    .copied()
+                    .with_context(|| format!("Label `{}` is undefined", label.0))
+            };
+            match command {
+                Command::ProgramFlow(ProgramFlow::Goto { label }) => {
+                    pending.push((target(label)?, next))
+                }
+                Command::ProgramFlow(ProgramFlow::IfGoto { label }) => {
+                    pending.push((target(label)?, next));
+                    pending.push((i + 1, next));
+                }
+                Command::FunctionCall(FunctionCall::Return) => {}
+                _ => pending.push((i + 1, next)),
+            }
+        }
+        Ok(heights)
+    }
+
+    /// Checks the stack heights of every function in `commands` of a class.
+    pub fn verify(commands: &[Command]) -> Result<()> {
+        for function in split_functions(commands) {
+            Self::stack_heights(function).with_context(|| match &function[0] {
+                Command::FunctionCall(FunctionCall::Declare { name, .. }) => {
+                    format!("Invalid function `{}`", name.0)
+                }
+                _ => "Invalid commands outside of functions".to_owned(),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::parser::Parser;
+
+    fn verify(vm: &str) -> Result<()> {
+        Verifier::verify(&Parser::parse(vm.as_bytes())?)
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let heights = Verifier::stack_heights(&Parser::parse(
+            r#"
+            function Main.main 0
+            label LOOP
+            push constant 1
+            if-goto LOOP
+            push constant 2
+            goto END
+            push constant 3
+            label END
+            return
+            "#
+            .as_bytes(),
+        )?)?;
+        assert_eq!(
+            heights,
+            [
+                Some(0),
+                Some(0),
+                Some(0),
+                Some(1),
+                Some(0),
+                Some(1),
+                None,
+                Some(1),
+                Some(1)
+            ]
+        );
+
+        let error = |vm| format!("{:#}", verify(vm).unwrap_err());
+        assert!(error(
+            "function F 0\npush constant 0\nif-goto L\npush constant 1\nlabel L\nreturn"
+        )
+        .ends_with("Stack heights 1 and 0 disagree at `label L` (command 4)"));
+        assert!(
+            error("function F 0\npush constant 0\nreturn\nfunction G 0\nadd\nreturn")
+                .starts_with("Invalid function `G`: `add` underflows")
+        );
+        assert!(error("function F 0\nreturn").ends_with("Return with an empty stack (command 1)"));
+        assert!(error("function F 0\npush constant 0\npop local 0")
+            .ends_with("Control reaches the end of the function"));
+        assert!(error("goto L").ends_with("Label `L` is undefined"));
+        let call = Command::FunctionCall(FunctionCall::InvokeIndirect { n_args: u16::MAX });
+        assert!(format!("{:#}", Verifier::verify(&[call]).unwrap_err())
+            .ends_with("`call-indirect 65535` pops too many values"));
+        verify("push constant 0\npop temp 0")
+    }
+}
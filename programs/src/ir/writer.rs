@@ -1,4 +1,4 @@
-use crate::ir::{Arithmetic, Command, FunctionCall, MemoryAccess, ProgramFlow};
+use crate::ir::{Arithmetic, Command, FunctionCall, Line, MemoryAccess, ProgramFlow};
 use anyhow::{Context, Result};
 use std::io::Write;
 pub struct IRWriter<W: Write> {
@@ -42,6 +42,10 @@ impl<W: Write> IRWriter<W> {
             Command::FunctionCall(function_call) => self.write_function_call(function_call),
         }
     }
+    /// Writes `line` with its comment, or an empty line if it has neither a command nor a comment.
+    pub fn write_line(&mut self, line: &Line) -> Result<()> {
+        self.writeln(&line.to_string())
+    }
     pub fn flush(&mut self) -> Result<()> {
         self.writer.flush().with_context(|| "Failed to flush")
     }
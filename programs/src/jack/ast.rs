@@ -0,0 +1,234 @@
+use crate::jack::token::{Identifier, Span};
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Type {
+    Int,
+    Char,
+    Boolean,
+    Class(Identifier),
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Char => write!(f, "char"),
+            Type::Boolean => write!(f, "boolean"),
+            Type::Class(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Class {
+    pub name: Identifier,
+    pub var_decs: Vec<ClassVarDec>,
+    pub subroutines: Vec<Subroutine>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ClassVarKind {
+    Static,
+    Field,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClassVarDec {
+    pub kind: ClassVarKind,
+    pub var_type: Type,
+    pub names: Vec<(Identifier, Span)>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SubroutineKind {
+    Constructor,
+    Function,
+    Method,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Parameter {
+    pub var_type: Type,
+    pub name: Identifier,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Subroutine {
+    pub kind: SubroutineKind,
+    // `None` for `void`
+    pub return_type: Option<Type>,
+    pub name: Identifier,
+    pub parameters: Vec<Parameter>,
+    pub var_decs: Vec<VarDec>,
+    pub statements: Vec<Statement>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VarDec {
+    pub var_type: Type,
+    pub names: Vec<(Identifier, Span)>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StatementKind {
+    Let {
+        name: Identifier,
+        index: Option<Box<Expression>>,
+        value: Expression,
+    },
+    If {
+        condition: Expression,
+        then_statements: Vec<Statement>,
+        else_statements: Option<Vec<Statement>>,
+    },
+    While {
+        condition: Expression,
+        statements: Vec<Statement>,
+    },
+    Do(SubroutineCall),
+    Return(Option<Expression>),
+    // Language extensions
+    /// `name op= value`, or `name++` and `name--` without a `value`, where `op` is `+` or `-`
+    CompoundAssignment {
+        name: Identifier,
+        index: Option<Box<Expression>>,
+        operator: BinaryOperator,
+        value: Option<Expression>,
+    },
+    /// `for (init; condition; step) { statements }`, where `init` and `step` are `Let`s or
+    /// `CompoundAssignment`s
+    For {
+        init: Option<Box<Statement>>,
+        condition: Expression,
+        step: Option<Box<Statement>>,
+        statements: Vec<Statement>,
+    },
+    Break,
+    Continue,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Lt,
+    Gt,
+    Eq,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnaryOperator {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeywordConstant {
+    True,
+    False,
+    Null,
+    This,
+}
+
+/// `term (op term)*` as written, leaving the order of the operations to `postfix`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Expression {
+    pub term: Term,
+    pub operations: Vec<(BinaryOperator, Term)>,
+    pub span: Span,
+}
+
+/// A term or an operator applied to the two values before it, in evaluation order.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Postfix<'a> {
+    Term(&'a Term),
+    Operator(BinaryOperator),
+}
+
+/// How the operators of an expression without parentheses are grouped.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum Semantics {
+    /// `*` and `/` bind tightest, then `+` and `-`, `&`, `|` and comparisons
+    #[default]
+    Precedence,
+    /// Operators apply from left to right, as specified by the course
+    LeftToRight,
+}
+
+impl BinaryOperator {
+    fn precedence(self) -> usize {
+        match self {
+            BinaryOperator::Mul | BinaryOperator::Div => 4,
+            BinaryOperator::Add | BinaryOperator::Sub => 3,
+            BinaryOperator::And => 2,
+            BinaryOperator::Or => 1,
+            BinaryOperator::Lt | BinaryOperator::Gt | BinaryOperator::Eq => 0,
+        }
+    }
+}
+
+impl Expression {
+    /// The terms and operators in the order they are evaluated under `semantics`.
+    pub fn postfix(&self, semantics: Semantics) -> Vec<Postfix<'_>> {
+        let mut postfix = vec![Postfix::Term(&self.term)];
+        let mut delayed: Vec<BinaryOperator> = vec![];
+        for (operator, term) in &self.operations {
+            while let Some(&last) = delayed.last() {
+                if semantics == Semantics::LeftToRight || operator.precedence() <= last.precedence()
+                {
+                    postfix.push(Postfix::Operator(last));
+                    delayed.pop();
+                } else {
+                    break;
+                }
+            }
+            postfix.push(Postfix::Term(term));
+            delayed.push(*operator);
+        }
+        postfix.extend(delayed.into_iter().rev().map(Postfix::Operator));
+        postfix
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TermKind {
+    IntegerConstant(i16),
+    StringConstant(String),
+    KeywordConstant(KeywordConstant),
+    Variable(Identifier),
+    ArrayElement(Identifier, Box<Expression>),
+    Call(SubroutineCall),
+    Parenthesized(Box<Expression>),
+    Unary(UnaryOperator, Box<Term>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Term {
+    pub kind: TermKind,
+    pub span: Span,
+}
+
+/// `name(arguments)`, or `receiver.name(arguments)` where `receiver` is a variable or a class.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SubroutineCall {
+    pub receiver: Option<Identifier>,
+    pub name: Identifier,
+    pub arguments: Vec<Expression>,
+    pub span: Span,
+}
@@ -1,114 +1,63 @@
 use crate::ir::writer::IRWriter;
 use crate::ir::{Arithmetic, FunctionCall, MemoryAccess, ProgramFlow, Segment};
-use crate::jack::symbol_table::{Kind, SymbolTable, VariableType};
-use crate::jack::token::{Identifier, Keyword, Symbol, Token};
-use anyhow::{bail, ensure, Context, Result};
-use std::collections::VecDeque;
+use crate::jack::ast::{
+    BinaryOperator, Class, Expression, KeywordConstant, Postfix, Semantics, Statement,
+    StatementKind, Subroutine, SubroutineCall, SubroutineKind, Term, TermKind, Type, UnaryOperator,
+};
+use crate::jack::signatures::Signatures;
+use crate::jack::symbol_table::{Kind, SymbolTable};
+use crate::jack::token::{Identifier, Span};
+use crate::jack::Errors;
+use anyhow::{anyhow, Context, Error, Result};
 use std::io::Write;
-use std::iter::Fuse;
 
-pub struct IRAnalyzer<I: Iterator<Item = Result<Token>>, W: Write> {
-    token_stream: Fuse<I>,
-    peeked: VecDeque<Result<Token>>,
+/// Generates VM code for classes parsed by `jack::parser::Parser`.
+pub struct IRAnalyzer<'a, W: Write> {
     ir_writer: IRWriter<W>,
     symbol_table: SymbolTable,
     class_name: Option<Identifier>,
+    subroutine_kind: Option<SubroutineKind>,
     next_label_id: usize,
+    // The labels `continue` and `break` jump to in the loops being compiled
+    loops: Vec<(crate::ir::Symbol, crate::ir::Symbol)>,
+    extended_instructions: bool,
+    semantics: Semantics,
+    signatures: Option<&'a Signatures>,
+    // Errors which don't stop the compilation, so that all of them are reported
+    errors: Vec<Error>,
 }
 
-impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
-    pub fn from(token_stream: I, ir_writer: IRWriter<W>) -> Self {
-        return Self {
-            token_stream: token_stream.fuse(),
-            peeked: VecDeque::new(),
+impl<'a, W: Write> IRAnalyzer<'a, W> {
+    pub fn new(ir_writer: IRWriter<W>) -> Self {
+        Self {
             ir_writer,
             symbol_table: SymbolTable::new(),
             class_name: None,
+            subroutine_kind: None,
             next_label_id: 0,
-        };
-    }
-
-    fn peek_more(&mut self) -> bool {
-        if let Some(token) = self.token_stream.next() {
-            self.peeked.push_back(token);
-            true
-        } else {
-            false
+            loops: vec![],
+            extended_instructions: false,
+            semantics: Semantics::default(),
+            signatures: None,
+            errors: vec![],
         }
     }
-    fn peek_nth(&mut self, i: usize) -> Result<Option<&Token>> {
-        while self.peeked.len() <= i {
-            if !self.peek_more() {
-                return Ok(None);
-            }
-        }
-        match self.peeked[i].as_ref() {
-            Ok(token) => Ok(Some(token)),
-            _ => bail!("Unable to peek token"),
-        }
-    }
-    fn peek_token(&mut self) -> Result<Option<&Token>> {
-        self.peek_nth(0)
-    }
-    fn peek_keyword(&mut self) -> Result<Option<&Keyword>> {
-        Ok(match self.peek_token()? {
-            Some(Token::Keyword(keyword)) => Some(keyword),
-            _ => None,
-        })
-    }
-    fn peek_symbol(&mut self) -> Result<Option<&Symbol>> {
-        Ok(match self.peek_token()? {
-            Some(Token::Symbol(symbol)) => Some(symbol),
-            _ => None,
-        })
-    }
 
-    fn next_token(&mut self) -> Result<Token> {
-        if let Some(peeked) = self.peeked.pop_front() {
-            return peeked;
-        }
-        match self.token_stream.next() {
-            Some(Ok(token)) => Ok(token),
-            Some(Err(e)) => Err(e),
-            None => bail!("Expected a token but reached to the end of the token stream"),
-        }
-    }
-    fn next_keyword(&mut self) -> Result<Keyword> {
-        match self.next_token()? {
-            Token::Keyword(keyword) => Ok(keyword),
-            token => bail!("Expected a keyword but was {:?}", token),
-        }
-    }
-    fn next_identifier(&mut self) -> Result<Identifier> {
-        match self.next_token()? {
-            Token::Identifier(identifier) => Ok(identifier),
-            token => bail!("Expected an identifier but was {:?}", token),
-        }
-    }
-    fn next_symbol(&mut self) -> Result<Symbol> {
-        match self.next_token()? {
-            Token::Symbol(symbol) => Ok(symbol),
-            token => bail!("Expected a symbol but was {:?}", token),
-        }
+    /// Compiles `*` and `/` to `mul` and `div`, and array accesses to `push-indirect` and
+    /// `pop-indirect`, which standard VM translators don't support.
+    pub fn set_extended_instructions(&mut self, extended_instructions: bool) {
+        self.extended_instructions = extended_instructions;
     }
 
-    fn next_non_void_type(&mut self) -> Result<VariableType> {
-        let token = self.next_token()?;
-        match token {
-            Token::Keyword(Keyword::Int) => Ok(VariableType::Int),
-            Token::Keyword(Keyword::Char) => Ok(VariableType::Char),
-            Token::Keyword(Keyword::Boolean) => Ok(VariableType::Boolean),
-            Token::Identifier(ident) => Ok(VariableType::Object(ident)),
-            _ => bail!("Expected a type but was {:?}", token),
-        }
+    /// Groups the operators of expressions according to `semantics`.
+    pub fn set_semantics(&mut self, semantics: Semantics) {
+        self.semantics = semantics;
     }
-    fn next_voidable_type(&mut self) -> Result<Option<VariableType>> {
-        if Some(&Keyword::Void) == self.peek_keyword()? {
-            self.next_token()?;
-            Ok(None)
-        } else {
-            Ok(Some(self.next_non_void_type()?))
-        }
+
+    /// Checks that calls to the classes in `signatures` are to subroutines of the right kind and
+    /// number of arguments.
+    pub fn set_signatures(&mut self, signatures: &'a Signatures) {
+        self.signatures = Some(signatures);
     }
 
     fn generate_label(&mut self, prefix: &str) -> crate::ir::Symbol {
@@ -121,7 +70,7 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
     fn get_class_name(&self) -> Result<&Identifier> {
         self.class_name
             .as_ref()
-            .with_context(|| format!("Why not inside a class???"))
+            .with_context(|| "Why not inside a class???".to_string())
     }
 
     fn compile_invoke_function(&mut self, name: &str, n_args: u16) -> Result<()> {
@@ -131,163 +80,126 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
         })
     }
 
-    fn compile_class(&mut self) -> Result<()> {
-        ensure!(Keyword::Class == self.next_keyword()?);
-        self.symbol_table.start_new_class();
-        let name = self.next_identifier()?;
-        ensure!(self.class_name.replace(name).is_none());
-        ensure!(Symbol::OpenBrace == self.next_symbol()?);
-        while self.try_compile_class_var_dec()? {}
-        while self.try_compile_subroutine()? {}
-        ensure!(Symbol::CloseBrace == self.next_symbol()?);
-        ensure!(None == self.peek_token()?);
-        ensure!(self.class_name.take().is_some());
-        Ok(())
-    }
-    fn try_compile_class_var_dec(&mut self) -> Result<bool> {
-        match self.peek_keyword()? {
-            Some(Keyword::Static) | Some(Keyword::Field) => {
-                let modifier = self.next_keyword()?;
-                let modifier = match modifier {
-                    Keyword::Static => Kind::Static,
-                    Keyword::Field => Kind::Field,
-                    _ => bail!("??? got {:?}", modifier),
-                };
-                let t = self.next_non_void_type()?;
-                let name = self.next_identifier()?;
-                self.symbol_table
-                    .define_class_variable(&modifier, &t, name)?;
-                while let Some(Symbol::Comma) = self.peek_symbol()? {
-                    ensure!(Symbol::Comma == self.next_symbol()?);
-                    let name = self.next_identifier()?;
-                    self.symbol_table
-                        .define_class_variable(&modifier, &t, name)?;
-                }
-                ensure!(Symbol::Semicolon == self.next_symbol()?);
-                Ok(true)
-            }
-            _ => Ok(false),
+    fn compile_class(&mut self, class: &Class) -> Result<()> {
+        let errors = self.symbol_table.define_class(class);
+        self.errors.extend(errors);
+        self.class_name = Some(class.name.clone());
+        for subroutine in &class.subroutines {
+            self.compile_subroutine(subroutine)?;
         }
+        self.class_name = None;
+        Ok(())
     }
-    fn try_compile_subroutine(&mut self) -> Result<bool> {
-        match self.peek_keyword()? {
-            Some(Keyword::Constructor) | Some(Keyword::Function) | Some(Keyword::Method) => {
-                self.symbol_table.start_new_subroutine();
+    fn compile_subroutine(&mut self, subroutine: &Subroutine) -> Result<()> {
+        self.subroutine_kind = Some(subroutine.kind);
+        let name = &subroutine.name;
+        self.ir_writer.comment(&format!(
+            "start function {}.{}",
+            self.get_class_name()?.0,
+            name.0
+        ))?;
+        let errors = self.symbol_table.define_subroutine(subroutine);
+        self.errors.extend(errors);
 
-                let modifier = self.next_keyword()?;
-                let _type = self.next_voidable_type();
-                let name = self.next_identifier()?;
-                self.ir_writer.comment(&format!(
-                    "start function {}.{}",
-                    self.get_class_name()?.0,
-                    name.0
-                ))?;
-                ensure!(Symbol::OpenParen == self.next_symbol()?);
-                self.compile_parameter_list()?;
-                ensure!(Symbol::CloseParen == self.next_symbol()?);
-                ensure!(Symbol::OpenBrace == self.next_symbol()?);
-                while self.try_compile_var_dec()? {}
-
-                let n_locals = self.symbol_table.get_count(Kind::Local);
-                self.ir_writer.write_function_call(&FunctionCall::Declare {
-                    name: format!("{}.{}", self.get_class_name()?.0, name.0).parse()?,
-                    n_locals,
+        let n_locals = self.symbol_table.get_count(Kind::Local);
+        self.ir_writer.write_function_call(&FunctionCall::Declare {
+            name: format!("{}.{}", self.get_class_name()?.0, name.0).parse()?,
+            n_locals,
+        })?;
+        match subroutine.kind {
+            SubroutineKind::Constructor => {
+                self.ir_writer.comment("Allocate memory")?;
+                self.alloc(self.symbol_table.get_count(Kind::Field))?;
+                self.ir_writer.comment("Set this pointer")?;
+                // set this to self
+                self.ir_writer.write_memory_access(&MemoryAccess::Pop {
+                    segment: Segment::Pointer,
+                    index: 0,
                 })?;
-                match modifier {
-                    Keyword::Constructor => {
-                        self.ir_writer.comment("Allocate memory")?;
-                        self.alloc(self.symbol_table.get_count(Kind::Field))?;
-                        self.ir_writer.comment("Set this pointer")?;
-                        // set this to self
-                        self.ir_writer.write_memory_access(&MemoryAccess::Pop {
-                            segment: Segment::Pointer,
-                            index: 0,
-                        })?;
-                    }
-                    Keyword::Function => {
-                        // NoOp
-                    }
-                    Keyword::Method => {
-                        self.symbol_table.shift_argument_variables_by_one();
-                        self.ir_writer.comment("Set this pointer")?;
-                        // set this to self
-                        self.ir_writer.write_memory_access(&MemoryAccess::Push {
-                            segment: Segment::Argument,
-                            index: 0,
-                        })?;
-                        self.ir_writer.write_memory_access(&MemoryAccess::Pop {
-                            segment: Segment::Pointer,
-                            index: 0,
-                        })?;
-                    }
-                    _ => unreachable!(),
-                }
-                self.ir_writer.comment("Function body")?;
-                self.compile_statements()?;
-                ensure!(Symbol::CloseBrace == self.next_symbol()?);
-                self.ir_writer.comment(&format!(
-                    "end function {}.{}",
-                    self.get_class_name()?.0,
-                    name.0
-                ))?;
-                Ok(true)
             }
-            _ => Ok(false),
-        }
-    }
-    fn compile_parameter_list(&mut self) -> Result<()> {
-        if self.peek_symbol()?.is_none() {
-            let t = self.next_non_void_type()?;
-            let name = self.next_identifier()?;
-            self.symbol_table.define_argument_variable(&t, name)?;
-            while let Some(&Symbol::Comma) = self.peek_symbol()? {
-                ensure!(Symbol::Comma == self.next_symbol()?);
-                let t = self.next_non_void_type()?;
-                let name = self.next_identifier()?;
-                self.symbol_table.define_argument_variable(&t, name)?;
+            SubroutineKind::Function => {
+                // NoOp
+            }
+            SubroutineKind::Method => {
+                self.symbol_table.shift_argument_variables_by_one();
+                self.ir_writer.comment("Set this pointer")?;
+                // set this to self
+                self.ir_writer.write_memory_access(&MemoryAccess::Push {
+                    segment: Segment::Argument,
+                    index: 0,
+                })?;
+                self.ir_writer.write_memory_access(&MemoryAccess::Pop {
+                    segment: Segment::Pointer,
+                    index: 0,
+                })?;
             }
         }
+        self.ir_writer.comment("Function body")?;
+        self.compile_statements(&subroutine.statements)?;
+        self.ir_writer.comment(&format!(
+            "end function {}.{}",
+            self.get_class_name()?.0,
+            name.0
+        ))?;
         Ok(())
     }
-    fn try_compile_var_dec(&mut self) -> Result<bool> {
-        if let Some(Keyword::Var) = self.peek_keyword()? {
-            ensure!(Keyword::Var == self.next_keyword()?);
-            let t = self.next_non_void_type()?;
-            let name = self.next_identifier()?;
-            self.symbol_table.define_local_variable(&t, name)?;
-            while let Some(Symbol::Comma) = self.peek_symbol()? {
-                ensure!(Symbol::Comma == self.next_symbol()?);
-                let name = self.next_identifier()?;
-                self.symbol_table.define_local_variable(&t, name)?;
+    fn compile_statements(&mut self, statements: &[Statement]) -> Result<()> {
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Let { name, index, value } => {
+                    self.compile_let(name, index.as_deref(), value, &statement.span)?
+                }
+                StatementKind::CompoundAssignment {
+                    name,
+                    index,
+                    operator,
+                    value,
+                } => self.compile_compound_assignment(
+                    name,
+                    index.as_deref(),
+                    *operator,
+                    value.as_ref(),
+                    &statement.span,
+                )?,
+                StatementKind::If {
+                    condition,
+                    then_statements,
+                    else_statements,
+                } => self.compile_if(condition, then_statements, else_statements.as_deref())?,
+                StatementKind::While {
+                    condition,
+                    statements,
+                } => self.compile_while(condition, statements)?,
+                StatementKind::Do(call) => self.compile_do(call)?,
+                StatementKind::Return(value) => self.compile_return(value.as_ref())?,
+                StatementKind::For {
+                    init,
+                    condition,
+                    step,
+                    statements,
+                } => self.compile_for(init.as_deref(), condition, step.as_deref(), statements)?,
+                StatementKind::Break | StatementKind::Continue => {
+                    let (continue_label, break_label) = self
+                        .loops
+                        .last()
+                        .with_context(|| format!("{}: Jump outside of a loop", statement.span))?;
+                    let label = match statement.kind {
+                        StatementKind::Break => break_label.clone(),
+                        _ => continue_label.clone(),
+                    };
+                    self.ir_writer
+                        .write_program_flow(&ProgramFlow::Goto { label })?;
+                }
             }
-            ensure!(Symbol::Semicolon == self.next_symbol()?);
-            Ok(true)
-        } else {
-            Ok(false)
-        }
-    }
-    fn compile_statements(&mut self) -> Result<()> {
-        while let Some(keyword) = self.peek_keyword()? {
-            match keyword {
-                Keyword::Let => self.compile_let()?,
-                Keyword::If => self.compile_if()?,
-                Keyword::While => self.compile_while()?,
-                Keyword::Do => self.compile_do()?,
-                Keyword::Return => self.compile_return()?,
-                _ => break,
-            };
         }
         Ok(())
     }
-    fn compile_do(&mut self) -> Result<()> {
-        ensure!(Keyword::Do == self.next_keyword()?);
-        self.compile_subroutine_call()?;
+    fn compile_do(&mut self, call: &SubroutineCall) -> Result<()> {
+        self.compile_subroutine_call(call)?;
         self.ir_writer.write_memory_access(&MemoryAccess::Pop {
             segment: Segment::Temp,
             index: 0,
-        })?;
-        ensure!(Symbol::Semicolon == self.next_symbol()?);
-        Ok(())
+        })
     }
 
     fn kind_to_segment(kind: &Kind) -> Segment {
@@ -299,19 +211,25 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
         }
     }
 
-    fn compile_let(&mut self) -> Result<()> {
-        ensure!(Keyword::Let == self.next_keyword()?);
-        let var = self.next_identifier()?;
-        if let Some(Symbol::OpenBracket) = self.peek_symbol()? {
+    fn compile_let(
+        &mut self,
+        var: &Identifier,
+        index: Option<&Expression>,
+        value: &Expression,
+        span: &Span,
+    ) -> Result<()> {
+        if let Some(index) = index {
             // var[expr] = expr
-            self.compile_push_variable(&var)?;
-            ensure!(Symbol::OpenBracket == self.next_symbol()?);
-            self.compile_expression()?;
-            ensure!(Symbol::CloseBracket == self.next_symbol()?);
+            self.compile_push_variable(var, span)?;
+            self.compile_expression(index)?;
             self.ir_writer.write_arithmetic(&Arithmetic::Add)?;
 
-            ensure!(Symbol::Equal == self.next_symbol()?);
-            self.compile_expression()?;
+            self.compile_expression(value)?;
+            if self.extended_instructions {
+                return self
+                    .ir_writer
+                    .write_memory_access(&MemoryAccess::PopIndirect);
+            }
             self.ir_writer.write_memory_access(&MemoryAccess::Pop {
                 segment: Segment::Temp,
                 index: 0,
@@ -330,37 +248,108 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
             })?;
         } else {
             // var = expr
-            ensure!(Symbol::Equal == self.next_symbol()?);
-            let (kind, _type, id) = self
-                .symbol_table
-                .lookup(&var)
-                .with_context(|| format!("Unknown variable `{}`", var.0))?;
-            self.compile_expression()?;
+            let variable = self.lookup(var, span);
+            self.compile_expression(value)?;
+            if let Some((kind, _type, id)) = variable {
+                self.ir_writer.write_memory_access(&MemoryAccess::Pop {
+                    segment: Self::kind_to_segment(&kind),
+                    index: id,
+                })?;
+            }
+        }
+        Ok(())
+    }
+    /// `var op= value`, or `var++` and `var--` without a `value`, computing the address of
+    /// `var[index]` once.
+    fn compile_compound_assignment(
+        &mut self,
+        var: &Identifier,
+        index: Option<&Expression>,
+        operator: BinaryOperator,
+        value: Option<&Expression>,
+        span: &Span,
+    ) -> Result<()> {
+        let variable = self.lookup(var, span);
+        let segment = variable.map(|(kind, _type, id)| (Self::kind_to_segment(&kind), id));
+        if let Some(index) = index {
+            if let Some((segment, id)) = &segment {
+                self.ir_writer.write_memory_access(&MemoryAccess::Push {
+                    segment: segment.clone(),
+                    index: *id,
+                })?;
+            }
+            self.compile_expression(index)?;
+            self.ir_writer.write_arithmetic(&Arithmetic::Add)?;
+            // Keep the address below the element, as `value` may move `that`
             self.ir_writer.write_memory_access(&MemoryAccess::Pop {
-                segment: Self::kind_to_segment(&kind),
-                index: id,
+                segment: Segment::Pointer,
+                index: 1,
+            })?;
+            self.ir_writer.write_memory_access(&MemoryAccess::Push {
+                segment: Segment::Pointer,
+                index: 1,
+            })?;
+            self.ir_writer.write_memory_access(&MemoryAccess::Push {
+                segment: Segment::That,
+                index: 0,
+            })?;
+        } else if let Some((segment, id)) = &segment {
+            self.ir_writer.write_memory_access(&MemoryAccess::Push {
+                segment: segment.clone(),
+                index: *id,
             })?;
         }
-        ensure!(Symbol::Semicolon == self.next_symbol()?);
+        match value {
+            Some(value) => self.compile_expression(value)?,
+            None => self.compile_push_constant(1)?,
+        }
+        self.ir_writer.write_arithmetic(&match operator {
+            BinaryOperator::Add => Arithmetic::Add,
+            _ => Arithmetic::Sub,
+        })?;
+
+        if index.is_some() {
+            if self.extended_instructions {
+                return self
+                    .ir_writer
+                    .write_memory_access(&MemoryAccess::PopIndirect);
+            }
+            self.ir_writer.write_memory_access(&MemoryAccess::Pop {
+                segment: Segment::Temp,
+                index: 0,
+            })?;
+            self.ir_writer.write_memory_access(&MemoryAccess::Pop {
+                segment: Segment::Pointer,
+                index: 1,
+            })?;
+            self.ir_writer.write_memory_access(&MemoryAccess::Push {
+                segment: Segment::Temp,
+                index: 0,
+            })?;
+            self.ir_writer.write_memory_access(&MemoryAccess::Pop {
+                segment: Segment::That,
+                index: 0,
+            })?;
+        } else if let Some((segment, id)) = segment {
+            self.ir_writer
+                .write_memory_access(&MemoryAccess::Pop { segment, index: id })?;
+        }
         Ok(())
     }
-    fn compile_while(&mut self) -> Result<()> {
-        ensure!(Keyword::While == self.next_keyword()?);
-        ensure!(Symbol::OpenParen == self.next_symbol()?);
+    fn compile_while(&mut self, condition: &Expression, statements: &[Statement]) -> Result<()> {
         let before_while = self.generate_label("before_while");
         let after_while = self.generate_label("after_while");
         self.ir_writer.write_program_flow(&ProgramFlow::Label {
             label: before_while.clone(),
         })?;
-        self.compile_expression()?;
-        ensure!(Symbol::CloseParen == self.next_symbol()?);
+        self.compile_expression(condition)?;
         self.ir_writer.write_arithmetic(&Arithmetic::Not)?;
         self.ir_writer.write_program_flow(&ProgramFlow::IfGoto {
             label: after_while.clone(),
         })?;
-        ensure!(Symbol::OpenBrace == self.next_symbol()?);
-        self.compile_statements()?;
-        ensure!(Symbol::CloseBrace == self.next_symbol()?);
+        self.loops.push((before_while.clone(), after_while.clone()));
+        self.compile_statements(statements)?;
+        self.loops.pop();
         self.ir_writer.write_program_flow(&ProgramFlow::Goto {
             label: before_while,
         })?;
@@ -368,35 +357,66 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
             .write_program_flow(&ProgramFlow::Label { label: after_while })?;
         Ok(())
     }
-    fn compile_return(&mut self) -> Result<()> {
+    fn compile_for(
+        &mut self,
+        init: Option<&Statement>,
+        condition: &Expression,
+        step: Option<&Statement>,
+        statements: &[Statement],
+    ) -> Result<()> {
+        if let Some(init) = init {
+            self.compile_statements(std::slice::from_ref(init))?;
+        }
+        let before_for = self.generate_label("before_for");
+        let continue_for = self.generate_label("continue_for");
+        let after_for = self.generate_label("after_for");
+        self.ir_writer.write_program_flow(&ProgramFlow::Label {
+            label: before_for.clone(),
+        })?;
+        self.compile_expression(condition)?;
+        self.ir_writer.write_arithmetic(&Arithmetic::Not)?;
+        self.ir_writer.write_program_flow(&ProgramFlow::IfGoto {
+            label: after_for.clone(),
+        })?;
+        self.loops.push((continue_for.clone(), after_for.clone()));
+        self.compile_statements(statements)?;
+        self.loops.pop();
+        self.ir_writer.write_program_flow(&ProgramFlow::Label {
+            label: continue_for,
+        })?;
+        if let Some(step) = step {
+            self.compile_statements(std::slice::from_ref(step))?;
+        }
+        self.ir_writer
+            .write_program_flow(&ProgramFlow::Goto { label: before_for })?;
+        self.ir_writer
+            .write_program_flow(&ProgramFlow::Label { label: after_for })?;
+        Ok(())
+    }
+    fn compile_return(&mut self, value: Option<&Expression>) -> Result<()> {
         self.write_open("returnStatement")?;
-        ensure!(Keyword::Return == self.next_keyword()?);
-        if let Some(Symbol::Semicolon) = self.peek_symbol()? {
-            self.compile_push_constant(0)?;
-        } else {
-            self.compile_expression()?;
+        match value {
+            Some(value) => self.compile_expression(value)?,
+            None => self.compile_push_constant(0)?,
         }
-        ensure!(Symbol::Semicolon == self.next_symbol()?);
         self.ir_writer.write_function_call(&FunctionCall::Return)?;
         self.write_close("returnStatement")?;
         Ok(())
     }
-    fn compile_if(&mut self) -> Result<()> {
-        ensure!(Keyword::If == self.next_keyword()?);
-        ensure!(Symbol::OpenParen == self.next_symbol()?);
-        self.compile_expression()?;
-        ensure!(Symbol::CloseParen == self.next_symbol()?);
+    fn compile_if(
+        &mut self,
+        condition: &Expression,
+        then_statements: &[Statement],
+        else_statements: Option<&[Statement]>,
+    ) -> Result<()> {
+        self.compile_expression(condition)?;
         let end_if = self.generate_label("end_if");
         self.ir_writer.write_arithmetic(&Arithmetic::Not)?;
         self.ir_writer.write_program_flow(&ProgramFlow::IfGoto {
             label: end_if.clone(),
         })?;
-        ensure!(Symbol::OpenBrace == self.next_symbol()?);
-        self.compile_statements()?;
-        ensure!(Symbol::CloseBrace == self.next_symbol()?);
-        if let Some(Keyword::Else) = self.peek_keyword()? {
-            ensure!(Keyword::Else == self.next_keyword()?);
-            ensure!(Symbol::OpenBrace == self.next_symbol()?);
+        self.compile_statements(then_statements)?;
+        if let Some(else_statements) = else_statements {
             let end_else = self.generate_label("end_else");
             self.ir_writer.write_program_flow(&ProgramFlow::Goto {
                 label: end_else.clone(),
@@ -404,8 +424,7 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
             self.ir_writer.write_program_flow(&ProgramFlow::Label {
                 label: end_if.clone(),
             })?;
-            self.compile_statements()?;
-            ensure!(Symbol::CloseBrace == self.next_symbol()?);
+            self.compile_statements(else_statements)?;
             self.ir_writer.write_program_flow(&ProgramFlow::Label {
                 label: end_else.clone(),
             })?;
@@ -416,29 +435,30 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
         }
         Ok(())
     }
-    fn compile_subroutine_call(&mut self) -> Result<()> {
-        let ident = self.next_identifier()?;
-        let (method_name, added_n_args) = if let Some(Symbol::Dot) = self.peek_symbol()? {
-            ensure!(Symbol::Dot == self.next_symbol()?);
+    fn compile_subroutine_call(&mut self, call: &SubroutineCall) -> Result<()> {
+        let (method_name, added_n_args) = if let Some(var_or_class) = &call.receiver {
             // var.method() or Class.func()
-            let var_or_class = ident;
-            let func_or_method = self.next_identifier()?;
-            if let Some((_kind, variable_type, _id)) = self.symbol_table.lookup(&var_or_class) {
+            let func_or_method = &call.name;
+            if let Some((_kind, variable_type, _id)) = self.symbol_table.lookup(var_or_class) {
                 // var.method
                 let var = var_or_class;
-                self.compile_push_variable(&var)?;
-                if let VariableType::Object(object_type) = variable_type {
+                self.compile_push_variable(var, &call.span)?;
+                if let Type::Class(object_type) = variable_type {
+                    self.check_call(&object_type, call, true);
                     (format!("{}.{}", object_type.0, func_or_method.0), 1)
                 } else {
-                    bail!(
-                        "Primitive type {:?} doesn't have method {}",
+                    self.errors.push(anyhow!(
+                        "{}: Primitive type {:?} doesn't have method {}",
+                        call.span,
                         variable_type,
                         func_or_method.0
-                    );
+                    ));
+                    (func_or_method.0.clone(), 1)
                 }
             } else {
                 // Class.func()
                 let class = var_or_class;
+                self.check_call(class, call, false);
                 (format!("{}.{}", class.0, func_or_method.0), 0)
             }
         } else {
@@ -447,70 +467,62 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
                 segment: Segment::Pointer,
                 index: 0,
             })?;
-            (format!("{}.{}", self.get_class_name()?.0, ident.0), 1)
+            let class = self.get_class_name()?.clone();
+            self.check_call(&class, call, true);
+            if self.subroutine_kind == Some(SubroutineKind::Function) {
+                self.errors.push(anyhow!(
+                    "{}: Method `{}.{}` can't be called without an object from a function",
+                    call.span,
+                    class,
+                    call.name
+                ));
+            }
+            (format!("{}.{}", class.0, call.name.0), 1)
         };
-        ensure!(Symbol::OpenParen == self.next_symbol()?);
-        let n_args = self.compile_expression_list()?;
-        ensure!(Symbol::CloseParen == self.next_symbol()?);
+        let n_args = self.compile_expression_list(&call.arguments)?;
         self.ir_writer.write_function_call(&FunctionCall::Invoke {
             name: method_name.parse()?,
             n_args: n_args + added_n_args,
         })?;
         Ok(())
     }
-    fn compile_expression(&mut self) -> Result<()> {
-        self.compile_term()?;
-        let mut delayed: Vec<(Arithmetic, usize)> = Vec::new();
-        loop {
-            if let Some(symbol) = self.peek_symbol()? {
-                match symbol {
-                    Symbol::Plus
-                    | Symbol::Dash
-                    | Symbol::Ampersand
-                    | Symbol::VerticalBar
-                    | Symbol::LessThan
-                    | Symbol::GreaterThan
-                    | Symbol::Equal => {
-                        let symbol = self.next_symbol()?;
-                        let (op, precedence) = match symbol {
-                            Symbol::Plus => (Arithmetic::Add, 3),
-                            Symbol::Dash => (Arithmetic::Sub, 3),
-                            Symbol::Ampersand => (Arithmetic::And, 2),
-                            Symbol::VerticalBar => (Arithmetic::Or, 1),
-                            Symbol::LessThan => (Arithmetic::Lt, 0),
-                            Symbol::GreaterThan => (Arithmetic::Gt, 0),
-                            Symbol::Equal => (Arithmetic::Eq, 0),
-                            _ => unreachable!(),
-                        };
-                        while let Some((_, p)) = delayed.last() {
-                            if &precedence <= p {
-                                self.ir_writer.write_arithmetic(&delayed.pop().unwrap().0)?;
-                            } else {
-                                break;
-                            }
-                        }
-                        self.compile_term()?;
-                        delayed.push((op, precedence));
-                    }
-                    Symbol::Star => {
-                        ensure!(Symbol::Star == self.next_symbol()?);
-                        self.compile_term()?;
-                        self.compile_invoke_function("Math.multiply", 2)?
-                    }
-                    Symbol::Slash => {
-                        ensure!(Symbol::Slash == self.next_symbol()?);
-                        self.compile_term()?;
-                        self.compile_invoke_function("Math.divide", 2)?
-                    }
-                    _ => break,
+    /// Reports the errors of `call` to `class` against the signatures, if set.
+    fn check_call(&mut self, class: &Identifier, call: &SubroutineCall, as_method: bool) {
+        if let Some(signatures) = self.signatures {
+            self.errors
+                .extend(signatures.check_call(class, call, as_method));
+        }
+    }
+    fn compile_expression(&mut self, expression: &Expression) -> Result<()> {
+        for postfix in expression.postfix(self.semantics) {
+            match postfix {
+                Postfix::Term(term) => self.compile_term(term)?,
+                Postfix::Operator(operator) => {
+                    self.compile_library_arithmetic(&match operator {
+                        BinaryOperator::Add => Arithmetic::Add,
+                        BinaryOperator::Sub => Arithmetic::Sub,
+                        BinaryOperator::Mul => Arithmetic::Mul,
+                        BinaryOperator::Div => Arithmetic::Div,
+                        BinaryOperator::And => Arithmetic::And,
+                        BinaryOperator::Or => Arithmetic::Or,
+                        BinaryOperator::Lt => Arithmetic::Lt,
+                        BinaryOperator::Gt => Arithmetic::Gt,
+                        BinaryOperator::Eq => Arithmetic::Eq,
+                    })?
                 }
             }
         }
-        while let Some((op, _)) = delayed.pop() {
-            self.ir_writer.write_arithmetic(&op)?;
-        }
         Ok(())
     }
+    /// Writes `arithmetic`, or calls the library function computing it without extensions.
+    fn compile_library_arithmetic(&mut self, arithmetic: &Arithmetic) -> Result<()> {
+        match arithmetic.library_function() {
+            Some(function) if !self.extended_instructions => {
+                self.compile_invoke_function(function, 2)
+            }
+            _ => self.ir_writer.write_arithmetic(arithmetic),
+        }
+    }
     fn compile_push_constant(&mut self, v: i16) -> Result<()> {
         if v < 0 {
             self.compile_push_constant(!v)?;
@@ -522,7 +534,7 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
             })
         }
     }
-    fn compile_push_string_constant(&mut self, s: &String) -> Result<()> {
+    fn compile_push_string_constant(&mut self, s: &str) -> Result<()> {
         let v: Vec<_> = s.encode_utf16().collect();
         self.compile_push_constant(v.len() as i16)?;
         self.compile_invoke_function("String.new", 1)?;
@@ -532,125 +544,94 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
         }
         Ok(())
     }
-    fn compile_push_variable(&mut self, variable: &Identifier) -> Result<()> {
-        let (kind, _type, id) = self
-            .symbol_table
-            .lookup(variable)
-            .with_context(|| format!("Unknown variable {}", variable.0))?;
-        self.ir_writer.write_memory_access(&MemoryAccess::Push {
-            segment: Self::kind_to_segment(&kind),
-            index: id,
-        })
+    /// Looks `variable` up, reporting it if it is unknown.
+    fn lookup(&mut self, variable: &Identifier, span: &Span) -> Option<(Kind, Type, u16)> {
+        let found = self.symbol_table.lookup(variable);
+        if found.is_none() {
+            self.errors
+                .push(anyhow!("{}: Unknown variable `{}`", span, variable.0));
+        }
+        found
+    }
+    fn compile_push_variable(&mut self, variable: &Identifier, span: &Span) -> Result<()> {
+        match self.lookup(variable, span) {
+            Some((kind, _type, id)) => self.ir_writer.write_memory_access(&MemoryAccess::Push {
+                segment: Self::kind_to_segment(&kind),
+                index: id,
+            }),
+            None => Ok(()),
+        }
     }
     fn alloc(&mut self, n: u16) -> Result<()> {
         self.compile_push_constant(n as i16)?;
         self.compile_invoke_function("Memory.alloc", 1)
     }
 
-    fn compile_term(&mut self) -> Result<()> {
-        if let Some(token) = self.peek_token()? {
-            match token {
-                Token::IntegerConstant(_) | Token::StringConstant(_) => {
-                    let token = self.next_token()?;
-                    match token {
-                        Token::IntegerConstant(v) => self.compile_push_constant(v)?,
-                        Token::StringConstant(s) => self.compile_push_string_constant(&s)?,
-                        _ => unreachable!(),
-                    }
-                }
-                Token::Keyword(_) => {
-                    match self.next_keyword()? {
-                        Keyword::True => self.compile_push_constant(-1)?,
-                        // TODO: I have a feeling that it is better to set Null to -1...
-                        Keyword::False | Keyword::Null => self.compile_push_constant(0)?,
-                        Keyword::This => {
-                            self.ir_writer.write_memory_access(&MemoryAccess::Push {
-                                segment: Segment::Pointer,
-                                index: 0,
-                            })?
-                        }
-                        keyword => bail!("Unexpected keyword {:?}", keyword),
-                    }
+    fn compile_term(&mut self, term: &Term) -> Result<()> {
+        match &term.kind {
+            TermKind::IntegerConstant(v) => self.compile_push_constant(*v)?,
+            TermKind::StringConstant(s) => self.compile_push_string_constant(s)?,
+            TermKind::KeywordConstant(constant) => match constant {
+                KeywordConstant::True => self.compile_push_constant(-1)?,
+                // TODO: I have a feeling that it is better to set Null to -1...
+                KeywordConstant::False | KeywordConstant::Null => self.compile_push_constant(0)?,
+                KeywordConstant::This => {
+                    self.ir_writer.write_memory_access(&MemoryAccess::Push {
+                        segment: Segment::Pointer,
+                        index: 0,
+                    })?
                 }
-                Token::Identifier(_) => {
-                    let next_next_token = self.peek_nth(1)?;
-                    if Some(&Token::Symbol(Symbol::OpenParen)) == next_next_token
-                        || Some(&Token::Symbol(Symbol::Dot)) == next_next_token
-                    {
-                        // name '(' expression list ')'
-                        // var '.' name '(' expression list ')'
-                        // Function invocation.
-                        self.compile_subroutine_call()?;
-                    } else if Some(&Token::Symbol(Symbol::OpenBracket)) == next_next_token {
-                        // var '[' expression ']'
-                        // Array access
-                        let var_name = self.next_identifier()?;
-                        self.compile_push_variable(&var_name)?;
-                        ensure!(Symbol::OpenBracket == self.next_symbol()?);
-                        self.compile_expression()?;
-                        ensure!(Symbol::CloseBracket == self.next_symbol()?);
-                        self.ir_writer.write_arithmetic(&Arithmetic::Add)?;
-                        self.ir_writer.write_memory_access(&MemoryAccess::Pop {
-                            segment: Segment::Pointer,
-                            index: 1,
-                        })?;
-                        self.ir_writer.write_memory_access(&MemoryAccess::Push {
-                            segment: Segment::That,
-                            index: 0,
-                        })?;
-                    } else {
-                        // var
-                        let var_name = self.next_identifier()?;
-                        self.compile_push_variable(&var_name)?;
-                    }
-                }
-                Token::Symbol(symbol) => {
-                    match symbol {
-                        Symbol::OpenParen => {
-                            // '(' expression ')'
-                            ensure!(Symbol::OpenParen == self.next_symbol()?);
-                            self.compile_expression()?;
-                            ensure!(Symbol::CloseParen == self.next_symbol()?);
-                        }
-                        Symbol::Dash => {
-                            ensure!(Symbol::Dash == self.next_symbol()?);
-                            self.compile_term()?;
-                            self.ir_writer.write_arithmetic(&Arithmetic::Neg)?;
-                        }
-                        Symbol::Tilde => {
-                            ensure!(Symbol::Tilde == self.next_symbol()?);
-                            self.compile_term()?;
-                            self.ir_writer.write_arithmetic(&Arithmetic::Not)?;
-                        }
-                        _ => bail!("Unexpected symbol {:?}", symbol),
-                    }
+            },
+            // Function invocation.
+            TermKind::Call(call) => self.compile_subroutine_call(call)?,
+            // Array access
+            TermKind::ArrayElement(var_name, index) => {
+                self.compile_push_variable(var_name, &term.span)?;
+                self.compile_expression(index)?;
+                self.ir_writer.write_arithmetic(&Arithmetic::Add)?;
+                if self.extended_instructions {
+                    self.ir_writer
+                        .write_memory_access(&MemoryAccess::PushIndirect)?;
+                } else {
+                    self.ir_writer.write_memory_access(&MemoryAccess::Pop {
+                        segment: Segment::Pointer,
+                        index: 1,
+                    })?;
+                    self.ir_writer.write_memory_access(&MemoryAccess::Push {
+                        segment: Segment::That,
+                        index: 0,
+                    })?;
                 }
             }
-            Ok(())
-        } else {
-            bail!("Unexpected end of token stream")
+            TermKind::Variable(var_name) => self.compile_push_variable(var_name, &term.span)?,
+            TermKind::Parenthesized(expression) => self.compile_expression(expression)?,
+            TermKind::Unary(operator, term) => {
+                self.compile_term(term)?;
+                self.ir_writer.write_arithmetic(match operator {
+                    UnaryOperator::Neg => &Arithmetic::Neg,
+                    UnaryOperator::Not => &Arithmetic::Not,
+                })?;
+            }
         }
+        Ok(())
     }
-    fn compile_expression_list(&mut self) -> Result<u16> {
+    fn compile_expression_list(&mut self, expressions: &[Expression]) -> Result<u16> {
         self.write_open("expressionList")?;
-        let mut n = 0;
-        if Some(&Symbol::CloseParen) != self.peek_symbol()? {
-            self.compile_expression()?;
-            n += 1;
-            while Some(&Symbol::Comma) == self.peek_symbol()? {
-                ensure!(Symbol::Comma == self.next_symbol()?);
-                self.compile_expression()?;
-                n += 1;
-            }
+        for expression in expressions {
+            self.compile_expression(expression)?;
         }
         self.write_close("expressionList")?;
-        Ok(n)
+        Ok(expressions.len() as u16)
     }
 
-    pub fn compile(&mut self) -> Result<()> {
-        self.compile_class()
+    /// Compiles `class`, failing with every semantic error in it.
+    pub fn compile(&mut self, class: &Class) -> Result<()> {
+        self.compile_class(class)
             .with_context(|| "Failed to compile...")?;
         self.ir_writer.flush()?;
+        if !self.errors.is_empty() {
+            return Err(Errors(std::mem::take(&mut self.errors)).into());
+        }
         Ok(())
     }
     fn write_open(&mut self, tag: &str) -> Result<()> {
@@ -664,6 +645,10 @@ impl<I: Iterator<Item = Result<Token>>, W: Write> IRAnalyzer<I, W> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::ir::parser::Parser;
+    use crate::ir::verifier::Verifier;
+    use crate::ir::Command;
+    use crate::jack::parser::Parser as JackParser;
     use crate::jack::tokenizer::TokenIterator;
     use anyhow::Result;
 
@@ -682,11 +667,108 @@ mod test {
             }
             "#
         .as_bytes();
-        let token_iterator = TokenIterator::from(input);
-        let ret = vec![];
-        let writer = IRWriter::new(ret);
-        let mut analyzer = IRAnalyzer::from(token_iterator, writer);
-        analyzer.compile()?;
+        let class = JackParser::parse(TokenIterator::from(input))?;
+        let mut ret = vec![];
+        let writer = IRWriter::new(&mut ret);
+        let mut analyzer = IRAnalyzer::new(writer);
+        analyzer.compile(&class)?;
+        Verifier::verify(&Parser::parse(ret.as_slice())?)?;
+
+        let input = "class Main {\n  function int f() {\n    return y;\n  }\n}".as_bytes();
+        let class = JackParser::parse(TokenIterator::from(input))?;
+        let error = IRAnalyzer::new(IRWriter::new(vec![]))
+            .compile(&class)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "3:12: Unknown variable `y`");
+
+        let input = r#"
+            class Main {
+              function void main() {
+                do Output.printInt();
+                do Main.f();
+                do f();
+                do Foo.bar(1);
+                return;
+              }
+              method void f() { return; }
+            }
+            "#
+        .as_bytes();
+        let class = JackParser::parse(TokenIterator::from(input))?;
+        let mut signatures = Signatures::new();
+        signatures.add_os_api();
+        signatures.add_class(&class);
+        let mut expected = vec![
+            "4:20: `Output.printInt` takes 1 arguments but was given 0",
+            "5:20: `Main.f` is a method but is called as a function",
+            "6:20: Method `Main.f` can't be called without an object from a function",
+        ];
+        for complete in [false, true] {
+            signatures.set_complete(complete);
+            if complete {
+                expected.push("7:20: `Foo` is not defined");
+            }
+            let mut analyzer = IRAnalyzer::new(IRWriter::new(vec![]));
+            analyzer.set_signatures(&signatures);
+            let error = analyzer.compile(&class).unwrap_err();
+            assert_eq!(error.to_string(), expected.join("\n"));
+        }
+
+        let input = "class Main { function int f(int a) { return 1 + 2 * a; } }".as_bytes();
+        let class = JackParser::parse(TokenIterator::from(input))?;
+        let compile = |semantics| -> Result<Vec<Command>> {
+            let mut ret = vec![];
+            let mut analyzer = IRAnalyzer::new(IRWriter::new(&mut ret));
+            analyzer.set_semantics(semantics);
+            analyzer.compile(&class)?;
+            Parser::parse(ret.as_slice())
+        };
+        assert_eq!(
+            compile(Semantics::Precedence)?,
+            Parser::parse(
+                "function Main.f 0\npush constant 1\npush constant 2\npush argument 0\n\
+                 call Math.multiply 2\nadd\nreturn"
+                    .as_bytes()
+            )?
+        );
+        assert_eq!(
+            compile(Semantics::LeftToRight)?,
+            Parser::parse(
+                "function Main.f 0\npush constant 1\npush constant 2\nadd\npush argument 0\n\
+                 call Math.multiply 2\nreturn"
+                    .as_bytes()
+            )?
+        );
+
+        let input = "class Main { function void g(Array a) { let a[Main.f()] += 1; return; } }";
+        let mut token_iterator = TokenIterator::from(input.as_bytes());
+        token_iterator.set_language_extensions(true);
+        let class = JackParser::parse(token_iterator)?;
+        let mut ret = vec![];
+        IRAnalyzer::new(IRWriter::new(&mut ret)).compile(&class)?;
+        assert_eq!(
+            Parser::parse(ret.as_slice())?,
+            Parser::parse(
+                r#"
+                function Main.g 0
+                push argument 0
+                call Main.f 0
+                add
+                pop pointer 1
+                push pointer 1
+                push that 0
+                push constant 1
+                add
+                pop temp 0
+                pop pointer 1
+                push temp 0
+                pop that 0
+                push constant 0
+                return
+                "#
+                .as_bytes()
+            )?
+        );
         Ok(())
     }
 }
@@ -1,5 +1,32 @@
+use anyhow::Error;
+use std::fmt::{self, Display, Formatter};
+
+pub mod ast;
 pub mod ir_analyzer;
+pub mod parser;
+pub mod return_checker;
+pub mod signatures;
 mod symbol_table;
-mod token;
+pub mod token;
 pub mod tokenizer;
+pub mod type_checker;
+pub mod warnings;
 pub mod xml_analyzer;
+
+/// Every error found in a class, reported one per line.
+#[derive(Debug)]
+pub struct Errors(pub Vec<Error>);
+
+impl Display for Errors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:#}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Errors {}
@@ -0,0 +1,855 @@
+use crate::jack::ast::{
+    BinaryOperator, Class, ClassVarDec, ClassVarKind, Expression, KeywordConstant, Parameter,
+    Statement, StatementKind, Subroutine, SubroutineCall, SubroutineKind, Term, TermKind, Type,
+    UnaryOperator, VarDec,
+};
+use crate::jack::token::{Identifier, Keyword, Span, Symbol, Token};
+use crate::jack::Errors;
+use anyhow::{anyhow, bail, Error, Result};
+use std::collections::VecDeque;
+use std::iter::Fuse;
+
+/// Recursive-descent parser building the AST of a class from its tokens.
+pub struct Parser<I: Iterator<Item = Result<(Token, Span)>>> {
+    token_stream: Fuse<I>,
+    peeked: VecDeque<Result<(Token, Span)>>,
+    // The span of the last token taken, where spans of nodes end
+    last_span: Span,
+    // The errors recovered from so far
+    errors: Vec<Error>,
+    // How many loops the next statement is in, for `break` and `continue`
+    loop_depth: usize,
+}
+
+impl<I: Iterator<Item = Result<(Token, Span)>>> Parser<I> {
+    /// Parses the single class making up `token_stream`, failing with every syntax error in it.
+    pub fn parse(token_stream: I) -> Result<Class> {
+        match Self::parse_recovering(token_stream) {
+            (Some(class), errors) if errors.is_empty() => Ok(class),
+            (_, errors) => Err(Errors(errors).into()),
+        }
+    }
+
+    /// Parses the single class making up `token_stream`, skipping the statements and
+    /// declarations with syntax errors to find all of them. The class is missing if even its
+    /// header has errors.
+    pub fn parse_recovering(token_stream: I) -> (Option<Class>, Vec<Error>) {
+        let mut parser = Self {
+            token_stream: token_stream.fuse(),
+            peeked: VecDeque::new(),
+            last_span: Span::default(),
+            errors: vec![],
+            loop_depth: 0,
+        };
+        let class = match parser.parse_class() {
+            Ok(class) => Some(class),
+            Err(e) => {
+                parser.errors.push(e);
+                None
+            }
+        };
+        (class, parser.errors)
+    }
+
+    fn peek_more(&mut self) -> bool {
+        if let Some(token) = self.token_stream.next() {
+            self.peeked.push_back(token);
+            true
+        } else {
+            false
+        }
+    }
+    fn peek_nth_with_span(&mut self, i: usize) -> Result<Option<&(Token, Span)>> {
+        while self.peeked.len() <= i {
+            if !self.peek_more() {
+                return Ok(None);
+            }
+        }
+        if self.peeked[i].is_err() {
+            // Parsing stops here, so give up the error rather than a vague one
+            return self.peeked.remove(i).unwrap().map(|_| None);
+        }
+        Ok(self.peeked[i].as_ref().ok())
+    }
+    fn peek_nth(&mut self, i: usize) -> Result<Option<&Token>> {
+        Ok(self.peek_nth_with_span(i)?.map(|(token, _)| token))
+    }
+    fn peek_token(&mut self) -> Result<Option<&Token>> {
+        self.peek_nth(0)
+    }
+    /// The span of the next token, where a node parsed from it starts.
+    fn peek_span(&mut self) -> Result<Span> {
+        Ok(match self.peek_nth_with_span(0)? {
+            Some((_, span)) => span.clone(),
+            None => self.last_span.clone(),
+        })
+    }
+    fn peek_keyword(&mut self) -> Result<Option<&Keyword>> {
+        Ok(match self.peek_token()? {
+            Some(Token::Keyword(keyword)) => Some(keyword),
+            _ => None,
+        })
+    }
+    fn peek_symbol(&mut self) -> Result<Option<&Symbol>> {
+        Ok(match self.peek_token()? {
+            Some(Token::Symbol(symbol)) => Some(symbol),
+            _ => None,
+        })
+    }
+
+    fn next_token(&mut self) -> Result<Token> {
+        let next = match self.peeked.pop_front() {
+            Some(peeked) => peeked,
+            None => match self.token_stream.next() {
+                Some(next) => next,
+                None => bail!(
+                    "{}: Expected a token but reached the end of the file",
+                    self.last_span
+                ),
+            },
+        };
+        let (token, span) = next?;
+        self.last_span = span;
+        Ok(token)
+    }
+    /// The error for the next token not being `expected`, which is left to recover from.
+    fn unexpected(&mut self, expected: &str) -> Error {
+        match self.peek_nth_with_span(0) {
+            Ok(Some((token, span))) => {
+                anyhow!("{}: Expected {} but was `{}`", span, expected, token)
+            }
+            Ok(None) => anyhow!(
+                "{}: Expected {} but reached the end of the file",
+                self.last_span,
+                expected
+            ),
+            Err(e) => e,
+        }
+    }
+    /// Takes the next token if `f` accepts it, leaving it otherwise.
+    fn next_if<T, F>(&mut self, expected: &str, f: F) -> Result<T>
+    where
+        F: Fn(&Token) -> Option<T>,
+    {
+        match self.peek_token()?.and_then(f) {
+            Some(value) => {
+                self.next_token()?;
+                Ok(value)
+            }
+            None => Err(self.unexpected(expected)),
+        }
+    }
+    fn next_keyword(&mut self) -> Result<Keyword> {
+        self.next_if("a keyword", |token| match token {
+            Token::Keyword(keyword) => Some(*keyword),
+            _ => None,
+        })
+    }
+    fn next_identifier(&mut self) -> Result<Identifier> {
+        self.next_if("an identifier", |token| match token {
+            Token::Identifier(identifier) => Some(identifier.clone()),
+            _ => None,
+        })
+    }
+    fn next_symbol(&mut self) -> Result<Symbol> {
+        self.next_if("a symbol", |token| match token {
+            Token::Symbol(symbol) => Some(*symbol),
+            _ => None,
+        })
+    }
+    fn expect_keyword(&mut self, expected: Keyword) -> Result<()> {
+        self.next_if(&format!("`{}`", expected), |token| match token {
+            Token::Keyword(keyword) if *keyword == expected => Some(()),
+            _ => None,
+        })
+    }
+    fn expect_symbol(&mut self, expected: Symbol) -> Result<()> {
+        self.next_if(&format!("`{}`", expected), |token| match token {
+            Token::Symbol(symbol) if *symbol == expected => Some(()),
+            _ => None,
+        })
+    }
+
+    fn next_non_void_type(&mut self) -> Result<Type> {
+        self.next_if("a type", |token| match token {
+            Token::Keyword(Keyword::Int) => Some(Type::Int),
+            Token::Keyword(Keyword::Char) => Some(Type::Char),
+            Token::Keyword(Keyword::Boolean) => Some(Type::Boolean),
+            Token::Identifier(identifier) => Some(Type::Class(identifier.clone())),
+            _ => None,
+        })
+    }
+    fn next_voidable_type(&mut self) -> Result<Option<Type>> {
+        if Some(&Keyword::Void) == self.peek_keyword()? {
+            self.next_token()?;
+            Ok(None)
+        } else {
+            Ok(Some(self.next_non_void_type()?))
+        }
+    }
+    /// Identifiers separated by commas, with their spans.
+    fn next_identifiers(&mut self) -> Result<Vec<(Identifier, Span)>> {
+        let mut names = vec![];
+        loop {
+            let name = self.next_identifier()?;
+            names.push((name, self.last_span.clone()));
+            if Some(&Symbol::Comma) != self.peek_symbol()? {
+                return Ok(names);
+            }
+            self.expect_symbol(Symbol::Comma)?;
+        }
+    }
+
+    fn span_from(&self, start: &Span) -> Span {
+        start.to(&self.last_span)
+    }
+
+    /// Skips tokens until `stop` accepts one, or taking a `;` when `statement` is set, to resume
+    /// parsing after an error. Blocks in a statement are skipped whole, so that their `}` doesn't
+    /// close the enclosing one.
+    fn skip_until<F>(&mut self, stop: F, statement: bool)
+    where
+        F: Fn(&Token) -> bool,
+    {
+        let mut depth = 0;
+        loop {
+            match self.peek_token() {
+                Ok(Some(token)) if depth == 0 && stop(token) => return,
+                Ok(Some(token)) => {
+                    let end = match token {
+                        Token::Symbol(Symbol::OpenBrace) if statement => {
+                            depth += 1;
+                            false
+                        }
+                        Token::Symbol(Symbol::CloseBrace) if depth > 0 => {
+                            depth -= 1;
+                            depth == 0
+                        }
+                        Token::Symbol(Symbol::Semicolon) => statement && depth == 0,
+                        _ => false,
+                    };
+                    let _ = self.next_token();
+                    if end {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => self.errors.push(e),
+            }
+        }
+    }
+    /// Records `error` and skips the rest of the statement it is in.
+    fn recover_statement(&mut self, error: Error) {
+        self.errors.push(error);
+        self.skip_until(
+            |token| {
+                matches!(
+                    token,
+                    Token::Symbol(Symbol::CloseBrace)
+                        | Token::Keyword(
+                            Keyword::Let
+                                | Keyword::If
+                                | Keyword::While
+                                | Keyword::Do
+                                | Keyword::Return
+                                | Keyword::For
+                                | Keyword::Break
+                                | Keyword::Continue
+                        )
+                )
+            },
+            true,
+        );
+    }
+    /// Records `error` and skips to the next declaration of a class variable or a subroutine.
+    fn recover_class_member(&mut self, error: Error) {
+        self.errors.push(error);
+        self.skip_until(
+            |token| {
+                matches!(
+                    token,
+                    Token::Keyword(
+                        Keyword::Static
+                            | Keyword::Field
+                            | Keyword::Constructor
+                            | Keyword::Function
+                            | Keyword::Method
+                    )
+                )
+            },
+            false,
+        );
+    }
+
+    fn parse_class(&mut self) -> Result<Class> {
+        let start = self.peek_span()?;
+        self.expect_keyword(Keyword::Class)?;
+        let name = self.next_identifier()?;
+        self.expect_symbol(Symbol::OpenBrace)?;
+        let mut var_decs = vec![];
+        loop {
+            match self.try_parse_class_var_dec() {
+                Ok(Some(var_dec)) => var_decs.push(var_dec),
+                Ok(None) => break,
+                Err(e) => self.recover_class_member(e),
+            }
+        }
+        let mut subroutines = vec![];
+        loop {
+            match self.try_parse_subroutine() {
+                Ok(Some(subroutine)) => subroutines.push(subroutine),
+                Ok(None) => break,
+                Err(e) => self.recover_class_member(e),
+            }
+        }
+        // Recovering from an error may have skipped the closing brace, and leftover tokens are
+        // reported without losing the members parsed so far
+        if self.errors.is_empty() || self.peek_token()?.is_some() {
+            let end =
+                self.expect_symbol(Symbol::CloseBrace)
+                    .and_then(|()| match self.peek_token()? {
+                        Some(_) => Err(self.unexpected("the end of the file")),
+                        None => Ok(()),
+                    });
+            if let Err(e) = end {
+                self.errors.push(e);
+            }
+        }
+        Ok(Class {
+            name,
+            var_decs,
+            subroutines,
+            span: self.span_from(&start),
+        })
+    }
+    fn try_parse_class_var_dec(&mut self) -> Result<Option<ClassVarDec>> {
+        let kind = match self.peek_keyword()? {
+            Some(Keyword::Static) => ClassVarKind::Static,
+            Some(Keyword::Field) => ClassVarKind::Field,
+            _ => return Ok(None),
+        };
+        let start = self.peek_span()?;
+        self.next_keyword()?;
+        let var_type = self.next_non_void_type()?;
+        let names = self.next_identifiers()?;
+        self.expect_symbol(Symbol::Semicolon)?;
+        Ok(Some(ClassVarDec {
+            kind,
+            var_type,
+            names,
+            span: self.span_from(&start),
+        }))
+    }
+    fn try_parse_subroutine(&mut self) -> Result<Option<Subroutine>> {
+        let kind = match self.peek_keyword()? {
+            Some(Keyword::Constructor) => SubroutineKind::Constructor,
+            Some(Keyword::Function) => SubroutineKind::Function,
+            Some(Keyword::Method) => SubroutineKind::Method,
+            _ => return Ok(None),
+        };
+        let start = self.peek_span()?;
+        self.next_keyword()?;
+        let return_type = self.next_voidable_type()?;
+        let name = self.next_identifier()?;
+        self.expect_symbol(Symbol::OpenParen)?;
+        let parameters = self.parse_parameter_list()?;
+        self.expect_symbol(Symbol::CloseParen)?;
+        self.expect_symbol(Symbol::OpenBrace)?;
+        let mut var_decs = vec![];
+        loop {
+            match self.try_parse_var_dec() {
+                Ok(Some(var_dec)) => var_decs.push(var_dec),
+                Ok(None) => break,
+                Err(e) => self.recover_statement(e),
+            }
+        }
+        let statements = self.parse_statements()?;
+        self.expect_symbol(Symbol::CloseBrace)?;
+        Ok(Some(Subroutine {
+            kind,
+            return_type,
+            name,
+            parameters,
+            var_decs,
+            statements,
+            span: self.span_from(&start),
+        }))
+    }
+    fn parse_parameter_list(&mut self) -> Result<Vec<Parameter>> {
+        let mut parameters = vec![];
+        if self.peek_symbol()?.is_none() {
+            loop {
+                let start = self.peek_span()?;
+                let var_type = self.next_non_void_type()?;
+                let name = self.next_identifier()?;
+                parameters.push(Parameter {
+                    var_type,
+                    name,
+                    span: self.span_from(&start),
+                });
+                if Some(&Symbol::Comma) != self.peek_symbol()? {
+                    break;
+                }
+                self.expect_symbol(Symbol::Comma)?;
+            }
+        }
+        Ok(parameters)
+    }
+    fn try_parse_var_dec(&mut self) -> Result<Option<VarDec>> {
+        if Some(&Keyword::Var) != self.peek_keyword()? {
+            return Ok(None);
+        }
+        let start = self.peek_span()?;
+        self.expect_keyword(Keyword::Var)?;
+        let var_type = self.next_non_void_type()?;
+        let names = self.next_identifiers()?;
+        self.expect_symbol(Symbol::Semicolon)?;
+        Ok(Some(VarDec {
+            var_type,
+            names,
+            span: self.span_from(&start),
+        }))
+    }
+
+    fn parse_statements(&mut self) -> Result<Vec<Statement>> {
+        let mut statements = vec![];
+        loop {
+            match self.try_parse_statement() {
+                Ok(Some(statement)) => statements.push(statement),
+                Ok(None) => break,
+                Err(e) => self.recover_statement(e),
+            }
+        }
+        Ok(statements)
+    }
+    fn try_parse_statement(&mut self) -> Result<Option<Statement>> {
+        let start = self.peek_span()?;
+        let kind = match self.peek_keyword()? {
+            Some(Keyword::Let) => self.parse_let()?,
+            Some(Keyword::If) => self.parse_if()?,
+            Some(Keyword::While) => self.parse_while()?,
+            Some(Keyword::Do) => self.parse_do()?,
+            Some(Keyword::Return) => self.parse_return()?,
+            Some(Keyword::For) => self.parse_for()?,
+            Some(Keyword::Break) | Some(Keyword::Continue) => self.parse_jump()?,
+            _ => return Ok(None),
+        };
+        Ok(Some(Statement {
+            kind,
+            span: self.span_from(&start),
+        }))
+    }
+    /// `{ statements }`
+    fn parse_block(&mut self) -> Result<Vec<Statement>> {
+        self.expect_symbol(Symbol::OpenBrace)?;
+        let statements = self.parse_statements()?;
+        self.expect_symbol(Symbol::CloseBrace)?;
+        Ok(statements)
+    }
+    /// `( expression )`
+    fn parse_condition(&mut self) -> Result<Expression> {
+        self.expect_symbol(Symbol::OpenParen)?;
+        let condition = self.parse_expression()?;
+        self.expect_symbol(Symbol::CloseParen)?;
+        Ok(condition)
+    }
+    fn parse_do(&mut self) -> Result<StatementKind> {
+        self.expect_keyword(Keyword::Do)?;
+        let call = self.parse_subroutine_call()?;
+        self.expect_symbol(Symbol::Semicolon)?;
+        Ok(StatementKind::Do(call))
+    }
+    fn parse_let(&mut self) -> Result<StatementKind> {
+        self.expect_keyword(Keyword::Let)?;
+        let assignment = self.parse_assignment()?;
+        self.expect_symbol(Symbol::Semicolon)?;
+        Ok(assignment)
+    }
+    /// `name = value` or `name[index] = value`, or with the language extensions `+= value`,
+    /// `-= value`, `++` or `--` in place of `= value`.
+    fn parse_assignment(&mut self) -> Result<StatementKind> {
+        let name = self.next_identifier()?;
+        let index = if let Some(Symbol::OpenBracket) = self.peek_symbol()? {
+            self.expect_symbol(Symbol::OpenBracket)?;
+            let index = self.parse_expression()?;
+            self.expect_symbol(Symbol::CloseBracket)?;
+            Some(Box::new(index))
+        } else {
+            None
+        };
+        let symbol = self.next_if("`=`", |token| match token {
+            Token::Symbol(
+                symbol @ (Symbol::Equal
+                | Symbol::PlusEqual
+                | Symbol::DashEqual
+                | Symbol::PlusPlus
+                | Symbol::DashDash),
+            ) => Some(*symbol),
+            _ => None,
+        })?;
+        let value = match symbol {
+            Symbol::Equal => {
+                let value = self.parse_expression()?;
+                return Ok(StatementKind::Let { name, index, value });
+            }
+            Symbol::PlusEqual | Symbol::DashEqual => Some(self.parse_expression()?),
+            _ => None,
+        };
+        let operator = match symbol {
+            Symbol::PlusEqual | Symbol::PlusPlus => BinaryOperator::Add,
+            _ => BinaryOperator::Sub,
+        };
+        Ok(StatementKind::CompoundAssignment {
+            name,
+            index,
+            operator,
+            value,
+        })
+    }
+    /// The statements of a loop, in which `break` and `continue` are allowed.
+    fn parse_loop_block(&mut self) -> Result<Vec<Statement>> {
+        self.loop_depth += 1;
+        let statements = self.parse_block();
+        self.loop_depth -= 1;
+        statements
+    }
+    fn parse_while(&mut self) -> Result<StatementKind> {
+        self.expect_keyword(Keyword::While)?;
+        let condition = self.parse_condition()?;
+        let statements = self.parse_loop_block()?;
+        Ok(StatementKind::While {
+            condition,
+            statements,
+        })
+    }
+    /// `for (init; condition; step) { statements }`, where `init` and `step` are optional
+    /// assignments without `let`.
+    fn parse_for(&mut self) -> Result<StatementKind> {
+        self.expect_keyword(Keyword::For)?;
+        self.expect_symbol(Symbol::OpenParen)?;
+        let init = self.parse_optional_assignment(Symbol::Semicolon)?;
+        self.expect_symbol(Symbol::Semicolon)?;
+        let condition = self.parse_expression()?;
+        self.expect_symbol(Symbol::Semicolon)?;
+        let step = self.parse_optional_assignment(Symbol::CloseParen)?;
+        self.expect_symbol(Symbol::CloseParen)?;
+        let statements = self.parse_loop_block()?;
+        Ok(StatementKind::For {
+            init,
+            condition,
+            step,
+            statements,
+        })
+    }
+    fn parse_optional_assignment(&mut self, end: Symbol) -> Result<Option<Box<Statement>>> {
+        if Some(&end) == self.peek_symbol()? {
+            return Ok(None);
+        }
+        let start = self.peek_span()?;
+        let kind = self.parse_assignment()?;
+        Ok(Some(Box::new(Statement {
+            kind,
+            span: self.span_from(&start),
+        })))
+    }
+    /// `break;` or `continue;`
+    fn parse_jump(&mut self) -> Result<StatementKind> {
+        let span = self.peek_span()?;
+        let keyword = self.next_keyword()?;
+        if self.loop_depth == 0 {
+            bail!("{}: `{}` outside of a loop", span, keyword);
+        }
+        self.expect_symbol(Symbol::Semicolon)?;
+        Ok(match keyword {
+            Keyword::Break => StatementKind::Break,
+            _ => StatementKind::Continue,
+        })
+    }
+    fn parse_return(&mut self) -> Result<StatementKind> {
+        self.expect_keyword(Keyword::Return)?;
+        let value = if let Some(Symbol::Semicolon) = self.peek_symbol()? {
+            None
+        } else {
+            Some(self.parse_expression()?)
+        };
+        self.expect_symbol(Symbol::Semicolon)?;
+        Ok(StatementKind::Return(value))
+    }
+    fn parse_if(&mut self) -> Result<StatementKind> {
+        self.expect_keyword(Keyword::If)?;
+        let condition = self.parse_condition()?;
+        let then_statements = self.parse_block()?;
+        let else_statements = if let Some(Keyword::Else) = self.peek_keyword()? {
+            self.expect_keyword(Keyword::Else)?;
+            Some(self.parse_block()?)
+        } else {
+            None
+        };
+        Ok(StatementKind::If {
+            condition,
+            then_statements,
+            else_statements,
+        })
+    }
+    fn parse_subroutine_call(&mut self) -> Result<SubroutineCall> {
+        let start = self.peek_span()?;
+        let mut name = self.next_identifier()?;
+        let mut receiver = None;
+        if let Some(Symbol::Dot) = self.peek_symbol()? {
+            self.expect_symbol(Symbol::Dot)?;
+            receiver = Some(name);
+            name = self.next_identifier()?;
+        }
+        self.expect_symbol(Symbol::OpenParen)?;
+        let arguments = self.parse_expression_list()?;
+        self.expect_symbol(Symbol::CloseParen)?;
+        Ok(SubroutineCall {
+            receiver,
+            name,
+            arguments,
+            span: self.span_from(&start),
+        })
+    }
+    fn parse_expression(&mut self) -> Result<Expression> {
+        let start = self.peek_span()?;
+        let term = self.parse_term()?;
+        let mut operations = vec![];
+        loop {
+            let operator = match self.peek_symbol()? {
+                Some(Symbol::Plus) => BinaryOperator::Add,
+                Some(Symbol::Dash) => BinaryOperator::Sub,
+                Some(Symbol::Star) => BinaryOperator::Mul,
+                Some(Symbol::Slash) => BinaryOperator::Div,
+                Some(Symbol::Ampersand) => BinaryOperator::And,
+                Some(Symbol::VerticalBar) => BinaryOperator::Or,
+                Some(Symbol::LessThan) => BinaryOperator::Lt,
+                Some(Symbol::GreaterThan) => BinaryOperator::Gt,
+                Some(Symbol::Equal) => BinaryOperator::Eq,
+                _ => break,
+            };
+            self.next_symbol()?;
+            operations.push((operator, self.parse_term()?));
+        }
+        Ok(Expression {
+            term,
+            operations,
+            span: self.span_from(&start),
+        })
+    }
+    fn parse_term(&mut self) -> Result<Term> {
+        let start = self.peek_span()?;
+        let kind = match self.peek_token()? {
+            Some(Token::IntegerConstant(_)) | Some(Token::StringConstant(_)) => {
+                match self.next_token()? {
+                    Token::IntegerConstant(v) => TermKind::IntegerConstant(v),
+                    Token::StringConstant(s) => TermKind::StringConstant(s),
+                    _ => unreachable!(),
+                }
+            }
+            Some(Token::Keyword(_)) => {
+                TermKind::KeywordConstant(self.next_if("a term", |token| match token {
+                    Token::Keyword(Keyword::True) => Some(KeywordConstant::True),
+                    Token::Keyword(Keyword::False) => Some(KeywordConstant::False),
+                    Token::Keyword(Keyword::Null) => Some(KeywordConstant::Null),
+                    Token::Keyword(Keyword::This) => Some(KeywordConstant::This),
+                    _ => None,
+                })?)
+            }
+            Some(Token::Identifier(_)) => match self.peek_nth(1)? {
+                // name '(' expression list ')'
+                // var '.' name '(' expression list ')'
+                Some(Token::Symbol(Symbol::OpenParen)) | Some(Token::Symbol(Symbol::Dot)) => {
+                    TermKind::Call(self.parse_subroutine_call()?)
+                }
+                // var '[' expression ']'
+                Some(Token::Symbol(Symbol::OpenBracket)) => {
+                    let name = self.next_identifier()?;
+                    self.expect_symbol(Symbol::OpenBracket)?;
+                    let index = self.parse_expression()?;
+                    self.expect_symbol(Symbol::CloseBracket)?;
+                    TermKind::ArrayElement(name, Box::new(index))
+                }
+                _ => TermKind::Variable(self.next_identifier()?),
+            },
+            Some(Token::Symbol(Symbol::OpenParen)) => {
+                self.expect_symbol(Symbol::OpenParen)?;
+                let expression = self.parse_expression()?;
+                self.expect_symbol(Symbol::CloseParen)?;
+                TermKind::Parenthesized(Box::new(expression))
+            }
+            Some(Token::Symbol(Symbol::Dash)) | Some(Token::Symbol(Symbol::Tilde)) => {
+                let operator = match self.next_symbol()? {
+                    Symbol::Dash => UnaryOperator::Neg,
+                    _ => UnaryOperator::Not,
+                };
+                TermKind::Unary(operator, Box::new(self.parse_term()?))
+            }
+            Some(Token::Symbol(_)) | None => return Err(self.unexpected("a term")),
+        };
+        Ok(Term {
+            kind,
+            span: self.span_from(&start),
+        })
+    }
+    fn parse_expression_list(&mut self) -> Result<Vec<Expression>> {
+        let mut expressions = vec![];
+        if Some(&Symbol::CloseParen) != self.peek_symbol()? {
+            expressions.push(self.parse_expression()?);
+            while Some(&Symbol::Comma) == self.peek_symbol()? {
+                self.expect_symbol(Symbol::Comma)?;
+                expressions.push(self.parse_expression()?);
+            }
+        }
+        Ok(expressions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jack::tokenizer::TokenIterator;
+
+    #[test]
+    fn test() -> Result<()> {
+        let input = r#"
+            class Main {
+              field int x;
+              method int f(int a) {
+                var Array b;
+                let b[a] = -x + 2 * a;
+                return b[a];
+              }
+            }
+            "#
+        .as_bytes();
+        let class = Parser::parse(TokenIterator::from(input))?;
+        assert_eq!(class.name.0, "Main");
+        assert_eq!(class.var_decs[0].kind, ClassVarKind::Field);
+        let method = &class.subroutines[0];
+        assert_eq!(method.return_type, Some(Type::Int));
+        assert_eq!(method.var_decs[0].var_type, Type::Class("Array".parse()?));
+        let (index, value) = match &method.statements[0].kind {
+            StatementKind::Let {
+                index: Some(index),
+                value,
+                ..
+            } => (index, value),
+            statement => panic!("Unexpected statement {:?}", statement),
+        };
+        assert_eq!(index.term.kind, TermKind::Variable("a".parse()?));
+        assert!(matches!(
+            value.term.kind,
+            TermKind::Unary(UnaryOperator::Neg, _)
+        ));
+        assert_eq!(
+            value
+                .operations
+                .iter()
+                .map(|(operator, _)| *operator)
+                .collect::<Vec<_>>(),
+            [BinaryOperator::Add, BinaryOperator::Mul]
+        );
+        let span = &method.statements[0].span;
+        assert_eq!((span.line, span.column), (6, 17));
+        assert_eq!(&input[span.bytes.clone()], b"let b[a] = -x + 2 * a;");
+
+        let unfinished = "class Main { function void f() { return 1 +".as_bytes();
+        assert!(Parser::parse(TokenIterator::from(unfinished)).is_err());
+        let mut misspelled = TokenIterator::from("class Main {\n  field int x\n}".as_bytes());
+        misspelled.set_file_name("Main.jack");
+        let error = Parser::parse(misspelled).unwrap_err();
+        assert_eq!(error.to_string(), "Main.jack:3:1: Expected `;` but was `}`");
+
+        let broken = r#"
+            class Main {
+              function void f() {
+                let x = 1 +;
+                while (x) { do g(; }
+                return
+              }
+              function void g() { return; }
+            }
+            "#
+        .as_bytes();
+        let (class, errors) = Parser::parse_recovering(TokenIterator::from(broken));
+        let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "4:28: Expected a term but was `;`",
+                "5:34: Expected a term but was `;`",
+                "7:15: Expected a term but was `}`",
+            ]
+        );
+        let class = class.unwrap();
+        assert_eq!(class.subroutines.len(), 2);
+        assert!(matches!(
+            class.subroutines[0].statements[0].kind,
+            StatementKind::While { .. }
+        ));
+
+        let broken = r#"
+            class Main {
+              function void f(int a) {
+                if (a { let a = 2; }
+                let a = 3
+                while (a) { let a = a - 1; }
+                return;
+              }
+            }
+            }
+            "#
+        .as_bytes();
+        let (class, errors) = Parser::parse_recovering(TokenIterator::from(broken));
+        let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "4:23: Expected `)` but was `{`",
+                "6:17: Expected `;` but was `while`",
+                "10:13: Expected the end of the file but was `}`",
+            ]
+        );
+        let statements = &class.unwrap().subroutines[0].statements;
+        assert_eq!(statements.len(), 2);
+        assert!(matches!(statements[0].kind, StatementKind::While { .. }));
+        assert_eq!(statements[1].kind, StatementKind::Return(None));
+
+        let extended = r#"
+            class Main {
+              function void f(int n) {
+                for (n = 0; n < 10; n++) { let n += 2; continue; }
+                break;
+                return;
+              }
+            }
+            "#
+        .as_bytes();
+        assert!(Parser::parse(TokenIterator::from(extended)).is_err());
+        let mut token_iterator = TokenIterator::from(extended);
+        token_iterator.set_language_extensions(true);
+        let (class, errors) = Parser::parse_recovering(token_iterator);
+        let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, ["5:17: `break` outside of a loop"]);
+        let statements = &class.unwrap().subroutines[0].statements;
+        let (step, body) = match &statements[0].kind {
+            StatementKind::For {
+                step: Some(step),
+                statements,
+                ..
+            } => (step, statements),
+            statement => panic!("Unexpected statement {:?}", statement),
+        };
+        assert_eq!(&extended[step.span.bytes.clone()], b"n++");
+        match &body[0].kind {
+            StatementKind::CompoundAssignment {
+                operator: BinaryOperator::Add,
+                value: Some(value),
+                ..
+            } => assert_eq!(value.term.kind, TermKind::IntegerConstant(2)),
+            statement => panic!("Unexpected statement {:?}", statement),
+        }
+        assert_eq!(body[1].kind, StatementKind::Continue);
+        Ok(())
+    }
+}
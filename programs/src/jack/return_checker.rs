@@ -0,0 +1,165 @@
+use crate::jack::ast::{
+    Class, Expression, KeywordConstant, Statement, StatementKind, Subroutine, SubroutineKind,
+    TermKind,
+};
+use crate::jack::Errors;
+use anyhow::{anyhow, Error, Result};
+
+/// Checks that every subroutine returns on all paths, with a value unless it is `void`, and
+/// `this` if it is a constructor.
+pub struct ReturnChecker();
+
+fn is_constant(expression: &Expression, constant: KeywordConstant) -> bool {
+    expression.operations.is_empty() && expression.term.kind == TermKind::KeywordConstant(constant)
+}
+
+/// Whether control never reaches the end of `statements`, jumping out of them instead.
+pub fn never_ends(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Return(_) | StatementKind::Break | StatementKind::Continue => true,
+        StatementKind::If {
+            then_statements,
+            else_statements: Some(else_statements),
+            ..
+        } => never_ends(then_statements) && never_ends(else_statements),
+        // `while (true)` loops forever unless it is broken out of
+        StatementKind::While {
+            condition,
+            statements,
+        }
+        | StatementKind::For {
+            condition,
+            statements,
+            ..
+        } => is_constant(condition, KeywordConstant::True) && !breaks(statements),
+        _ => false,
+    })
+}
+
+/// Whether `statements` can `break` out of the loop they are the body of.
+fn breaks(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Break => true,
+        StatementKind::If {
+            then_statements,
+            else_statements,
+            ..
+        } => breaks(then_statements) || else_statements.as_deref().is_some_and(breaks),
+        _ => false,
+    })
+}
+
+impl ReturnChecker {
+    /// Checks `class`, failing with every error in it.
+    pub fn check(class: &Class) -> Result<()> {
+        let mut errors = vec![];
+        for subroutine in &class.subroutines {
+            let name = format!("{}.{}", class.name, subroutine.name);
+            if !never_ends(&subroutine.statements) {
+                errors.push(anyhow!(
+                    "{}: `{}` can reach its end without returning",
+                    subroutine.span,
+                    name
+                ));
+            }
+            Self::check_statements(&subroutine.statements, subroutine, &name, &mut errors);
+        }
+        if !errors.is_empty() {
+            return Err(Errors(errors).into());
+        }
+        Ok(())
+    }
+
+    fn check_statements(
+        statements: &[Statement],
+        subroutine: &Subroutine,
+        name: &str,
+        errors: &mut Vec<Error>,
+    ) {
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Return(value) => {
+                    let error = match (subroutine.kind, &subroutine.return_type, value) {
+                        (SubroutineKind::Constructor, _, Some(value))
+                            if is_constant(value, KeywordConstant::This) =>
+                        {
+                            None
+                        }
+                        (SubroutineKind::Constructor, _, _) => {
+                            Some(format!("Constructor `{}` must return `this`", name))
+                        }
+                        (_, None, Some(_)) => Some(format!("Void `{}` can't return a value", name)),
+                        (_, Some(return_type), None) => Some(format!(
+                            "`{}` must return a value of type `{}`",
+                            name, return_type
+                        )),
+                        _ => None,
+                    };
+                    if let Some(error) = error {
+                        errors.push(anyhow!("{}: {}", statement.span, error));
+                    }
+                }
+                StatementKind::If {
+                    then_statements,
+                    else_statements,
+                    ..
+                } => {
+                    Self::check_statements(then_statements, subroutine, name, errors);
+                    if let Some(else_statements) = else_statements {
+                        Self::check_statements(else_statements, subroutine, name, errors);
+                    }
+                }
+                StatementKind::While { statements, .. } | StatementKind::For { statements, .. } => {
+                    Self::check_statements(statements, subroutine, name, errors);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jack::parser::Parser;
+    use crate::jack::tokenizer::TokenIterator;
+
+    #[test]
+    fn test() -> Result<()> {
+        let input = r#"
+            class Main {
+              constructor Main new() { return 0; }
+              function int f(int x) {
+                if (x) { return 1; } else { return; }
+              }
+              function void g(int x) {
+                if (x) { return x; }
+                while (true) { }
+              }
+              method int h(int x) {
+                if (x) { return x; }
+              }
+              function int k(int x) {
+                while (true) { if (x) { break; } }
+              }
+            }
+            "#
+        .as_bytes();
+        let mut token_iterator = TokenIterator::from(input);
+        token_iterator.set_language_extensions(true);
+        let class = Parser::parse(token_iterator)?;
+        let error = ReturnChecker::check(&class).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            [
+                "3:40: Constructor `Main.new` must return `this`",
+                "5:45: `Main.f` must return a value of type `int`",
+                "8:26: Void `Main.g` can't return a value",
+                "11:15: `Main.h` can reach its end without returning",
+                "14:15: `Main.k` can reach its end without returning",
+            ]
+            .join("\n")
+        );
+        Ok(())
+    }
+}
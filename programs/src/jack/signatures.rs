@@ -0,0 +1,193 @@
+use crate::jack::ast::{Class, SubroutineCall, SubroutineKind, Type};
+use crate::jack::parser::Parser;
+use crate::jack::token::Identifier;
+use crate::jack::tokenizer::TokenIterator;
+use anyhow::{anyhow, Error};
+use std::collections::HashMap;
+
+/// The subroutines of the Jack OS, as declared in the course's API.
+const OS_API: [&str; 8] = [
+    "class Math {
+        function void init() {}
+        function int abs(int x) {}
+        function int multiply(int x, int y) {}
+        function int divide(int x, int y) {}
+        function int min(int x, int y) {}
+        function int max(int x, int y) {}
+        function int sqrt(int x) {}
+    }",
+    "class String {
+        constructor String new(int maxLength) {}
+        method void dispose() {}
+        method int length() {}
+        method char charAt(int j) {}
+        method void setCharAt(int j, char c) {}
+        method String appendChar(char c) {}
+        method void eraseLastChar() {}
+        method int intValue() {}
+        method void setInt(int val) {}
+        function char backSpace() {}
+        function char doubleQuote() {}
+        function char newLine() {}
+    }",
+    "class Array {
+        function Array new(int size) {}
+        method void dispose() {}
+    }",
+    "class Output {
+        function void init() {}
+        function void moveCursor(int i, int j) {}
+        function void printChar(char c) {}
+        function void printString(String s) {}
+        function void printInt(int i) {}
+        function void println() {}
+        function void backSpace() {}
+    }",
+    "class Screen {
+        function void init() {}
+        function void clearScreen() {}
+        function void setColor(boolean b) {}
+        function void drawPixel(int x, int y) {}
+        function void drawLine(int x1, int y1, int x2, int y2) {}
+        function void drawRectangle(int x1, int y1, int x2, int y2) {}
+        function void drawCircle(int x, int y, int r) {}
+    }",
+    "class Keyboard {
+        function void init() {}
+        function char keyPressed() {}
+        function char readChar() {}
+        function String readLine(String message) {}
+        function int readInt(String message) {}
+    }",
+    "class Memory {
+        function void init() {}
+        function int peek(int address) {}
+        function void poke(int address, int value) {}
+        function Array alloc(int size) {}
+        function void deAlloc(Array o) {}
+    }",
+    "class Sys {
+        function void init() {}
+        function void halt() {}
+        function void error(int errorCode) {}
+        function void wait(int duration) {}
+    }",
+];
+
+/// The entry point `Sys.init` calls, so that the OS can be compiled without a program.
+const ENTRY_POINT: &str = "class Main { function void main() {} }";
+
+/// What calls to a subroutine are checked against.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Signature {
+    pub kind: SubroutineKind,
+    // `None` for `void`
+    pub return_type: Option<Type>,
+    pub parameters: Vec<Type>,
+}
+
+/// The subroutines of every class of a project, by class and name.
+#[derive(Debug, Default)]
+pub struct Signatures {
+    classes: HashMap<Identifier, HashMap<Identifier, Signature>>,
+    complete: bool,
+}
+
+impl Signatures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether every class of the program is added, making calls to other classes errors.
+    pub fn set_complete(&mut self, complete: bool) {
+        self.complete = complete;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Adds the classes of the OS and the `Main.main` it calls, which classes added later replace.
+    pub fn add_os_api(&mut self) {
+        for source in OS_API.iter().chain(&[ENTRY_POINT]) {
+            let class = Parser::parse(TokenIterator::from(source.as_bytes()))
+                .expect("The OS API should be valid Jack");
+            self.add_class(&class);
+        }
+    }
+
+    /// Adds the subroutines of `class`, replacing those of any class of the same name.
+    pub fn add_class(&mut self, class: &Class) {
+        let subroutines = self.classes.entry(class.name.clone()).or_default();
+        subroutines.clear();
+        for subroutine in &class.subroutines {
+            subroutines.insert(
+                subroutine.name.clone(),
+                Signature {
+                    kind: subroutine.kind,
+                    return_type: subroutine.return_type.clone(),
+                    parameters: subroutine
+                        .parameters
+                        .iter()
+                        .map(|parameter| parameter.var_type.clone())
+                        .collect(),
+                },
+            );
+        }
+    }
+
+    pub fn has_class(&self, class: &Identifier) -> bool {
+        self.classes.contains_key(class)
+    }
+
+    pub fn get(&self, class: &Identifier, subroutine: &Identifier) -> Option<&Signature> {
+        self.classes.get(class)?.get(subroutine)
+    }
+
+    /// The errors of `call` to `class` if it isn't to a method when `as_method` or to a function or
+    /// a constructor otherwise, has the wrong number of arguments, or isn't defined.
+    pub fn check_call(
+        &self,
+        class: &Identifier,
+        call: &SubroutineCall,
+        as_method: bool,
+    ) -> Vec<Error> {
+        if !self.has_class(class) {
+            // Classes outside the project may be compiled separately
+            return match self.complete {
+                true => vec![anyhow!("{}: `{}` is not defined", call.span, class)],
+                false => vec![],
+            };
+        }
+        let name = format!("{}.{}", class, call.name);
+        let signature = match self.get(class, &call.name) {
+            Some(signature) => signature,
+            None => return vec![anyhow!("{}: `{}` is not defined", call.span, name)],
+        };
+        let mut errors = vec![];
+        let kind = match signature.kind {
+            SubroutineKind::Constructor => "constructor",
+            SubroutineKind::Function => "function",
+            SubroutineKind::Method => "method",
+        };
+        if as_method != (signature.kind == SubroutineKind::Method) {
+            errors.push(anyhow!(
+                "{}: `{}` is a {} but is called as a {}",
+                call.span,
+                name,
+                kind,
+                if as_method { "method" } else { "function" }
+            ));
+        }
+        if call.arguments.len() != signature.parameters.len() {
+            errors.push(anyhow!(
+                "{}: `{}` takes {} arguments but was given {}",
+                call.span,
+                name,
+                signature.parameters.len(),
+                call.arguments.len()
+            ));
+        }
+        errors
+    }
+}
@@ -1,15 +1,16 @@
+use crate::jack::ast::{Class, ClassVarKind, Subroutine, Type};
+use crate::jack::symbol_table::Kind::Argument;
 use crate::jack::token::Identifier;
-use anyhow::{bail, Result};
+use anyhow::{bail, Error, Result};
 use enum_map::EnumMap;
 use std::collections::HashMap;
-use crate::jack::symbol_table::Kind::Argument;
 
 #[derive(Debug, Enum, Copy, Clone, Eq, PartialEq)]
 pub enum Scope {
     Class = 0,
     Subroutine = 1,
 }
-#[derive(Debug, Enum, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Enum, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum Kind {
     Static = 0,
     Field = 1,
@@ -18,18 +19,10 @@ pub enum Kind {
 }
 #[derive(Default, Debug)]
 pub struct SymbolTable {
-    tables: EnumMap<Scope, HashMap<Identifier, (Kind, VariableType, u16)>>,
+    tables: EnumMap<Scope, HashMap<Identifier, (Kind, Type, u16)>>,
     counts: EnumMap<Kind, u16>,
 }
 
-#[derive(Debug, Clone)]
-pub enum VariableType {
-    Int,
-    Char,
-    Boolean,
-    Object(Identifier),
-}
-
 impl SymbolTable {
     pub fn new() -> Self {
         Self::default()
@@ -48,54 +41,95 @@ impl SymbolTable {
         &mut self,
         scope: &Scope,
         kind: &Kind,
-        t: &VariableType,
+        t: &Type,
         name: Identifier,
     ) -> Result<()> {
         if self.tables[*scope].contains_key(&name) {
             bail!(
-                "Variable {:?} is already define in the scope {:?}",
-                &name,
+                "Variable `{}` is already defined in the scope {:?}",
+                name,
                 scope
             );
         }
         let id = self.counts[*kind];
         self.counts[*kind] += 1;
-        self.tables[*scope].insert(name, (kind.clone(), t.clone(), id));
+        self.tables[*scope].insert(name, (*kind, t.clone(), id));
         Ok(())
     }
-    pub fn define_class_variable(
-        &mut self,
-        kind: &Kind,
-        t: &VariableType,
-        name: Identifier,
-    ) -> Result<()> {
+    pub fn define_class_variable(&mut self, kind: &Kind, t: &Type, name: Identifier) -> Result<()> {
         match kind {
             Kind::Static | Kind::Field => self.define_variable(&Scope::Class, kind, t, name),
             _ => bail!("{:?} is not a class var", kind),
         }
     }
-    pub fn define_argument_variable(&mut self, t: &VariableType, name: Identifier) -> Result<()> {
+    pub fn define_argument_variable(&mut self, t: &Type, name: Identifier) -> Result<()> {
         self.define_variable(&Scope::Subroutine, &Kind::Argument, t, name)
     }
-    pub fn define_local_variable(&mut self, t: &VariableType, name: Identifier) -> Result<()> {
+    pub fn define_local_variable(&mut self, t: &Type, name: Identifier) -> Result<()> {
         self.define_variable(&Scope::Subroutine, &Kind::Local, t, name)
     }
+    /// Starts `class` and defines its static variables and fields, with an error for each one
+    /// defined twice.
+    pub fn define_class(&mut self, class: &Class) -> Vec<Error> {
+        self.start_new_class();
+        let mut errors = vec![];
+        for var_dec in &class.var_decs {
+            let kind = match var_dec.kind {
+                ClassVarKind::Static => Kind::Static,
+                ClassVarKind::Field => Kind::Field,
+            };
+            for (name, span) in &var_dec.names {
+                if let Err(e) = self.define_class_variable(&kind, &var_dec.var_type, name.clone()) {
+                    errors.push(e.context(format!("{}: Unable to declare `{}`", span, name)));
+                }
+            }
+        }
+        errors
+    }
+    /// Starts `subroutine` and defines its arguments and locals, with an error for each one
+    /// defined twice.
+    pub fn define_subroutine(&mut self, subroutine: &Subroutine) -> Vec<Error> {
+        self.start_new_subroutine();
+        let mut errors = vec![];
+        for parameter in &subroutine.parameters {
+            if let Err(e) =
+                self.define_argument_variable(&parameter.var_type, parameter.name.clone())
+            {
+                let context = format!("{}: Unable to declare `{}`", parameter.span, parameter.name);
+                errors.push(e.context(context));
+            }
+        }
+        for var_dec in &subroutine.var_decs {
+            for (name, span) in &var_dec.names {
+                if let Err(e) = self.define_local_variable(&var_dec.var_type, name.clone()) {
+                    errors.push(e.context(format!("{}: Unable to declare `{}`", span, name)));
+                }
+            }
+        }
+        errors
+    }
     pub fn get_count(&self, kind: Kind) -> u16 {
         self.counts[kind]
     }
     pub fn shift_argument_variables_by_one(&mut self) {
         self.counts[Kind::Argument] += 1;
-        self.tables[Scope::Subroutine].iter_mut().for_each(|(_, (kind, _, id))| {
-            if *kind == Argument {
-                *id += 1;
-            }
-        });
+        self.tables[Scope::Subroutine]
+            .iter_mut()
+            .for_each(|(_, (kind, _, id))| {
+                if *kind == Argument {
+                    *id += 1;
+                }
+            });
     }
 
-    pub fn lookup(&self, name: &Identifier) -> Option<(Kind, VariableType, u16)> {
+    pub fn lookup(&self, name: &Identifier) -> Option<(Kind, Type, u16)> {
         self.tables[Scope::Subroutine]
-            .get(&name)
+            .get(name)
             .or_else(|| self.tables[Scope::Class].get(name))
             .cloned()
     }
+    /// Looks `name` up among the static variables and fields, even where a local hides it.
+    pub fn lookup_class_variable(&self, name: &Identifier) -> Option<(Kind, Type, u16)> {
+        self.tables[Scope::Class].get(name).cloned()
+    }
 }
@@ -1,8 +1,11 @@
 use crate::regex;
 use anyhow::{anyhow, ensure, Error, Result};
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+use std::rc::Rc;
 use std::str::FromStr;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Keyword {
     Class,
     Constructor,
@@ -25,8 +28,12 @@ pub enum Keyword {
     Else,
     While,
     Return,
+    // Language extensions
+    For,
+    Break,
+    Continue,
 }
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Symbol {
     OpenBrace,
     CloseBrace,
@@ -47,6 +54,11 @@ pub enum Symbol {
     GreaterThan,
     Equal,
     Tilde,
+    // Language extensions
+    PlusEqual,
+    DashEqual,
+    PlusPlus,
+    DashDash,
 }
 #[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub struct Identifier(pub String);
@@ -60,6 +72,26 @@ pub enum Token {
     Identifier(Identifier),
 }
 
+/// Where a token or a syntax node is in its source file: the line and the column it starts at,
+/// both counted from 1, and the range of bytes it covers.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Span {
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    pub column: usize,
+    pub bytes: Range<usize>,
+}
+
+impl Span {
+    /// The span from the start of `self` to the end of `end`.
+    pub fn to(&self, end: &Span) -> Span {
+        Span {
+            bytes: self.bytes.start..end.bytes.end.max(self.bytes.start),
+            ..self.clone()
+        }
+    }
+}
+
 impl From<Keyword> for Token {
     fn from(keyword: Keyword) -> Self {
         Token::Keyword(keyword)
@@ -75,9 +107,9 @@ impl From<Identifier> for Token {
         Token::Identifier(identifier)
     }
 }
-impl Into<crate::ir::Symbol> for Identifier {
-    fn into(self) -> crate::ir::Symbol {
-        self.0.parse().unwrap()
+impl From<Identifier> for crate::ir::Symbol {
+    fn from(val: Identifier) -> Self {
+        val.0.parse().unwrap()
     }
 }
 
@@ -107,6 +139,9 @@ impl FromStr for Keyword {
             "else" => Keyword::Else,
             "while" => Keyword::While,
             "return" => Keyword::Return,
+            "for" => Keyword::For,
+            "break" => Keyword::Break,
+            "continue" => Keyword::Continue,
             _ => return Err(anyhow!("Unknown keyword {}", s)),
         };
         Ok(keyword)
@@ -136,6 +171,10 @@ impl FromStr for Symbol {
             ">" => Symbol::GreaterThan,
             "=" => Symbol::Equal,
             "~" => Symbol::Tilde,
+            "+=" => Symbol::PlusEqual,
+            "-=" => Symbol::DashEqual,
+            "++" => Symbol::PlusPlus,
+            "--" => Symbol::DashDash,
             _ => {
                 return Err(anyhow!("Unknown symbol {}", s));
             }
@@ -157,9 +196,9 @@ impl FromStr for Identifier {
     }
 }
 
-impl ToString for Keyword {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for Keyword {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
             Keyword::Class => "class",
             Keyword::Constructor => "constructor",
             Keyword::Function => "function",
@@ -181,38 +220,64 @@ impl ToString for Keyword {
             Keyword::Else => "else",
             Keyword::While => "while",
             Keyword::Return => "return",
-        }
-        .to_owned()
+            Keyword::For => "for",
+            Keyword::Break => "break",
+            Keyword::Continue => "continue",
+        };
+        write!(f, "{}", s)
     }
 }
-impl ToString for Symbol {
-    fn to_string(&self) -> String {
-        let c = match self {
-            Symbol::OpenBrace => '{',
-            Symbol::CloseBrace => '}',
-            Symbol::OpenParen => '(',
-            Symbol::CloseParen => ')',
-            Symbol::OpenBracket => '[',
-            Symbol::CloseBracket => ']',
-            Symbol::Dot => '.',
-            Symbol::Comma => ',',
-            Symbol::Semicolon => ';',
-            Symbol::Plus => '+',
-            Symbol::Dash => '-',
-            Symbol::Star => '*',
-            Symbol::Slash => '/',
-            Symbol::Ampersand => '&',
-            Symbol::VerticalBar => '|',
-            Symbol::LessThan => '<',
-            Symbol::GreaterThan => '>',
-            Symbol::Equal => '=',
-            Symbol::Tilde => '~',
+impl Display for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Symbol::OpenBrace => "{",
+            Symbol::CloseBrace => "}",
+            Symbol::OpenParen => "(",
+            Symbol::CloseParen => ")",
+            Symbol::OpenBracket => "[",
+            Symbol::CloseBracket => "]",
+            Symbol::Dot => ".",
+            Symbol::Comma => ",",
+            Symbol::Semicolon => ";",
+            Symbol::Plus => "+",
+            Symbol::Dash => "-",
+            Symbol::Star => "*",
+            Symbol::Slash => "/",
+            Symbol::Ampersand => "&",
+            Symbol::VerticalBar => "|",
+            Symbol::LessThan => "<",
+            Symbol::GreaterThan => ">",
+            Symbol::Equal => "=",
+            Symbol::Tilde => "~",
+            Symbol::PlusEqual => "+=",
+            Symbol::DashEqual => "-=",
+            Symbol::PlusPlus => "++",
+            Symbol::DashDash => "--",
         };
-        c.to_string()
+        write!(f, "{}", s)
+    }
+}
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Keyword(keyword) => write!(f, "{}", keyword),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+            Token::IntegerConstant(n) => write!(f, "{}", n),
+            Token::StringConstant(s) => write!(f, "\"{}\"", s),
+            Token::Identifier(identifier) => write!(f, "{}", identifier),
+        }
+    }
+}
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
     }
 }
-impl ToString for Identifier {
-    fn to_string(&self) -> String {
-        self.0.clone()
+impl Display for Identifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
@@ -1,17 +1,28 @@
 use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::io::BufRead;
+use std::rc::Rc;
 
 use anyhow::{bail, ensure, Context, Result};
 
 use crate::jack::token::Token::{self, IntegerConstant, StringConstant};
-use crate::jack::token::{Identifier, Keyword, Symbol};
+use crate::jack::token::{Identifier, Keyword, Span, Symbol};
+
+// The keys of the Hack keyboard which escape sequences stand for
+const NEW_LINE: char = '\u{80}';
+const BACKSPACE: char = '\u{81}';
 
 pub struct TokenIterator<R: BufRead> {
     reader: R,
     current_line: VecDeque<char>,
     buffer: String,
+    file: Option<Rc<str>>,
     line_no: usize,
+    // Characters of the current line and bytes of the input consumed so far
+    column: usize,
+    offset: usize,
     finished: bool,
+    language_extensions: bool,
 }
 
 impl<R: BufRead> TokenIterator<R> {
@@ -20,19 +31,39 @@ impl<R: BufRead> TokenIterator<R> {
             reader,
             current_line: VecDeque::new(),
             buffer: String::new(),
+            file: None,
             line_no: 0,
+            column: 0,
+            offset: 0,
             finished: false,
+            language_extensions: false,
         }
     }
 
+    /// Names the input in the spans of the tokens.
+    pub fn set_file_name(&mut self, file_name: &str) {
+        self.file = Some(file_name.into());
+    }
+
+    /// Reads `for`, `break` and `continue` as keywords, `+=`, `-=`, `++` and `--` as symbols,
+    /// character literals like `'A'` as integer constants and `\"`, `\'`, `\\`, `\n` and `\b` in
+    /// strings and character literals as escape sequences, which aren't part of standard Jack.
+    pub fn set_language_extensions(&mut self, language_extensions: bool) {
+        self.language_extensions = language_extensions;
+    }
+
     fn fill_if_needed(&mut self) -> Result<()> {
         if self.current_line.is_empty() {
-            self.reader
+            let n = self
+                .reader
                 .read_line(&mut self.buffer)
-                .with_context(|| format!("Unable to read line {}", self.line_no))?;
+                .with_context(|| format!("Unable to read line {}", self.line_no + 1))?;
             self.current_line.extend(self.buffer.chars());
             self.buffer.clear();
-            self.line_no += 1;
+            if n > 0 {
+                self.line_no += 1;
+                self.column = 0;
+            }
         }
         Ok(())
     }
@@ -44,7 +75,22 @@ impl<R: BufRead> TokenIterator<R> {
 
     fn next_char(&mut self) -> Result<Option<char>> {
         self.fill_if_needed()?;
-        Ok(self.current_line.pop_front())
+        let c = self.current_line.pop_front();
+        if let Some(c) = c {
+            self.column += 1;
+            self.offset += c.len_utf8();
+        }
+        Ok(c)
+    }
+
+    /// The span of the next character, to be extended to the end of a token.
+    fn start_span(&self) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: self.line_no,
+            column: self.column + 1,
+            bytes: self.offset..self.offset,
+        }
     }
 
     fn read_while<F>(&mut self, f: F) -> Result<String>
@@ -59,7 +105,7 @@ impl<R: BufRead> TokenIterator<R> {
                 break;
             }
         }
-        return Ok(res);
+        Ok(res)
     }
 
     fn discard_while<F>(&mut self, f: F) -> Result<()>
@@ -76,13 +122,27 @@ impl<R: BufRead> TokenIterator<R> {
         Ok(())
     }
 
+    /// The character an escape sequence stands for, after its `\\`.
+    fn read_escape(&mut self) -> Result<char> {
+        let c = match self.next_char()? {
+            Some('"') => '"',
+            Some('\'') => '\'',
+            Some('\\') => '\\',
+            Some('n') => NEW_LINE,
+            Some('b') => BACKSPACE,
+            Some(c) => bail!("Unknown escape sequence `\\{}`", c.escape_default()),
+            None => bail!("Escape sequence must be completed"),
+        };
+        Ok(c)
+    }
+
     fn try_read_token(&mut self) -> Result<Option<Token>> {
         if let Some(&c) = self.peek_char()? {
             if c == '/' {
                 ensure!(Some('/') == self.next_char()?);
                 let is_comment = self
                     .peek_char()?
-                    .map_or(false, |&next| next == '/' || next == '*');
+                    .is_some_and(|&next| next == '/' || next == '*');
                 if is_comment {
                     if let Some('/') = self.peek_char()? {
                         self.read_while(|&c| c != '\n')?;
@@ -94,7 +154,7 @@ impl<R: BufRead> TokenIterator<R> {
                             self.discard_while(|&c| c != '*')?;
                             if Some(&'*') == self.peek_char()? {
                                 ensure!(Some('*') == self.next_char()?);
-                                if self.peek_char()?.map_or(false, |&c| c == '/') {
+                                if self.peek_char()?.is_some_and(|&c| c == '/') {
                                     ensure!(Some('/') == self.next_char()?);
                                     return Ok(None);
                                 }
@@ -112,9 +172,33 @@ impl<R: BufRead> TokenIterator<R> {
             } else if c == '"' {
                 // should be stringConstant
                 ensure!(Some('"') == self.next_char()?);
-                let str = self.read_while(|&c| c != '"')?;
-                ensure!(Some('"') == self.next_char()?);
-                return Ok(Some(StringConstant(str).into()));
+                let mut str = String::new();
+                loop {
+                    match self.next_char()? {
+                        Some('"') => break,
+                        Some('\\') if self.language_extensions => str.push(self.read_escape()?),
+                        Some('\n') => bail!("String must be closed on the line it starts"),
+                        Some(c) => str.push(c),
+                        None => bail!("String must be closed"),
+                    }
+                }
+                return Ok(Some(StringConstant(str)));
+            } else if c == '\'' && self.language_extensions {
+                // character literal, read as integerConstant
+                ensure!(Some('\'') == self.next_char()?);
+                let c = match self.next_char()? {
+                    Some('\\') => self.read_escape()?,
+                    Some(c) if c != '\'' && c != '\n' => c,
+                    _ => bail!("Character literal must contain a character"),
+                };
+                ensure!(
+                    Some('\'') == self.next_char()?,
+                    "Character literal must be closed after one character"
+                );
+                let n = i16::try_from(c as u32)
+                    .ok()
+                    .with_context(|| format!("Character `{}` is out of range", c))?;
+                return Ok(Some(IntegerConstant(n)));
             } else if c.is_ascii_digit() {
                 // should be integerConstant
                 let n_str = self.read_while(|c| c.is_ascii_digit())?;
@@ -124,13 +208,24 @@ impl<R: BufRead> TokenIterator<R> {
                 return Ok(IntegerConstant(n).into());
             } else if !c.is_alphabetic() && c != '_' {
                 // should be symbol
-                let symbol: Symbol = self.next_char()?.unwrap().to_string().parse()?;
+                let mut symbol = self.next_char()?.unwrap().to_string();
+                if self.language_extensions && (c == '+' || c == '-') {
+                    if let Some(&next) = self.peek_char()? {
+                        if next == '=' || next == c {
+                            symbol.push(self.next_char()?.unwrap());
+                        }
+                    }
+                }
+                let symbol: Symbol = symbol.parse()?;
                 return Ok(Some(symbol.into()));
             } else {
                 // identifier or keyword
                 let word = self.read_while(|&c| c.is_alphanumeric() || c == '_')?;
-                if let Ok(keyword) = word.parse::<Keyword>() {
-                    return Ok(Some(keyword.into()));
+                match word.parse::<Keyword>() {
+                    Ok(Keyword::For | Keyword::Break | Keyword::Continue)
+                        if !self.language_extensions => {}
+                    Ok(keyword) => return Ok(Some(keyword.into())),
+                    Err(_) => {}
                 }
                 return word
                     .parse::<Identifier>()
@@ -146,18 +241,25 @@ impl<R: BufRead> TokenIterator<R> {
         Ok(None)
     }
 
-    fn next_token(&mut self) -> Result<Option<Token>> {
+    fn next_token(&mut self) -> Result<Option<(Token, Span)>> {
         if self.finished {
             return Ok(None);
         }
         self.finished = true;
         while self.peek_char()?.is_some() {
-            let token = self
-                .try_read_token()
-                .with_context(|| format!("Failed to tokenize at line {}", self.line_no));
-            if let ret @ Ok(Some(_)) = token {
+            let mut span = self.start_span();
+            let token = match self.try_read_token() {
+                Ok(token) => token,
+                Err(e) => {
+                    // Carry on after the offending characters to find further errors
+                    self.finished = false;
+                    return Err(e.context(format!("{}: Failed to tokenize", span)));
+                }
+            };
+            if let Some(token) = token {
                 self.finished = false;
-                return ret;
+                span.bytes.end = self.offset;
+                return Ok(Some((token, span)));
             }
         }
         Ok(None)
@@ -165,7 +267,7 @@ impl<R: BufRead> TokenIterator<R> {
 }
 
 impl<R: BufRead> Iterator for TokenIterator<R> {
-    type Item = Result<Token>;
+    type Item = Result<(Token, Span)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.next_token() {
@@ -196,8 +298,43 @@ mod test {
             }
             "#
         .as_bytes();
-        let token_iterator = TokenIterator::from(input);
-        let _tokens: Vec<_> = token_iterator.collect();
+        let mut token_iterator = TokenIterator::from(input);
+        token_iterator.set_file_name("Main.jack");
+        let tokens = token_iterator.collect::<Result<Vec<_>>>()?;
+        let (token, span) = &tokens[13];
+        assert_eq!(token, &Token::StringConstant("Hello, world!".to_owned()));
+        assert_eq!(span.to_string(), "Main.jack:7:33");
+        assert_eq!(&input[span.bytes.clone()], b"\"Hello, world!\"");
+
+        let input = r#"'A' '\'' "say \"hi\"\n" "a\b"
+            "unterminated
+            "#
+        .as_bytes();
+        let mut standard = TokenIterator::from(r#""a\b""#.as_bytes());
+        let (token, _) = standard.next().unwrap()?;
+        assert_eq!(token, Token::StringConstant("a\\b".to_owned()));
+        let mut token_iterator = TokenIterator::from(input);
+        token_iterator.set_language_extensions(true);
+        let tokens: Vec<_> = token_iterator
+            .map(|token| {
+                token
+                    .map(|(token, _)| token)
+                    .map_err(|e| format!("{:#}", e))
+            })
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::IntegerConstant(65)),
+                Ok(Token::IntegerConstant(39)),
+                Ok(Token::StringConstant("say \"hi\"\u{80}".to_owned())),
+                Ok(Token::StringConstant("a\u{81}".to_owned())),
+                Err(
+                    "2:13: Failed to tokenize: String must be closed on the line it starts"
+                        .to_owned()
+                ),
+            ]
+        );
         Ok(())
     }
 }
@@ -0,0 +1,565 @@
+use crate::jack::ast::{
+    BinaryOperator, Class, Expression, KeywordConstant, Postfix, Semantics, Statement,
+    StatementKind, Subroutine, SubroutineCall, Term, TermKind, Type, UnaryOperator,
+};
+use crate::jack::signatures::Signatures;
+use crate::jack::symbol_table::SymbolTable;
+use crate::jack::token::{Identifier, Span};
+use crate::jack::Errors;
+use anyhow::{anyhow, Error, Result};
+use std::fmt::{self, Display, Formatter};
+
+/// The type of a value as far as it can be told.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Value {
+    Typed(Type),
+    Null,
+    // Array elements and results of subroutines outside the project, which can be anything
+    Unknown,
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Typed(t) => write!(f, "{}", t),
+            Value::Null => write!(f, "null"),
+            Value::Unknown => write!(f, "?"),
+        }
+    }
+}
+
+fn is_primitive(t: &Type) -> bool {
+    matches!(t, Type::Int | Type::Char | Type::Boolean)
+}
+fn is_array(t: &Type) -> bool {
+    matches!(t, Type::Class(name) if name.0 == "Array")
+}
+
+/// Checks the types of assignments, operands, conditions, arguments and return values.
+///
+/// Like Jack itself, the permissive mode converts freely between `int`, `char` and `boolean`, and
+/// between `Array` and anything else, indexes any object like an `Array`, and doesn't check
+/// values of classes outside the project. The strict mode takes types as written, only allowing
+/// `null` for objects and `char`s where `int`s are expected.
+pub struct TypeChecker<'a> {
+    signatures: &'a Signatures,
+    strict: bool,
+    semantics: Semantics,
+    symbol_table: SymbolTable,
+    class_name: Option<Identifier>,
+    return_type: Option<Type>,
+    errors: Vec<Error>,
+}
+
+impl<'a> TypeChecker<'a> {
+    /// Checks calls against the subroutines in `signatures`, leaving calls to others unchecked.
+    pub fn new(signatures: &'a Signatures) -> Self {
+        Self {
+            signatures,
+            strict: false,
+            semantics: Semantics::default(),
+            symbol_table: SymbolTable::new(),
+            class_name: None,
+            return_type: None,
+            errors: vec![],
+        }
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Groups the operators of expressions according to `semantics`.
+    pub fn set_semantics(&mut self, semantics: Semantics) {
+        self.semantics = semantics;
+    }
+
+    /// Checks `class`, failing with every type error in it.
+    pub fn check(&mut self, class: &Class) -> Result<()> {
+        // Redefinitions are left to `IRAnalyzer` to report
+        let _ = self.symbol_table.define_class(class);
+        self.class_name = Some(class.name.clone());
+        for subroutine in &class.subroutines {
+            self.check_subroutine(subroutine);
+        }
+        self.class_name = None;
+        if !self.errors.is_empty() {
+            return Err(Errors(std::mem::take(&mut self.errors)).into());
+        }
+        Ok(())
+    }
+
+    fn check_subroutine(&mut self, subroutine: &Subroutine) {
+        let _ = self.symbol_table.define_subroutine(subroutine);
+        self.return_type = subroutine.return_type.clone();
+        self.check_statements(&subroutine.statements);
+    }
+
+    fn check_statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Let { name, index, value } => {
+                    let variable = self.type_of_variable(name);
+                    if let Some(index) = index {
+                        self.check_indexed(name, &variable, index, &statement.span);
+                        // Elements of arrays can be anything
+                        self.type_of_expression(value);
+                    } else {
+                        let value_type = self.type_of_expression(value);
+                        if let Value::Typed(variable) = variable {
+                            let what = format!("`{}`", name);
+                            self.expect(&value_type, &variable, &value.span, &what);
+                        }
+                    }
+                }
+                StatementKind::CompoundAssignment {
+                    name,
+                    index,
+                    operator,
+                    value,
+                } => {
+                    let variable = self.type_of_variable(name);
+                    let target = match index {
+                        Some(index) => {
+                            self.check_indexed(name, &variable, index, &statement.span);
+                            Value::Unknown
+                        }
+                        None => variable.clone(),
+                    };
+                    let (value_type, value_span) = match value {
+                        Some(value) => (self.type_of_expression(value), value.span.clone()),
+                        None => (Value::Typed(Type::Int), statement.span.clone()),
+                    };
+                    let result = self.type_of_operation(
+                        *operator,
+                        (&target, &statement.span),
+                        (&value_type, &value_span),
+                    );
+                    if let (None, Value::Typed(variable)) = (index, variable) {
+                        let what = format!("`{}`", name);
+                        self.expect(&result, &variable, &statement.span, &what);
+                    }
+                }
+                StatementKind::If {
+                    condition,
+                    then_statements,
+                    else_statements,
+                } => {
+                    self.check_condition(condition);
+                    self.check_statements(then_statements);
+                    if let Some(else_statements) = else_statements {
+                        self.check_statements(else_statements);
+                    }
+                }
+                StatementKind::While {
+                    condition,
+                    statements,
+                } => {
+                    self.check_condition(condition);
+                    self.check_statements(statements);
+                }
+                StatementKind::Do(call) => {
+                    self.type_of_call(call);
+                }
+                StatementKind::Return(value) => {
+                    if let Some(value) = value {
+                        let value_type = self.type_of_expression(value);
+                        // Returning a value from a void subroutine is a control flow error
+                        if let Some(return_type) = self.return_type.clone() {
+                            self.expect(&value_type, &return_type, &value.span, "the return value");
+                        }
+                    }
+                }
+                StatementKind::For {
+                    init,
+                    condition,
+                    step,
+                    statements,
+                } => {
+                    if let Some(init) = init {
+                        self.check_statements(std::slice::from_ref(init));
+                    }
+                    self.check_condition(condition);
+                    self.check_statements(statements);
+                    if let Some(step) = step {
+                        self.check_statements(std::slice::from_ref(step));
+                    }
+                }
+                StatementKind::Break | StatementKind::Continue => {}
+            }
+        }
+    }
+
+    fn assignable(&self, value: &Value, to: &Type) -> bool {
+        match value {
+            Value::Unknown => true,
+            Value::Null => !self.strict || matches!(to, Type::Class(_)),
+            Value::Typed(from) if from == to => true,
+            Value::Typed(Type::Char) if to == &Type::Int => true,
+            Value::Typed(from) => {
+                !self.strict
+                    && (is_array(from) || is_array(to) || (is_primitive(from) && is_primitive(to)))
+            }
+        }
+    }
+    fn expect(&mut self, value: &Value, expected: &Type, span: &Span, what: &str) {
+        if !self.assignable(value, expected) {
+            self.errors.push(anyhow!(
+                "{}: Expected `{}` for {} but was `{}`",
+                span,
+                expected,
+                what,
+                value
+            ));
+        }
+    }
+    /// Whether `value` can be an operand of arithmetic and comparisons.
+    fn is_numeric(&self, value: &Value) -> bool {
+        match value {
+            Value::Unknown | Value::Typed(Type::Int) | Value::Typed(Type::Char) => true,
+            Value::Typed(Type::Boolean) | Value::Null => !self.strict,
+            Value::Typed(t) => !self.strict && is_array(t),
+        }
+    }
+    /// Whether `value` can be an operand of `&`, `|` and `~`.
+    fn is_bitwise(&self, value: &Value) -> bool {
+        match value {
+            Value::Unknown | Value::Typed(Type::Int) | Value::Typed(Type::Boolean) => true,
+            Value::Typed(Type::Char) | Value::Null => !self.strict,
+            Value::Typed(t) => !self.strict && is_array(t),
+        }
+    }
+    fn check_operand(&mut self, operator: &str, value: &Value, span: &Span, bitwise: bool) {
+        let valid = if bitwise {
+            self.is_bitwise(value)
+        } else {
+            self.is_numeric(value)
+        };
+        if !valid {
+            self.errors.push(anyhow!(
+                "{}: `{}` can't be an operand of `{}`",
+                span,
+                value,
+                operator
+            ));
+        }
+    }
+    fn check_condition(&mut self, condition: &Expression) {
+        let value = self.type_of_expression(condition);
+        let valid = match &value {
+            Value::Unknown | Value::Typed(Type::Boolean) => true,
+            Value::Typed(t) if is_primitive(t) || is_array(t) => !self.strict,
+            _ => false,
+        };
+        if !valid {
+            self.errors.push(anyhow!(
+                "{}: Expected `boolean` for the condition but was `{}`",
+                condition.span,
+                value
+            ));
+        }
+    }
+    /// Checks `name[index]`, whose type is `variable`.
+    fn check_indexed(
+        &mut self,
+        name: &Identifier,
+        variable: &Value,
+        index: &Expression,
+        span: &Span,
+    ) {
+        let valid = match variable {
+            Value::Typed(t) if is_array(t) => true,
+            Value::Typed(Type::Char) | Value::Typed(Type::Boolean) => false,
+            Value::Typed(_) => !self.strict,
+            _ => true,
+        };
+        if !valid {
+            self.errors.push(anyhow!(
+                "{}: `{}` of type `{}` can't be indexed",
+                span,
+                name,
+                variable
+            ));
+        }
+        let index_type = self.type_of_expression(index);
+        self.expect(&index_type, &Type::Int, &index.span, "the index");
+    }
+
+    fn value_of(&self, t: Type) -> Value {
+        match &t {
+            Type::Class(class) if !self.strict && !self.signatures.has_class(class) => {
+                Value::Unknown
+            }
+            _ => Value::Typed(t),
+        }
+    }
+    fn type_of_variable(&self, name: &Identifier) -> Value {
+        // Unknown variables are left to `IRAnalyzer` to report
+        match self.symbol_table.lookup(name) {
+            Some((_kind, t, _id)) => self.value_of(t),
+            None => Value::Unknown,
+        }
+    }
+    fn type_of_expression(&mut self, expression: &Expression) -> Value {
+        let mut stack: Vec<(Value, Span)> = vec![];
+        for postfix in expression.postfix(self.semantics) {
+            match postfix {
+                Postfix::Term(term) => {
+                    let value = self.type_of_term(term);
+                    stack.push((value, term.span.clone()));
+                }
+                Postfix::Operator(operator) => {
+                    let (right, right_span) = stack.pop().unwrap();
+                    let (left, left_span) = stack.pop().unwrap();
+                    let value = self.type_of_operation(
+                        operator,
+                        (&left, &left_span),
+                        (&right, &right_span),
+                    );
+                    stack.push((value, left_span.to(&right_span)));
+                }
+            }
+        }
+        stack.pop().unwrap().0
+    }
+    fn type_of_operation(
+        &mut self,
+        operator: BinaryOperator,
+        (left, left_span): (&Value, &Span),
+        (right, right_span): (&Value, &Span),
+    ) -> Value {
+        let symbol = match operator {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Sub => "-",
+            BinaryOperator::Mul => "*",
+            BinaryOperator::Div => "/",
+            BinaryOperator::And => "&",
+            BinaryOperator::Or => "|",
+            BinaryOperator::Lt => "<",
+            BinaryOperator::Gt => ">",
+            BinaryOperator::Eq => "=",
+        };
+        match operator {
+            BinaryOperator::Add
+            | BinaryOperator::Sub
+            | BinaryOperator::Mul
+            | BinaryOperator::Div => {
+                self.check_operand(symbol, left, left_span, false);
+                self.check_operand(symbol, right, right_span, false);
+                Value::Typed(Type::Int)
+            }
+            BinaryOperator::Lt | BinaryOperator::Gt => {
+                self.check_operand(symbol, left, left_span, false);
+                self.check_operand(symbol, right, right_span, false);
+                Value::Typed(Type::Boolean)
+            }
+            BinaryOperator::And | BinaryOperator::Or => {
+                self.check_operand(symbol, left, left_span, true);
+                self.check_operand(symbol, right, right_span, true);
+                match (left, right) {
+                    (Value::Typed(Type::Boolean), Value::Typed(Type::Boolean))
+                    | (Value::Typed(Type::Boolean), Value::Unknown)
+                    | (Value::Unknown, Value::Typed(Type::Boolean)) => Value::Typed(Type::Boolean),
+                    (Value::Typed(Type::Boolean), Value::Typed(Type::Int))
+                    | (Value::Typed(Type::Int), Value::Typed(Type::Boolean))
+                        if self.strict =>
+                    {
+                        self.errors.push(anyhow!(
+                            "{}: `{}` and `{}` can't be operands of the same `{}`",
+                            left_span.to(right_span),
+                            left,
+                            right,
+                            symbol
+                        ));
+                        Value::Unknown
+                    }
+                    _ => Value::Typed(Type::Int),
+                }
+            }
+            BinaryOperator::Eq => {
+                let comparable = match (left, right) {
+                    (Value::Typed(l), _) if self.assignable(right, l) => true,
+                    (_, Value::Typed(r)) => self.assignable(left, r),
+                    _ => true,
+                };
+                if !comparable {
+                    self.errors.push(anyhow!(
+                        "{}: `{}` and `{}` can't be compared",
+                        left_span.to(right_span),
+                        left,
+                        right
+                    ));
+                }
+                Value::Typed(Type::Boolean)
+            }
+        }
+    }
+    fn type_of_term(&mut self, term: &Term) -> Value {
+        match &term.kind {
+            TermKind::IntegerConstant(_) => Value::Typed(Type::Int),
+            TermKind::StringConstant(_) => {
+                self.value_of(Type::Class(Identifier("String".to_owned())))
+            }
+            TermKind::KeywordConstant(constant) => match constant {
+                KeywordConstant::True | KeywordConstant::False => Value::Typed(Type::Boolean),
+                KeywordConstant::Null => Value::Null,
+                KeywordConstant::This => match &self.class_name {
+                    Some(class_name) => self.value_of(Type::Class(class_name.clone())),
+                    None => Value::Unknown,
+                },
+            },
+            TermKind::Variable(name) => self.type_of_variable(name),
+            TermKind::ArrayElement(name, index) => {
+                let variable = self.type_of_variable(name);
+                self.check_indexed(name, &variable, index, &term.span);
+                Value::Unknown
+            }
+            TermKind::Call(call) => self.type_of_call(call),
+            TermKind::Parenthesized(expression) => self.type_of_expression(expression),
+            TermKind::Unary(operator, operand) => {
+                let value = self.type_of_term(operand);
+                match operator {
+                    UnaryOperator::Neg => {
+                        self.check_operand("-", &value, &operand.span, false);
+                        Value::Typed(Type::Int)
+                    }
+                    UnaryOperator::Not => {
+                        self.check_operand("~", &value, &operand.span, true);
+                        match value {
+                            Value::Typed(Type::Boolean) | Value::Unknown => value,
+                            _ => Value::Typed(Type::Int),
+                        }
+                    }
+                }
+            }
+        }
+    }
+    fn type_of_call(&mut self, call: &SubroutineCall) -> Value {
+        let arguments: Vec<_> = call
+            .arguments
+            .iter()
+            .map(|argument| self.type_of_expression(argument))
+            .collect();
+        let (class, as_method) = match &call.receiver {
+            None => match &self.class_name {
+                Some(class_name) => (class_name.clone(), true),
+                None => return Value::Unknown,
+            },
+            Some(receiver) => match self.symbol_table.lookup(receiver) {
+                Some((_kind, Type::Class(class), _id)) => (class, true),
+                // Methods of primitives are left to `IRAnalyzer` to report
+                Some(_) => return Value::Unknown,
+                None => (receiver.clone(), false),
+            },
+        };
+        let errors = self.signatures.check_call(&class, call, as_method);
+        self.errors.extend(errors);
+        let signature = match self.signatures.get(&class, &call.name) {
+            Some(signature) => signature,
+            None => return Value::Unknown,
+        };
+        let name = format!("{}.{}", class, call.name);
+        if arguments.len() == signature.parameters.len() {
+            for (i, ((argument, value), parameter)) in call
+                .arguments
+                .iter()
+                .zip(&arguments)
+                .zip(&signature.parameters)
+                .enumerate()
+            {
+                self.expect(
+                    value,
+                    parameter,
+                    &argument.span,
+                    &format!("argument {} of `{}`", i + 1, name),
+                );
+            }
+        }
+        match &signature.return_type {
+            Some(return_type) => self.value_of(return_type.clone()),
+            None => Value::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jack::parser::Parser;
+    use crate::jack::tokenizer::TokenIterator;
+
+    fn check(input: &str, strict: bool, semantics: Semantics) -> Result<Vec<String>> {
+        let class = Parser::parse(TokenIterator::from(input.as_bytes()))?;
+        let mut signatures = Signatures::new();
+        signatures.add_class(&class);
+        let mut checker = TypeChecker::new(&signatures);
+        checker.set_strict(strict);
+        checker.set_semantics(semantics);
+        Ok(match checker.check(&class) {
+            Ok(()) => vec![],
+            Err(e) => e
+                .downcast::<Errors>()?
+                .0
+                .iter()
+                .map(|e| e.to_string())
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let input = r#"
+            class Main {
+              field Array a;
+              function int f(int n, Main m) {
+                var boolean b;
+                var char c;
+                let b = n;
+                let c = n + 1;
+                if (n) { let a[b] = m; }
+                let m = Main.f(c, null);
+                while (~b & (n < 3)) { let m = 1; }
+                return Main.f(1) + "s";
+              }
+            }
+            "#;
+        assert_eq!(
+            check(input, false, Semantics::Precedence)?,
+            [
+                "10:25: Expected `Main` for `m` but was `int`",
+                "11:48: Expected `Main` for `m` but was `int`",
+                "12:24: `Main.f` takes 2 arguments but was given 1",
+            ]
+        );
+        assert_eq!(
+            check(input, true, Semantics::Precedence)?,
+            [
+                "7:25: Expected `boolean` for `b` but was `int`",
+                "8:25: Expected `char` for `c` but was `int`",
+                "9:21: Expected `boolean` for the condition but was `int`",
+                "9:32: Expected `int` for the index but was `boolean`",
+                "10:25: Expected `Main` for `m` but was `int`",
+                "11:48: Expected `Main` for `m` but was `int`",
+                "12:24: `Main.f` takes 2 arguments but was given 1",
+                "12:36: `String` can't be an operand of `+`",
+            ]
+        );
+
+        let input = r#"
+            class Main {
+              function boolean f(int a, int b) {
+                return a = b + 1;
+              }
+            }
+            "#;
+        assert!(check(input, true, Semantics::Precedence)?.is_empty());
+        assert_eq!(
+            check(input, true, Semantics::LeftToRight)?,
+            [
+                "4:24: `boolean` can't be an operand of `+`",
+                "4:24: Expected `boolean` for the return value but was `int`",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,405 @@
+use crate::jack::ast::{
+    Class, Expression, Semantics, Statement, StatementKind, SubroutineCall, Term, TermKind,
+};
+use crate::jack::return_checker::never_ends;
+use crate::jack::symbol_table::{Kind, SymbolTable};
+use crate::jack::token::{Identifier, Span};
+use anyhow::anyhow;
+use enumset::EnumSet;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// A kind of suspicious code which compiles but is likely a mistake.
+#[derive(EnumSetType, Debug)]
+pub enum Warning {
+    /// Locals never used, and fields and statics never read
+    UnusedVariable,
+    UnusedParameter,
+    /// Locals and parameters named like a field or a static
+    Shadowing,
+    /// Statements after a `return` or an endless loop
+    UnreachableCode,
+    /// Locals assigned but never read
+    WriteOnlyVariable,
+    /// Expressions without parentheses whose value depends on the `Semantics`
+    OperatorPrecedence,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Warning::UnusedVariable => "unused-variable",
+            Warning::UnusedParameter => "unused-parameter",
+            Warning::Shadowing => "shadowing",
+            Warning::UnreachableCode => "unreachable-code",
+            Warning::WriteOnlyVariable => "write-only-variable",
+            Warning::OperatorPrecedence => "operator-precedence",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Warning {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EnumSet::<Warning>::all()
+            .iter()
+            .find(|warning| warning.to_string() == s)
+            .ok_or_else(|| anyhow!("Unknown warning {}", s))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Usage {
+    read: bool,
+    written: bool,
+}
+
+/// A variable checked for use once its scope ends.
+struct Declaration {
+    kind: Kind,
+    id: u16,
+    name: Identifier,
+    span: Span,
+}
+
+/// Finds `Warning`s in classes, reporting them in the format of errors.
+#[derive(Default)]
+pub struct WarningChecker {
+    allowed: EnumSet<Warning>,
+    symbol_table: SymbolTable,
+    usages: HashMap<(Kind, u16), Usage>,
+    warnings: Vec<(Span, String)>,
+}
+
+impl WarningChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Suppresses `warnings`.
+    pub fn allow(&mut self, warnings: EnumSet<Warning>) {
+        self.allowed |= warnings;
+    }
+
+    /// The warnings for `class`, in the order of the code they are about.
+    pub fn check(&mut self, class: &Class) -> Vec<String> {
+        // Redefinitions are left to `IRAnalyzer` to report
+        let _ = self.symbol_table.define_class(class);
+        self.usages.clear();
+        let mut class_variables = vec![];
+        for var_dec in &class.var_decs {
+            for (name, span) in &var_dec.names {
+                self.declare(&mut class_variables, name, span);
+            }
+        }
+
+        for subroutine in &class.subroutines {
+            let _ = self.symbol_table.define_subroutine(subroutine);
+            // Ids of locals and arguments restart in every subroutine
+            self.usages
+                .retain(|(kind, _id), _usage| matches!(kind, Kind::Static | Kind::Field));
+            let mut variables = vec![];
+            for parameter in &subroutine.parameters {
+                self.check_shadowing(&parameter.name, &parameter.span, "Parameter");
+                self.declare(&mut variables, &parameter.name, &parameter.span);
+            }
+            for var_dec in &subroutine.var_decs {
+                for (name, span) in &var_dec.names {
+                    self.check_shadowing(name, span, "Local variable");
+                    self.declare(&mut variables, name, span);
+                }
+            }
+            self.check_statements(&subroutine.statements);
+
+            for variable in variables {
+                let usage = self.usage(&variable);
+                if variable.kind == Kind::Argument {
+                    if !usage.read && !usage.written {
+                        self.warn(
+                            Warning::UnusedParameter,
+                            &variable.span,
+                            format!("Parameter `{}` is never used", variable.name),
+                        );
+                    }
+                } else if !usage.read && !usage.written {
+                    self.warn(
+                        Warning::UnusedVariable,
+                        &variable.span,
+                        format!("Local variable `{}` is never used", variable.name),
+                    );
+                } else if !usage.read {
+                    self.warn(
+                        Warning::WriteOnlyVariable,
+                        &variable.span,
+                        format!(
+                            "Local variable `{}` is assigned but never read",
+                            variable.name
+                        ),
+                    );
+                }
+            }
+        }
+
+        for variable in class_variables {
+            if !self.usage(&variable).read {
+                let kind = match variable.kind {
+                    Kind::Static => "Static variable",
+                    _ => "Field",
+                };
+                self.warn(
+                    Warning::UnusedVariable,
+                    &variable.span,
+                    format!("{} `{}` is never read", kind, variable.name),
+                );
+            }
+        }
+
+        let mut warnings = std::mem::take(&mut self.warnings);
+        warnings.sort_by_key(|(span, _)| span.bytes.start);
+        warnings.into_iter().map(|(_, warning)| warning).collect()
+    }
+
+    fn warn(&mut self, warning: Warning, span: &Span, message: String) {
+        if !self.allowed.contains(warning) {
+            self.warnings
+                .push((span.clone(), format!("{}: {} [{}]", span, message, warning)));
+        }
+    }
+    /// Adds the variable defined as `name` to `declarations`, unless the name was defined twice.
+    fn declare(&self, declarations: &mut Vec<Declaration>, name: &Identifier, span: &Span) {
+        let (kind, _type, id) = self.symbol_table.lookup(name).unwrap();
+        if !declarations.iter().any(|d| d.kind == kind && d.id == id) {
+            declarations.push(Declaration {
+                kind,
+                id,
+                name: name.clone(),
+                span: span.clone(),
+            });
+        }
+    }
+    fn usage(&self, declaration: &Declaration) -> Usage {
+        self.usages
+            .get(&(declaration.kind, declaration.id))
+            .copied()
+            .unwrap_or_default()
+    }
+    fn check_shadowing(&mut self, name: &Identifier, span: &Span, what: &str) {
+        if let Some((kind, _type, _id)) = self.symbol_table.lookup_class_variable(name) {
+            let shadowed = match kind {
+                Kind::Static => "static variable",
+                _ => "field",
+            };
+            self.warn(
+                Warning::Shadowing,
+                span,
+                format!("{} `{}` shadows the {} `{}`", what, name, shadowed, name),
+            );
+        }
+    }
+    fn mark(&mut self, name: &Identifier, read: bool) {
+        // Unknown variables are left to `IRAnalyzer` to report
+        if let Some((kind, _type, id)) = self.symbol_table.lookup(name) {
+            let usage = self.usages.entry((kind, id)).or_default();
+            if read {
+                usage.read = true;
+            } else {
+                usage.written = true;
+            }
+        }
+    }
+
+    fn check_statements(&mut self, statements: &[Statement]) {
+        let mut ended = false;
+        for (i, statement) in statements.iter().enumerate() {
+            if ended {
+                // Jack wants a final `return` even after a loop that never ends
+                let required = i + 1 == statements.len()
+                    && matches!(statement.kind, StatementKind::Return(_))
+                    && !matches!(
+                        statements[i - 1].kind,
+                        StatementKind::Return(_) | StatementKind::Break | StatementKind::Continue
+                    );
+                if !required {
+                    self.warn(
+                        Warning::UnreachableCode,
+                        &statement.span,
+                        "Statement is unreachable".to_owned(),
+                    );
+                }
+                // Once for the rest of the block, which is still checked for uses of variables
+                ended = false;
+            }
+            self.check_statement(statement);
+            if never_ends(std::slice::from_ref(statement)) {
+                ended = true;
+            }
+        }
+    }
+    fn check_statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let { name, index, value } => {
+                if let Some(index) = index {
+                    // Storing into an element reads the array
+                    self.mark(name, true);
+                    self.check_expression(index);
+                    self.check_expression(value);
+                } else {
+                    self.check_expression(value);
+                    self.mark(name, false);
+                }
+            }
+            StatementKind::CompoundAssignment {
+                name, index, value, ..
+            } => {
+                self.mark(name, true);
+                if let Some(index) = index {
+                    self.check_expression(index);
+                }
+                if let Some(value) = value {
+                    self.check_expression(value);
+                }
+                if index.is_none() {
+                    self.mark(name, false);
+                }
+            }
+            StatementKind::If {
+                condition,
+                then_statements,
+                else_statements,
+            } => {
+                self.check_expression(condition);
+                self.check_statements(then_statements);
+                if let Some(else_statements) = else_statements {
+                    self.check_statements(else_statements);
+                }
+            }
+            StatementKind::While {
+                condition,
+                statements,
+            } => {
+                self.check_expression(condition);
+                self.check_statements(statements);
+            }
+            StatementKind::Do(call) => self.check_call(call),
+            StatementKind::Return(value) => {
+                if let Some(value) = value {
+                    self.check_expression(value);
+                }
+            }
+            StatementKind::For {
+                init,
+                condition,
+                step,
+                statements,
+            } => {
+                if let Some(init) = init {
+                    self.check_statement(init);
+                }
+                self.check_expression(condition);
+                self.check_statements(statements);
+                if let Some(step) = step {
+                    self.check_statement(step);
+                }
+            }
+            StatementKind::Break | StatementKind::Continue => {}
+        }
+    }
+    fn check_expression(&mut self, expression: &Expression) {
+        if expression.postfix(Semantics::Precedence) != expression.postfix(Semantics::LeftToRight) {
+            self.warn(
+                Warning::OperatorPrecedence,
+                &expression.span,
+                "Expression evaluates differently from left to right than by precedence".to_owned(),
+            );
+        }
+        self.check_term(&expression.term);
+        for (_operator, term) in &expression.operations {
+            self.check_term(term);
+        }
+    }
+    fn check_term(&mut self, term: &Term) {
+        match &term.kind {
+            TermKind::Variable(name) => self.mark(name, true),
+            TermKind::ArrayElement(name, index) => {
+                self.mark(name, true);
+                self.check_expression(index);
+            }
+            TermKind::Call(call) => self.check_call(call),
+            TermKind::Parenthesized(expression) => self.check_expression(expression),
+            TermKind::Unary(_operator, term) => self.check_term(term),
+            TermKind::IntegerConstant(_)
+            | TermKind::StringConstant(_)
+            | TermKind::KeywordConstant(_) => {}
+        }
+    }
+    fn check_call(&mut self, call: &SubroutineCall) {
+        if let Some(receiver) = &call.receiver {
+            self.mark(receiver, true);
+        }
+        for argument in &call.arguments {
+            self.check_expression(argument);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jack::parser::Parser;
+    use crate::jack::tokenizer::TokenIterator;
+    use anyhow::Result;
+
+    #[test]
+    fn test() -> Result<()> {
+        let input = r#"
+            class Main {
+              field int x, y;
+              method int f(int a, int b) {
+                var int x, c, d;
+                let c = 1;
+                let d = y;
+                return d - d * 2;
+                do f(1, 2);
+              }
+              function int h(int p) {
+                var int q;
+                let q = p;
+                return q;
+              }
+              function int g(int e) {
+                var int z;
+                return 0;
+              }
+              function void halt() {
+                while (true) {}
+                return;
+              }
+            }
+            "#
+        .as_bytes();
+        let class = Parser::parse(TokenIterator::from(input))?;
+        let mut checker = WarningChecker::new();
+        assert_eq!(
+            checker.check(&class),
+            [
+                "3:25: Field `x` is never read [unused-variable]",
+                "4:28: Parameter `a` is never used [unused-parameter]",
+                "4:35: Parameter `b` is never used [unused-parameter]",
+                "5:25: Local variable `x` shadows the field `x` [shadowing]",
+                "5:25: Local variable `x` is never used [unused-variable]",
+                "5:28: Local variable `c` is assigned but never read [write-only-variable]",
+                "8:24: Expression evaluates differently from left to right than by precedence \
+                 [operator-precedence]",
+                "9:17: Statement is unreachable [unreachable-code]",
+                "16:30: Parameter `e` is never used [unused-parameter]",
+                "17:25: Local variable `z` is never used [unused-variable]",
+            ]
+        );
+        checker.allow(EnumSet::only(Warning::UnusedParameter) | Warning::Shadowing);
+        assert_eq!(checker.check(&class).len(), 6);
+        Ok(())
+    }
+}
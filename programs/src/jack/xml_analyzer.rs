@@ -1,24 +1,18 @@
-use crate::jack::token::{Identifier, Keyword, Symbol, Token};
-use anyhow::{bail, ensure, Context, Result};
-use std::collections::VecDeque;
+use crate::jack::ast::{
+    BinaryOperator, Class, ClassVarDec, ClassVarKind, Expression, KeywordConstant, Statement,
+    StatementKind, Subroutine, SubroutineCall, SubroutineKind, Term, TermKind, Type, UnaryOperator,
+    VarDec,
+};
+use crate::jack::token::{Identifier, Keyword, Span, Symbol, Token};
 use std::fmt::Display;
-use std::iter::Fuse;
 
-pub struct XMLAnalyzer<I: Iterator<Item = Result<Token>>> {
-    token_stream: Fuse<I>,
-    peeked: VecDeque<Result<Token>>,
+/// Writes classes parsed by `jack::parser::Parser` as the XML of the course's syntax analyzer,
+/// with each token of the source as a terminal element.
+pub struct XMLAnalyzer {
     result_xml: String,
 }
 
-impl<I: Iterator<Item = Result<Token>>> XMLAnalyzer<I> {
-    pub fn from(token_stream: I) -> Self {
-        return Self {
-            token_stream: token_stream.fuse(),
-            peeked: VecDeque::new(),
-            result_xml: String::new(),
-        };
-    }
-
+impl XMLAnalyzer {
     fn write_open<S: Display>(&mut self, tag: S) {
         self.result_xml.push_str(&format!("<{}>\n", tag));
     }
@@ -46,386 +40,346 @@ impl<I: Iterator<Item = Result<Token>>> XMLAnalyzer<I> {
             Token::Identifier(s) => self.write_content_with_body("identifier", &s.0),
         }
     }
-
-    fn peek_more(&mut self) -> bool {
-        if let Some(token) = self.token_stream.next() {
-            self.peeked.push_back(token);
-            true
-        } else {
-            false
-        }
-    }
-    fn peek_nth(&mut self, i: usize) -> Result<Option<&Token>> {
-        while self.peeked.len() <= i {
-            if !self.peek_more() {
-                return Ok(None);
-            }
-        }
-        match self.peeked[i].as_ref() {
-            Ok(token) => Ok(Some(token)),
-            _ => bail!("Unable to peek token"),
-        }
+    fn write_keyword(&mut self, keyword: Keyword) {
+        self.write_terminal(&keyword.into());
     }
-    fn peek_token(&mut self) -> Result<Option<&Token>> {
-        self.peek_nth(0)
+    fn write_symbol(&mut self, symbol: Symbol) {
+        self.write_terminal(&symbol.into());
     }
-    fn peek_keyword(&mut self) -> Result<Option<&Keyword>> {
-        Ok(match self.peek_token()? {
-            Some(Token::Keyword(keyword)) => Some(keyword),
-            _ => None,
-        })
+    fn write_identifier(&mut self, identifier: &Identifier) {
+        self.write_terminal(&identifier.clone().into());
     }
-    fn peek_symbol(&mut self) -> Result<Option<&Symbol>> {
-        Ok(match self.peek_token()? {
-            Some(Token::Symbol(symbol)) => Some(symbol),
-            _ => None,
-        })
-    }
-
-    fn next_token(&mut self) -> Result<Token> {
-        if let Some(peeked) = self.peeked.pop_front() {
-            if let Ok(token) = &peeked {
-                self.write_terminal(token);
-            }
-            return peeked;
-        }
-        match self.token_stream.next() {
-            Some(Ok(token)) => {
-                self.write_terminal(&token);
-                Ok(token)
-            }
-            Some(Err(e)) => Err(e),
-            None => bail!("Expected a token but reached to the end of the token stream"),
+    fn write_type(&mut self, var_type: &Type) {
+        match var_type {
+            Type::Int => self.write_keyword(Keyword::Int),
+            Type::Char => self.write_keyword(Keyword::Char),
+            Type::Boolean => self.write_keyword(Keyword::Boolean),
+            Type::Class(name) => self.write_identifier(name),
         }
     }
-    fn next_keyword(&mut self) -> Result<Keyword> {
-        match self.next_token()? {
-            Token::Keyword(keyword) => Ok(keyword),
-            token => bail!("Expected a keyword but was {:?}", token),
-        }
-    }
-    fn next_identifier(&mut self) -> Result<Identifier> {
-        match self.next_token()? {
-            Token::Identifier(identifier) => Ok(identifier),
-            token => bail!("Expected an identifier but was {:?}", token),
-        }
-    }
-    fn next_symbol(&mut self) -> Result<Symbol> {
-        match self.next_token()? {
-            Token::Symbol(symbol) => Ok(symbol),
-            token => bail!("Expected a symbol but was {:?}", token),
+    /// Identifiers separated by commas.
+    fn write_identifiers(&mut self, names: &[(Identifier, Span)]) {
+        for (i, (name, _span)) in names.iter().enumerate() {
+            if i > 0 {
+                self.write_symbol(Symbol::Comma);
+            }
+            self.write_identifier(name);
         }
     }
 
-    fn next_non_void_type(&mut self) -> Result<Token> {
-        let token = self.next_token()?;
-        match token {
-            Token::Keyword(Keyword::Int)
-            | Token::Keyword(Keyword::Char)
-            | Token::Keyword(Keyword::Boolean) => Ok(token),
-            Token::Identifier(_) => Ok(token),
-            _ => bail!("Expected a type but was {:?}", token),
+    fn compile_class(&mut self, class: &Class) {
+        self.write_open("class");
+        self.write_keyword(Keyword::Class);
+        self.write_identifier(&class.name);
+        self.write_symbol(Symbol::OpenBrace);
+        for var_dec in &class.var_decs {
+            self.compile_class_var_dec(var_dec);
         }
-    }
-    fn next_voidable_type(&mut self) -> Result<Token> {
-        if Some(&Keyword::Void) == self.peek_keyword()? {
-            self.next_token()
-        } else {
-            self.next_non_void_type()
+        for subroutine in &class.subroutines {
+            self.compile_subroutine(subroutine);
         }
-    }
-
-    fn compile_class(&mut self) -> Result<()> {
-        self.write_open("class");
-        ensure!(Keyword::Class == self.next_keyword()?);
-        let _name = self.next_identifier()?;
-        ensure!(Symbol::OpenBrace == self.next_symbol()?);
-        while self.try_compile_class_var_dec()? {}
-        while self.try_compile_subroutine()? {}
-        ensure!(Symbol::CloseBrace == self.next_symbol()?);
-        ensure!(None == self.peek_token()?);
+        self.write_symbol(Symbol::CloseBrace);
         self.write_close("class");
-        Ok(())
-    }
-    fn try_compile_class_var_dec(&mut self) -> Result<bool> {
-        match self.peek_keyword()? {
-            Some(Keyword::Static) | Some(Keyword::Field) => {
-                self.write_open("classVarDec");
-                let _modifier = self.next_keyword()?;
-                let _type = self.next_non_void_type();
-
-                let _name = self.next_identifier()?;
-                while let Some(Symbol::Comma) = self.peek_symbol()? {
-                    ensure!(Symbol::Comma == self.next_symbol()?);
-                    let _name = self.next_identifier()?;
-                }
-                ensure!(Symbol::Semicolon == self.next_symbol()?);
-                self.write_close("classVarDec");
-                Ok(true)
-            }
-            _ => Ok(false),
-        }
     }
-    fn try_compile_subroutine(&mut self) -> Result<bool> {
-        match self.peek_keyword()? {
-            Some(Keyword::Constructor) | Some(Keyword::Function) | Some(Keyword::Method) => {
-                self.write_open("subroutineDec");
-                let _modifier = self.next_keyword()?;
-                let _type = self.next_voidable_type();
-                let _name = self.next_identifier()?;
-                ensure!(Symbol::OpenParen == self.next_symbol()?);
-                self.compile_parameter_list()?;
-                ensure!(Symbol::CloseParen == self.next_symbol()?);
-
-                self.write_open("subroutineBody");
-                ensure!(Symbol::OpenBrace == self.next_symbol()?);
-                while self.try_compile_var_dec()? {}
-                self.compile_statements()?;
-                ensure!(Symbol::CloseBrace == self.next_symbol()?);
-                self.write_close("subroutineBody");
-
-                self.write_close("subroutineDec");
-                Ok(true)
-            }
-            _ => Ok(false),
+    fn compile_class_var_dec(&mut self, var_dec: &ClassVarDec) {
+        self.write_open("classVarDec");
+        self.write_keyword(match var_dec.kind {
+            ClassVarKind::Static => Keyword::Static,
+            ClassVarKind::Field => Keyword::Field,
+        });
+        self.write_type(&var_dec.var_type);
+        self.write_identifiers(&var_dec.names);
+        self.write_symbol(Symbol::Semicolon);
+        self.write_close("classVarDec");
+    }
+    fn compile_subroutine(&mut self, subroutine: &Subroutine) {
+        self.write_open("subroutineDec");
+        self.write_keyword(match subroutine.kind {
+            SubroutineKind::Constructor => Keyword::Constructor,
+            SubroutineKind::Function => Keyword::Function,
+            SubroutineKind::Method => Keyword::Method,
+        });
+        match &subroutine.return_type {
+            Some(return_type) => self.write_type(return_type),
+            None => self.write_keyword(Keyword::Void),
         }
-    }
-    fn compile_parameter_list(&mut self) -> Result<()> {
+        self.write_identifier(&subroutine.name);
+        self.write_symbol(Symbol::OpenParen);
         self.write_open("parameterList");
-        if self.peek_symbol()?.is_none() {
-            let _type = self.next_non_void_type()?;
-            let _name = self.next_identifier()?;
-            while let Some(&Symbol::Comma) = self.peek_symbol()? {
-                ensure!(Symbol::Comma == self.next_symbol()?);
-                let _type = self.next_non_void_type()?;
-                let _name = self.next_identifier()?;
+        for (i, parameter) in subroutine.parameters.iter().enumerate() {
+            if i > 0 {
+                self.write_symbol(Symbol::Comma);
             }
+            self.write_type(&parameter.var_type);
+            self.write_identifier(&parameter.name);
         }
         self.write_close("parameterList");
-        Ok(())
-    }
-    fn try_compile_var_dec(&mut self) -> Result<bool> {
-        if let Some(Keyword::Var) = self.peek_keyword()? {
-            self.write_open("varDec");
-            ensure!(Keyword::Var == self.next_keyword()?);
-            let _type = self.next_non_void_type()?;
-            let _name = self.next_identifier()?;
-            while let Some(Symbol::Comma) = self.peek_symbol()? {
-                ensure!(Symbol::Comma == self.next_symbol()?);
-                let _name = self.next_identifier()?;
-            }
-            ensure!(Symbol::Semicolon == self.next_symbol()?);
-            self.write_close("varDec");
-            Ok(true)
-        } else {
-            Ok(false)
+        self.write_symbol(Symbol::CloseParen);
+
+        self.write_open("subroutineBody");
+        self.write_symbol(Symbol::OpenBrace);
+        for var_dec in &subroutine.var_decs {
+            self.compile_var_dec(var_dec);
         }
+        self.compile_statements(&subroutine.statements);
+        self.write_symbol(Symbol::CloseBrace);
+        self.write_close("subroutineBody");
+
+        self.write_close("subroutineDec");
     }
-    fn compile_statements(&mut self) -> Result<()> {
+    fn compile_var_dec(&mut self, var_dec: &VarDec) {
+        self.write_open("varDec");
+        self.write_keyword(Keyword::Var);
+        self.write_type(&var_dec.var_type);
+        self.write_identifiers(&var_dec.names);
+        self.write_symbol(Symbol::Semicolon);
+        self.write_close("varDec");
+    }
+    fn compile_statements(&mut self, statements: &[Statement]) {
         self.write_open("statements");
-        while let Some(keyword) = self.peek_keyword()? {
-            match keyword {
-                Keyword::Let => self.compile_let()?,
-                Keyword::If => self.compile_if()?,
-                Keyword::While => self.compile_while()?,
-                Keyword::Do => self.compile_do()?,
-                Keyword::Return => self.compile_return()?,
-                _ => break,
-            };
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Let { .. } | StatementKind::CompoundAssignment { .. } => {
+                    self.compile_let(&statement.kind)
+                }
+                StatementKind::If {
+                    condition,
+                    then_statements,
+                    else_statements,
+                } => self.compile_if(condition, then_statements, else_statements.as_deref()),
+                StatementKind::While {
+                    condition,
+                    statements,
+                } => self.compile_while(condition, statements),
+                StatementKind::Do(call) => self.compile_do(call),
+                StatementKind::Return(value) => self.compile_return(value.as_ref()),
+                StatementKind::For {
+                    init,
+                    condition,
+                    step,
+                    statements,
+                } => self.compile_for(init.as_deref(), condition, step.as_deref(), statements),
+                StatementKind::Break => self.compile_jump("breakStatement", Keyword::Break),
+                StatementKind::Continue => {
+                    self.compile_jump("continueStatement", Keyword::Continue)
+                }
+            }
         }
         self.write_close("statements");
-        Ok(())
     }
-    fn compile_do(&mut self) -> Result<()> {
+    /// `{ statements }`
+    fn compile_block(&mut self, statements: &[Statement]) {
+        self.write_symbol(Symbol::OpenBrace);
+        self.compile_statements(statements);
+        self.write_symbol(Symbol::CloseBrace);
+    }
+    /// `( expression )`
+    fn compile_condition(&mut self, condition: &Expression) {
+        self.write_symbol(Symbol::OpenParen);
+        self.compile_expression(condition);
+        self.write_symbol(Symbol::CloseParen);
+    }
+    fn compile_do(&mut self, call: &SubroutineCall) {
         self.write_open("doStatement");
-        ensure!(Keyword::Do == self.next_keyword()?);
-        self.compile_subroutine_call()?;
-        ensure!(Symbol::Semicolon == self.next_symbol()?);
+        self.write_keyword(Keyword::Do);
+        self.compile_subroutine_call(call);
+        self.write_symbol(Symbol::Semicolon);
         self.write_close("doStatement");
-        Ok(())
     }
-    fn compile_let(&mut self) -> Result<()> {
+    fn compile_let(&mut self, assignment: &StatementKind) {
         self.write_open("letStatement");
-        ensure!(Keyword::Let == self.next_keyword()?);
-        let _var = self.next_identifier()?;
-        if let Some(Symbol::OpenBracket) = self.peek_symbol()? {
-            ensure!(Symbol::OpenBracket == self.next_symbol()?);
-            let _expression = self.compile_expression()?;
-            ensure!(Symbol::CloseBracket == self.next_symbol()?);
-        }
-        ensure!(Symbol::Equal == self.next_symbol()?);
-        self.compile_expression()?;
-        ensure!(Symbol::Semicolon == self.next_symbol()?);
+        self.write_keyword(Keyword::Let);
+        self.compile_assignment(assignment);
+        self.write_symbol(Symbol::Semicolon);
         self.write_close("letStatement");
-        Ok(())
     }
-    fn compile_while(&mut self) -> Result<()> {
+    /// `name = value` or `name[index] = value`, or their compound forms as in the source.
+    fn compile_assignment(&mut self, assignment: &StatementKind) {
+        let (name, index) = match assignment {
+            StatementKind::Let { name, index, .. }
+            | StatementKind::CompoundAssignment { name, index, .. } => (name, index),
+            _ => return,
+        };
+        self.write_identifier(name);
+        if let Some(index) = index {
+            self.write_symbol(Symbol::OpenBracket);
+            self.compile_expression(index);
+            self.write_symbol(Symbol::CloseBracket);
+        }
+        match assignment {
+            StatementKind::CompoundAssignment {
+                operator, value, ..
+            } => {
+                self.write_symbol(match (operator, value) {
+                    (BinaryOperator::Add, Some(_)) => Symbol::PlusEqual,
+                    (BinaryOperator::Add, None) => Symbol::PlusPlus,
+                    (_, Some(_)) => Symbol::DashEqual,
+                    (_, None) => Symbol::DashDash,
+                });
+                if let Some(value) = value {
+                    self.compile_expression(value);
+                }
+            }
+            StatementKind::Let { value, .. } => {
+                self.write_symbol(Symbol::Equal);
+                self.compile_expression(value);
+            }
+            _ => {}
+        }
+    }
+    fn compile_while(&mut self, condition: &Expression, statements: &[Statement]) {
         self.write_open("whileStatement");
-        ensure!(Keyword::While == self.next_keyword()?);
-        ensure!(Symbol::OpenParen == self.next_symbol()?);
-        self.compile_expression()?;
-        ensure!(Symbol::CloseParen == self.next_symbol()?);
-        ensure!(Symbol::OpenBrace == self.next_symbol()?);
-        self.compile_statements()?;
-        ensure!(Symbol::CloseBrace == self.next_symbol()?);
+        self.write_keyword(Keyword::While);
+        self.compile_condition(condition);
+        self.compile_block(statements);
         self.write_close("whileStatement");
-        Ok(())
     }
-    fn compile_return(&mut self) -> Result<()> {
+    /// Without a standard format, the `init` and `step` of `for` are written as in the source and
+    /// tagged as `assignment`s.
+    fn compile_for(
+        &mut self,
+        init: Option<&Statement>,
+        condition: &Expression,
+        step: Option<&Statement>,
+        statements: &[Statement],
+    ) {
+        self.write_open("forStatement");
+        self.write_keyword(Keyword::For);
+        self.write_symbol(Symbol::OpenParen);
+        self.compile_for_assignment(init);
+        self.write_symbol(Symbol::Semicolon);
+        self.compile_expression(condition);
+        self.write_symbol(Symbol::Semicolon);
+        self.compile_for_assignment(step);
+        self.write_symbol(Symbol::CloseParen);
+        self.compile_block(statements);
+        self.write_close("forStatement");
+    }
+    fn compile_for_assignment(&mut self, assignment: Option<&Statement>) {
+        if let Some(assignment) = assignment {
+            self.write_open("assignment");
+            self.compile_assignment(&assignment.kind);
+            self.write_close("assignment");
+        }
+    }
+    fn compile_jump(&mut self, tag: &str, keyword: Keyword) {
+        self.write_open(tag);
+        self.write_keyword(keyword);
+        self.write_symbol(Symbol::Semicolon);
+        self.write_close(tag);
+    }
+    fn compile_return(&mut self, value: Option<&Expression>) {
         self.write_open("returnStatement");
-        ensure!(Keyword::Return == self.next_keyword()?);
-        if let Some(Symbol::Semicolon) = self.peek_symbol()? {
-        } else {
-            self.compile_expression()?;
+        self.write_keyword(Keyword::Return);
+        if let Some(value) = value {
+            self.compile_expression(value);
         }
-        ensure!(Symbol::Semicolon == self.next_symbol()?);
+        self.write_symbol(Symbol::Semicolon);
         self.write_close("returnStatement");
-        Ok(())
     }
-    fn compile_if(&mut self) -> Result<()> {
+    fn compile_if(
+        &mut self,
+        condition: &Expression,
+        then_statements: &[Statement],
+        else_statements: Option<&[Statement]>,
+    ) {
         self.write_open("ifStatement");
-        ensure!(Keyword::If == self.next_keyword()?);
-        ensure!(Symbol::OpenParen == self.next_symbol()?);
-        self.compile_expression()?;
-        ensure!(Symbol::CloseParen == self.next_symbol()?);
-        ensure!(Symbol::OpenBrace == self.next_symbol()?);
-        self.compile_statements()?;
-        ensure!(Symbol::CloseBrace == self.next_symbol()?);
-        if let Some(Keyword::Else) = self.peek_keyword()? {
-            ensure!(Keyword::Else == self.next_keyword()?);
-            ensure!(Symbol::OpenBrace == self.next_symbol()?);
-            self.compile_statements()?;
-            ensure!(Symbol::CloseBrace == self.next_symbol()?);
+        self.write_keyword(Keyword::If);
+        self.compile_condition(condition);
+        self.compile_block(then_statements);
+        if let Some(else_statements) = else_statements {
+            self.write_keyword(Keyword::Else);
+            self.compile_block(else_statements);
         }
         self.write_close("ifStatement");
-        Ok(())
     }
-    fn compile_subroutine_call(&mut self) -> Result<()> {
-        self.next_identifier()?;
-        if let Some(Symbol::Dot) = self.peek_symbol()? {
-            ensure!(Symbol::Dot == self.next_symbol()?);
-            self.next_identifier()?;
+    fn compile_subroutine_call(&mut self, call: &SubroutineCall) {
+        if let Some(receiver) = &call.receiver {
+            self.write_identifier(receiver);
+            self.write_symbol(Symbol::Dot);
         }
-        ensure!(Symbol::OpenParen == self.next_symbol()?);
-        self.compile_expression_list()?;
-        ensure!(Symbol::CloseParen == self.next_symbol()?);
-        Ok(())
+        self.write_identifier(&call.name);
+        self.write_symbol(Symbol::OpenParen);
+        self.compile_expression_list(&call.arguments);
+        self.write_symbol(Symbol::CloseParen);
     }
-    fn compile_expression(&mut self) -> Result<()> {
+    fn compile_expression(&mut self, expression: &Expression) {
         self.write_open("expression");
-        self.compile_term()?;
-        loop {
-            if let Some(symbol) = self.peek_symbol()? {
-                match symbol {
-                    Symbol::Plus
-                    | Symbol::Dash
-                    | Symbol::Star
-                    | Symbol::Slash
-                    | Symbol::Ampersand
-                    | Symbol::VerticalBar
-                    | Symbol::LessThan
-                    | Symbol::GreaterThan
-                    | Symbol::Equal => {
-                        self.next_symbol()?;
-                        let _ = self.compile_term()?;
-                    }
-                    _ => break,
-                }
-            }
+        self.compile_term(&expression.term);
+        for (operator, term) in &expression.operations {
+            self.write_symbol(match operator {
+                BinaryOperator::Add => Symbol::Plus,
+                BinaryOperator::Sub => Symbol::Dash,
+                BinaryOperator::Mul => Symbol::Star,
+                BinaryOperator::Div => Symbol::Slash,
+                BinaryOperator::And => Symbol::Ampersand,
+                BinaryOperator::Or => Symbol::VerticalBar,
+                BinaryOperator::Lt => Symbol::LessThan,
+                BinaryOperator::Gt => Symbol::GreaterThan,
+                BinaryOperator::Eq => Symbol::Equal,
+            });
+            self.compile_term(term);
         }
         self.write_close("expression");
-        Ok(())
     }
-    fn compile_term(&mut self) -> Result<()> {
-        if let Some(token) = self.peek_token()? {
-            match token {
-                Token::IntegerConstant(_) | Token::StringConstant(_) => {
-                    self.write_open("term");
-                    self.next_token()?;
-                    self.write_close("term");
-                }
-                Token::Keyword(keyword) => match keyword {
-                    Keyword::True | Keyword::False | Keyword::Null | Keyword::This => {
-                        self.write_open("term");
-                        self.next_keyword()?;
-                        self.write_close("term");
-                    }
-                    _ => bail!("Unexpected keyword {:?}", keyword),
-                },
-                Token::Identifier(_) => {
-                    let next_next_token = self.peek_nth(1)?;
-                    if Some(&Token::Symbol(Symbol::OpenParen)) == next_next_token
-                        || Some(&Token::Symbol(Symbol::Dot)) == next_next_token
-                    {
-                        // name '(' expression list ')'
-                        // var '.' name '(' expression list ')'
-                        self.write_open("term");
-                        self.compile_subroutine_call()?;
-                        self.write_close("term");
-                    } else if Some(&Token::Symbol(Symbol::OpenBracket)) == next_next_token {
-                        // var '[' expression ']'
-                        self.write_open("term");
-                        self.next_identifier()?;
-                        ensure!(Symbol::OpenBracket == self.next_symbol()?);
-                        self.compile_expression()?;
-                        ensure!(Symbol::CloseBracket == self.next_symbol()?);
-                        self.write_close("term");
-                    } else {
-                        // var
-                        self.write_open("term");
-                        self.next_identifier()?;
-                        self.write_close("term");
-                    }
-                }
-                Token::Symbol(symbol) => {
-                    match symbol {
-                        Symbol::OpenParen => {
-                            // '(' expression ')'
-                            self.write_open("term");
-                            ensure!(Symbol::OpenParen == self.next_symbol()?);
-                            self.compile_expression()?;
-                            ensure!(Symbol::CloseParen == self.next_symbol()?);
-                            self.write_close("term");
-                        }
-                        Symbol::Dash | Symbol::Tilde => {
-                            self.write_open("term");
-                            self.next_symbol()?;
-                            self.compile_term()?;
-                            self.write_close("term");
-                        }
-                        _ => bail!("Unexpected symbol {:?}", symbol),
-                    }
-                }
+    fn compile_term(&mut self, term: &Term) {
+        self.write_open("term");
+        match &term.kind {
+            TermKind::IntegerConstant(v) => self.write_terminal(&Token::IntegerConstant(*v)),
+            TermKind::StringConstant(s) => self.write_terminal(&Token::StringConstant(s.clone())),
+            TermKind::KeywordConstant(constant) => self.write_keyword(match constant {
+                KeywordConstant::True => Keyword::True,
+                KeywordConstant::False => Keyword::False,
+                KeywordConstant::Null => Keyword::Null,
+                KeywordConstant::This => Keyword::This,
+            }),
+            TermKind::Variable(name) => self.write_identifier(name),
+            TermKind::ArrayElement(name, index) => {
+                self.write_identifier(name);
+                self.write_symbol(Symbol::OpenBracket);
+                self.compile_expression(index);
+                self.write_symbol(Symbol::CloseBracket);
+            }
+            TermKind::Call(call) => self.compile_subroutine_call(call),
+            TermKind::Parenthesized(expression) => {
+                self.write_symbol(Symbol::OpenParen);
+                self.compile_expression(expression);
+                self.write_symbol(Symbol::CloseParen);
+            }
+            TermKind::Unary(operator, term) => {
+                self.write_symbol(match operator {
+                    UnaryOperator::Neg => Symbol::Dash,
+                    UnaryOperator::Not => Symbol::Tilde,
+                });
+                self.compile_term(term);
             }
-            Ok(())
-        } else {
-            bail!("Unexpected end of token stream")
         }
+        self.write_close("term");
     }
-    fn compile_expression_list(&mut self) -> Result<()> {
+    fn compile_expression_list(&mut self, expressions: &[Expression]) {
         self.write_open("expressionList");
-        if Some(&Symbol::CloseParen) != self.peek_symbol()? {
-            self.compile_expression()?;
-            while Some(&Symbol::Comma) == self.peek_symbol()? {
-                ensure!(Symbol::Comma == self.next_symbol()?);
-                self.compile_expression()?;
+        for (i, expression) in expressions.iter().enumerate() {
+            if i > 0 {
+                self.write_symbol(Symbol::Comma);
             }
+            self.compile_expression(expression);
         }
         self.write_close("expressionList");
-        Ok(())
     }
 
-    pub fn compile(&mut self) -> Result<String> {
-        self.result_xml.clear();
-        self.compile_class()
-            .with_context(|| self.result_xml.clone())?;
-        Ok(std::mem::take(&mut self.result_xml))
+    pub fn compile(class: &Class) -> String {
+        let mut analyzer = XMLAnalyzer {
+            result_xml: String::new(),
+        };
+        analyzer.compile_class(class);
+        analyzer.result_xml
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::jack::parser::Parser;
     use crate::jack::tokenizer::TokenIterator;
     use anyhow::Result;
 
@@ -444,10 +398,17 @@ mod test {
             }
             "#
         .as_bytes();
-        let token_iterator = TokenIterator::from(input);
-        let mut analyzer = XMLAnalyzer::from(token_iterator);
-        let ret = analyzer.compile()?;
+        let class = Parser::parse(TokenIterator::from(input))?;
+        let ret = XMLAnalyzer::compile(&class);
         println!("{}", ret);
+
+        let input = "class Main { function void f(int i) { for (i = 0; i < 3; i++) {} return; } }";
+        let mut token_iterator = TokenIterator::from(input.as_bytes());
+        token_iterator.set_language_extensions(true);
+        let ret = XMLAnalyzer::compile(&Parser::parse(token_iterator)?);
+        assert!(ret.contains(
+            "<assignment>\n<identifier> i </identifier>\n<symbol> ++ </symbol>\n</assignment>"
+        ));
         Ok(())
     }
 }